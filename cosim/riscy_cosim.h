@@ -0,0 +1,84 @@
+/*
+ * Lockstep co-simulation interface of the RISC-V emulator.
+ *
+ * `cargo build --release` produces libriscy_cosim.so next to the emulator.
+ * The testbench creates a machine, loads the same program as the RTL and
+ * calls riscy_cosim_step() every time the core under test retires an
+ * instruction, comparing the commit it gets back with the RTL's. Devices
+ * run as in the emulator, UART output goes to stdout.
+ *
+ * Functions returning int return 0 on success and -1 on failure, see
+ * riscy_cosim_last_error(). A machine is not thread safe.
+ */
+#ifndef RISCY_COSIM_H
+#define RISCY_COSIM_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct riscy_cosim riscy_cosim_t;
+
+#define RISCY_MEM_NONE  0
+#define RISCY_MEM_READ  1
+#define RISCY_MEM_WRITE 2
+
+/* What one retired instruction did. */
+typedef struct {
+    uint64_t cycle;         /* instructions retired before this one */
+    uint32_t pc;
+    uint32_t insn;
+    uint32_t next_pc;
+    uint8_t rd;             /* register written, 0 for none */
+    uint8_t mem_op;         /* RISCY_MEM_* */
+    uint8_t mem_size;       /* 1, 2 or 4 */
+    uint8_t trapped;
+    uint32_t rd_value;
+    uint32_t mem_addr;
+    uint32_t mem_value;     /* loaded (before sign extension) or stored */
+    uint32_t trap_cause;
+} riscy_commit_t;
+
+/* `platform` is a platform file, NULL for the RISC-Y board. Returns NULL
+ * on failure, with the reason printed to stderr. */
+riscy_cosim_t *riscy_cosim_create(const char *platform);
+void riscy_cosim_destroy(riscy_cosim_t *sim);
+
+/* Resets the machine and loads a hex, assembly or ELF program. */
+int riscy_cosim_load(riscy_cosim_t *sim, const char *program);
+
+/* Executes one instruction and describes it in `commit` (may be NULL).
+ * After a failure the machine stays stopped until the next load, and
+ * so does it once the program exited. */
+int riscy_cosim_step(riscy_cosim_t *sim, riscy_commit_t *commit);
+
+/* Reason for the last failed call, "" if none. Valid until the next call. */
+const char *riscy_cosim_last_error(const riscy_cosim_t *sim);
+
+uint32_t riscy_cosim_pc(const riscy_cosim_t *sim);
+void riscy_cosim_set_pc(riscy_cosim_t *sim, uint32_t pc);
+uint32_t riscy_cosim_reg(const riscy_cosim_t *sim, uint32_t reg);
+void riscy_cosim_set_reg(riscy_cosim_t *sim, uint32_t reg, uint32_t value);
+
+/* Accesses go through the bus, device side effects included. `size` is
+ * 1, 2 or 4. */
+int riscy_cosim_read_mem(riscy_cosim_t *sim, uint32_t addr, uint8_t size, uint32_t *value);
+int riscy_cosim_write_mem(riscy_cosim_t *sim, uint32_t addr, uint8_t size, uint32_t value);
+
+/* External state the emulator can't know from the program alone. */
+
+/* Drives interrupt line `line` (0 to 31). The hart doesn't take interrupts
+ * yet, only the pending set changes. */
+void riscy_cosim_set_irq(riscy_cosim_t *sim, uint32_t line, int level);
+uint32_t riscy_cosim_pending_irqs(const riscy_cosim_t *sim);
+/* The next load from `addr` returns `value`, for MMIO reads the RTL saw
+ * differently (timers, status registers). The device is still read. */
+void riscy_cosim_force_load(riscy_cosim_t *sim, uint32_t addr, uint32_t value);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,5 @@
+//! `libriscy_cosim`, the emulator as a C library for RTL testbenches. The
+//! interface lives in the emulator's `cosim` module and is declared in
+//! `riscy_cosim.h` next to this crate.
+
+pub use risv_emulator::cosim::*;
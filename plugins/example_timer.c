@@ -0,0 +1,105 @@
+/*
+ * Example plugin: a down counter that raises its interrupt when it hits 0.
+ *
+ *   cc -shared -fPIC -o libtimer.so plugins/example_timer.c
+ *   RISV_Emulator program.hex --device ./libtimer.so@0x90000000,irq=3
+ *
+ * 0x0 COUNT  write to load, counts down once per CPU cycle
+ * 0x4 STATUS bit 0 expired, write 1 to clear (and drop the interrupt)
+ */
+#include <stdlib.h>
+#include <string.h>
+#include "riscy_device.h"
+
+typedef struct {
+    uint32_t count;
+    uint32_t expired;
+    void *host;
+    riscy_set_irq_fn set_irq;
+} timer_dev;
+
+static void *create(const char *args, void *host, riscy_set_irq_fn set_irq) {
+    (void)args;
+    timer_dev *t = calloc(1, sizeof(*t));
+    if (t) {
+        t->host = host;
+        t->set_irq = set_irq;
+    }
+    return t;
+}
+
+static void destroy(void *dev) { free(dev); }
+
+static uint32_t read(void *dev, uint8_t size, uint32_t offset) {
+    timer_dev *t = dev;
+    (void)size;
+    return offset == 0 ? t->count : offset == 4 ? t->expired : 0;
+}
+
+static int write(void *dev, uint8_t size, uint32_t offset, uint32_t value) {
+    timer_dev *t = dev;
+    (void)size;
+    if (offset == 0) {
+        t->count = value;
+    } else if (offset == 4) {
+        t->expired &= ~value;
+        t->set_irq(t->host, t->expired != 0);
+    } else {
+        return -1;
+    }
+    return 0;
+}
+
+static void tick(void *dev, uint64_t cycles) {
+    timer_dev *t = dev;
+    if (t->count == 0) {
+        return;
+    }
+    t->count = cycles >= t->count ? 0 : t->count - (uint32_t)cycles;
+    if (t->count == 0) {
+        t->expired = 1;
+        t->set_irq(t->host, 1);
+    }
+}
+
+static void reset(void *dev) {
+    timer_dev *t = dev;
+    t->count = 0;
+    t->expired = 0;
+    t->set_irq(t->host, 0);
+}
+
+static size_t save(const void *dev, uint8_t *buf, size_t len) {
+    const timer_dev *t = dev;
+    if (len >= 8) {
+        memcpy(buf, &t->count, 4);
+        memcpy(buf + 4, &t->expired, 4);
+    }
+    return 8;
+}
+
+static int restore(void *dev, const uint8_t *buf, size_t len) {
+    timer_dev *t = dev;
+    if (len != 8) {
+        return -1;
+    }
+    memcpy(&t->count, buf, 4);
+    memcpy(&t->expired, buf + 4, 4);
+    t->set_irq(t->host, t->expired != 0);
+    return 0;
+}
+
+static const riscy_device_api_t api = {
+    .abi_version = RISCY_DEVICE_ABI_VERSION,
+    .size = 8,
+    .create = create,
+    .destroy = destroy,
+    .read = read,
+    .write = write,
+    .tick = tick,
+    .reset = reset,
+    .save = save,
+    .restore = restore,
+};
+
+const riscy_device_api_t *riscy_device_api(void) { return &api; }
@@ -0,0 +1,45 @@
+/*
+ * Device plugin interface of the RISC-V emulator.
+ *
+ * A plugin is a shared library exporting `riscy_device_api`, which returns a
+ * pointer to a static `riscy_device_api_t`. The emulator maps the device at
+ * the base given with `--device libdev.so@0x90000000` (or a `plugin` entry
+ * in the platform file) and forwards every access inside `size` bytes to it
+ * with the offset from the base. All callbacks run on the CPU thread.
+ */
+#ifndef RISCY_DEVICE_H
+#define RISCY_DEVICE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define RISCY_DEVICE_ABI_VERSION 1
+
+/* Drives the device's interrupt line, level triggered. The hart doesn't
+ * take interrupts yet, the level is only reported to the user. */
+typedef void (*riscy_set_irq_fn)(void *host, int level);
+
+typedef struct {
+    uint32_t abi_version;   /* RISCY_DEVICE_ABI_VERSION */
+    uint32_t size;          /* MMIO window in bytes */
+
+    /* `args` comes from the platform file, `host`/`set_irq` raise the IRQ.
+     * Returns the device instance, or NULL on failure. */
+    void *(*create)(const char *args, void *host, riscy_set_irq_fn set_irq);
+    void (*destroy)(void *dev);                                     /* optional */
+
+    /* `size` is 1, 2 or 4. write returns 0 on success. */
+    uint32_t (*read)(void *dev, uint8_t size, uint32_t offset);
+    int (*write)(void *dev, uint8_t size, uint32_t offset, uint32_t value);
+
+    void (*tick)(void *dev, uint64_t cycles);                       /* optional */
+    void (*reset)(void *dev);                                       /* optional */
+    /* Snapshots: save writes at most `len` bytes and returns the size it
+     * needs, restore returns 0 on success. */
+    size_t (*save)(const void *dev, uint8_t *buf, size_t len);      /* optional */
+    int (*restore)(void *dev, const uint8_t *buf, size_t len);      /* optional */
+} riscy_device_api_t;
+
+const riscy_device_api_t *riscy_device_api(void);
+
+#endif
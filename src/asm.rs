@@ -0,0 +1,697 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use crate::constants::*;
+use crate::elf::{Symbol, Symbols};
+use crate::platform::parse_u64;
+
+const NOP: u32 = 0x0000_0013;
+
+/// Directives accepted for compatibility with GNU as sources and ignored:
+/// everything goes into one flat image.
+const IGNORED_DIRECTIVES: [&str; 12] = [
+    ".global", ".globl", ".local", ".text", ".data", ".bss", ".rodata", ".section", ".type", ".size", ".option", ".file",
+];
+
+/// A program assembled by `assemble`, starting at `base`.
+pub struct Assembly {
+    pub words: Vec<u32>,
+    /// Labels, for the debugger.
+    pub symbols: Symbols,
+}
+
+/// One source line after the first pass, with the address it starts at.
+struct Statement<'a> {
+    line: usize,
+    addr: u32,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+/// Assembles `path` for loading at `base`. Errors name the file and line.
+pub fn assemble_file(path: &str, base: u32) -> Result<Assembly, Box<dyn Error>> {
+    let source = fs::read_to_string(path)?;
+    assemble(&source, base).map_err(|e| format!("{}:{}", path, e).into())
+}
+
+/// Whether `path` is assembly source rather than a hex or ELF file.
+pub fn is_source(path: &str) -> bool {
+    path.ends_with(".s") || path.ends_with(".S") || path.ends_with(".asm")
+}
+
+/// Two pass assembler for RV32I: labels, `li`/`la`/`call`/`ret`/`j`/`mv`/
+/// `nop` and the other common pseudo-instructions, `%hi`/`%lo`, and the
+/// `.word`/`.half`/`.byte`/`.string`/`.ascii`/`.org`/`.align`/`.space`/
+/// `.equ` directives. Branch and jump targets are labels or absolute
+/// addresses, so `disasm` output assembles back to the same words.
+pub fn assemble(source: &str, base: u32) -> Result<Assembly, String> {
+    let source = strip_block_comments(source);
+    let mut labels: HashMap<String, i64> = HashMap::new();
+    let mut label_order = Vec::new();
+    let mut statements = Vec::new();
+    let mut addr = base;
+
+    // First pass: sizes and label addresses.
+    for (index, raw) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |message: String| format!("{}: {}", line, message);
+        let mut text = strip_comment(raw).trim();
+        while let Some((label, rest)) = split_label(text) {
+            if labels.insert(label.to_string(), addr as i64).is_some() {
+                return Err(error(format!("label '{}' is defined twice", label)));
+            }
+            label_order.push((label.to_string(), addr));
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands = split_operands(rest.trim());
+        let size = match mnemonic.to_ascii_lowercase().as_str() {
+            directive if directive.starts_with('.') => {
+                match directive {
+                    ".equ" | ".set" => {
+                        let [name, value] = operands[..] else {
+                            return Err(error(format!("usage: {} name, value", directive)));
+                        };
+                        let value = eval(value, &labels).map_err(error)?;
+                        labels.insert(name.to_string(), value);
+                        continue;
+                    }
+                    ".org" => {
+                        let [offset] = operands[..] else { return Err(error(".org takes one offset".to_string())) };
+                        let target = base as i64 + eval(offset, &labels).map_err(error)?;
+                        if target < addr as i64 {
+                            return Err(error(format!(".org 0x{:x} moves backwards", target - base as i64)));
+                        }
+                        (target - addr as i64) as u32
+                    }
+                    ".align" | ".p2align" | ".balign" => {
+                        let [amount] = operands[..] else { return Err(error(format!("{} takes one argument", directive))) };
+                        let amount = eval(amount, &labels).map_err(error)? as u32;
+                        let alignment = if directive == ".balign" { amount } else { 1u32.checked_shl(amount).unwrap_or(0) };
+                        if !alignment.is_power_of_two() {
+                            return Err(error(format!("bad alignment {}", amount)));
+                        }
+                        addr.next_multiple_of(alignment) - addr
+                    }
+                    _ => data_size(directive, &operands, &labels).map_err(error)?,
+                }
+            }
+            "li" => match operands.get(1).map(|value| eval(value, &HashMap::new())) {
+                Some(Ok(value)) => li_sequence(0, value).len() as u32 * 4,
+                _ => 8,
+            },
+            "la" => 8,
+            _ => 4,
+        };
+        statements.push(Statement { line, addr, mnemonic, operands });
+        addr = addr.checked_add(size).ok_or_else(|| error("the program runs past the end of memory".to_string()))?;
+    }
+
+    // Second pass: encode with every label known.
+    let mut bytes = vec![0u8; (addr - base).next_multiple_of(4) as usize];
+    for statement in &statements {
+        let offset = (statement.addr - base) as usize;
+        let error = |message: String| format!("{}: {}", statement.line, message);
+        let encoded = encode(statement, &labels).map_err(error)?;
+        bytes[offset..offset + encoded.len()].copy_from_slice(&encoded);
+    }
+    let words = bytes.chunks(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect();
+    let symbols = label_order.into_iter().map(|(name, addr)| Symbol { name, addr, size: 0 }).collect();
+    Ok(Assembly { words, symbols: Symbols::new(symbols) })
+}
+
+/// Replaces `/* */` comments with spaces, keeping the line count.
+fn strip_block_comments(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find("*/").map_or(rest.len(), |end| start + end + 2);
+        out.extend(rest[start..end].chars().filter(|&c| c == '\n'));
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Cuts a `#` or `//` comment, leaving strings and character literals alone.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            (None, '/') if line[i..].starts_with("//") => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let (label, rest) = text.split_once(':')?;
+    let valid = label.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || "_.$".contains(c))
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || "_.$".contains(c));
+    valid.then_some((label, rest))
+}
+
+/// Splits on commas outside of quotes.
+fn split_operands(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(_), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ',') => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+        escaped = false;
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+fn register(name: &str) -> Result<u32, String> {
+    let name = name.trim();
+    if let Some(index) = name.strip_prefix('x').and_then(|n| n.parse::<u32>().ok()) && index < 32 {
+        return Ok(index);
+    }
+    if name == "fp" {
+        return Ok(8);
+    }
+    REGISTER_NAMES.iter().position(|&abi| abi == name)
+        .map(|index| index as u32)
+        .ok_or_else(|| format!("'{}' is not a register", name))
+}
+
+/// Evaluates `term [+|- term]...`, where a term is a number, a character
+/// literal, a label, or `%hi(expr)`/`%lo(expr)`.
+fn eval(expr: &str, labels: &HashMap<String, i64>) -> Result<i64, String> {
+    let expr = expr.trim();
+    if expr.is_empty() {
+        return Err("missing value".to_string());
+    }
+    if expr.len() >= 3 && expr.starts_with('\'') && expr.ends_with('\'') {
+        return match unescape(&expr[1..expr.len() - 1])?[..] {
+            [byte] => Ok(byte as i64),
+            _ => Err(format!("bad character literal {}", expr)),
+        };
+    }
+    // Split at the last top level + or - that is not a sign.
+    let mut depth = 0;
+    let mut split = None;
+    for (i, c) in expr.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '+' | '-' if depth == 0 && i > 0 && !expr[..i].trim_end().ends_with(['+', '-', '(']) => split = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(i) = split {
+        let (left, right) = (eval(&expr[..i], labels)?, eval(&expr[i + 1..], labels)?);
+        return Ok(if expr.as_bytes()[i] == b'+' { left + right } else { left - right });
+    }
+    if let Some(inner) = expr.strip_prefix("%hi(").and_then(|rest| rest.strip_suffix(')')) {
+        return Ok(((eval(inner, labels)? + 0x800) >> 12) & 0xFFFFF);
+    }
+    if let Some(inner) = expr.strip_prefix("%lo(").and_then(|rest| rest.strip_suffix(')')) {
+        return Ok(sign_extend12(eval(inner, labels)?));
+    }
+    if let Some(inner) = expr.strip_prefix('(').and_then(|rest| rest.strip_suffix(')')) {
+        return eval(inner, labels);
+    }
+    if let Some(rest) = expr.strip_prefix('-') {
+        return Ok(-eval(rest, labels)?);
+    }
+    if expr.starts_with(|c: char| c.is_ascii_digit()) {
+        return parse_u64(expr).map(|value| value as i64);
+    }
+    labels.get(expr).copied().ok_or_else(|| format!("undefined label '{}'", expr))
+}
+
+fn sign_extend12(value: i64) -> i64 {
+    ((value & 0xFFF) ^ 0x800) - 0x800
+}
+
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('\'') => b'\'',
+            other => return Err(format!("unknown escape \\{}", other.map_or(String::new(), String::from))),
+        });
+    }
+    Ok(bytes)
+}
+
+fn string_operand(operand: &str) -> Result<Vec<u8>, String> {
+    let inner = operand.strip_prefix('"').and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, found {}", operand))?;
+    unescape(inner)
+}
+
+fn data_size(directive: &str, operands: &[&str], labels: &HashMap<String, i64>) -> Result<u32, String> {
+    let count = operands.len() as u32;
+    Ok(match directive {
+        ".word" | ".4byte" | ".long" => 4 * count,
+        ".half" | ".2byte" | ".short" => 2 * count,
+        ".byte" => count,
+        ".string" | ".asciz" | ".ascii" => {
+            let terminator = (directive != ".ascii") as u32;
+            operands.iter().map(|operand| Ok(string_operand(operand)?.len() as u32 + terminator)).sum::<Result<u32, String>>()?
+        }
+        ".space" | ".zero" | ".skip" => {
+            let [size] = operands[..] else { return Err(format!("{} takes one size", directive)) };
+            eval(size, labels)? as u32
+        }
+        _ if IGNORED_DIRECTIVES.contains(&directive) => 0,
+        _ => return Err(format!("unknown directive {}", directive)),
+    })
+}
+
+fn encode(statement: &Statement, labels: &HashMap<String, i64>) -> Result<Vec<u8>, String> {
+    let mnemonic = statement.mnemonic.to_ascii_lowercase();
+    let operands = &statement.operands;
+    let value = |index: usize| operands.get(index).ok_or_else(|| format!("{} needs more operands", mnemonic)).and_then(|o| eval(o, labels));
+    if mnemonic.starts_with('.') {
+        let mut bytes = Vec::new();
+        match mnemonic.as_str() {
+            ".word" | ".4byte" | ".long" => for i in 0..operands.len() {
+                bytes.extend_from_slice(&(value(i)? as u32).to_le_bytes());
+            },
+            ".half" | ".2byte" | ".short" => for i in 0..operands.len() {
+                bytes.extend_from_slice(&(value(i)? as u16).to_le_bytes());
+            },
+            ".byte" => for i in 0..operands.len() {
+                bytes.push(value(i)? as u8);
+            },
+            ".string" | ".asciz" | ".ascii" => for operand in operands {
+                bytes.extend(string_operand(operand)?);
+                if mnemonic != ".ascii" {
+                    bytes.push(0);
+                }
+            },
+            // .org, .align and .space leave the zero fill in place.
+            _ => {}
+        }
+        return Ok(bytes);
+    }
+    if !statement.addr.is_multiple_of(4) {
+        return Err(format!("instruction at 0x{:08x} is not word aligned, add .align 2", statement.addr));
+    }
+    let words = instruction(&mnemonic, operands, statement.addr, labels)?;
+    Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+}
+
+fn check_range(value: i64, bits: u32, what: &str) -> Result<u32, String> {
+    let limit = 1i64 << (bits - 1);
+    if value < -limit || value >= limit {
+        return Err(format!("{} {} does not fit in {} bits", what, value, bits));
+    }
+    Ok(value as u32)
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | R_FORMAT
+}
+
+fn i_type(imm: i64, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> Result<u32, String> {
+    let imm = check_range(imm, 12, "immediate")?;
+    Ok(((imm & 0xFFF) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode)
+}
+
+fn s_type(imm: i64, rs2: u32, rs1: u32, funct3: u32) -> Result<u32, String> {
+    let imm = check_range(imm, 12, "offset")?;
+    Ok(((imm >> 5 & 0x7F) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1F) << 7) | S_FORMAT)
+}
+
+fn b_type(offset: i64, rs1: u32, rs2: u32, funct3: u32) -> Result<u32, String> {
+    let imm = check_range(offset, 13, "branch offset")?;
+    if offset & 1 != 0 {
+        return Err("branch target is not 2 byte aligned".to_string());
+    }
+    Ok(((imm >> 12 & 1) << 31) | ((imm >> 5 & 0x3F) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12)
+        | ((imm >> 1 & 0xF) << 8) | ((imm >> 11 & 1) << 7) | B_FORMAT)
+}
+
+fn u_type(imm: i64, rd: u32, opcode: u32) -> Result<u32, String> {
+    if !(-0x80000..=0xFFFFF).contains(&imm) {
+        return Err(format!("upper immediate 0x{:x} does not fit in 20 bits", imm));
+    }
+    Ok(((imm as u32 & 0xFFFFF) << 12) | (rd << 7) | opcode)
+}
+
+fn j_type(offset: i64, rd: u32) -> Result<u32, String> {
+    let imm = check_range(offset, 21, "jump offset")?;
+    if offset & 1 != 0 {
+        return Err("jump target is not 2 byte aligned".to_string());
+    }
+    Ok(((imm >> 20 & 1) << 31) | ((imm >> 1 & 0x3FF) << 21) | ((imm >> 11 & 1) << 20) | ((imm >> 12 & 0xFF) << 12)
+        | (rd << 7) | J_FORMAT)
+}
+
+/// `lui`+`addi` (or just one of them) loading `value` into `rd`.
+fn li_sequence(rd: u32, value: i64) -> Vec<u32> {
+    let value = value as i32 as i64;
+    if (-2048..2048).contains(&value) {
+        return vec![i_type(value, 0, FUNCT3_ADDI, rd, I_COMP_FORMAT).unwrap_or_default()];
+    }
+    let lo = sign_extend12(value);
+    let hi = ((value - lo) >> 12) & 0xFFFFF;
+    let mut words = vec![u_type(hi, rd, U_FORMAT_LUI).unwrap_or_default()];
+    if lo != 0 {
+        words.push(i_type(lo, rd, FUNCT3_ADDI, rd, I_COMP_FORMAT).unwrap_or_default());
+    }
+    words
+}
+
+/// `offset(reg)` memory operand.
+fn memory_operand(operand: &str, labels: &HashMap<String, i64>) -> Result<(i64, u32), String> {
+    let (offset, reg) = operand.strip_suffix(')').and_then(|rest| rest.rsplit_once('('))
+        .ok_or_else(|| format!("expected offset(register), found '{}'", operand))?;
+    let offset = if offset.trim().is_empty() { 0 } else { eval(offset, labels)? };
+    Ok((offset, register(reg)?))
+}
+
+fn instruction(mnemonic: &str, operands: &[&str], pc: u32, labels: &HashMap<String, i64>) -> Result<Vec<u32>, String> {
+    let arity = |count: usize| {
+        if operands.len() == count {
+            Ok(())
+        } else {
+            Err(format!("{} takes {} operands, found {}", mnemonic, count, operands.len()))
+        }
+    };
+    let reg = |index: usize| register(operands[index]);
+    let value = |index: usize| eval(operands[index], labels);
+    let relative = |index: usize| Ok::<i64, String>(eval(operands[index], labels)? - pc as i64);
+
+    let r_ops = [
+        ("add", FUNCT3_ADD_SUB, 0x00), ("sub", FUNCT3_ADD_SUB, 0x20), ("sll", FUNCT3_SLL, 0x00),
+        ("slt", FUNCT3_SLT, 0x00), ("sltu", FUNCT3_SLTU, 0x00), ("xor", FUNCT3_XOR, 0x00),
+        ("srl", FUNCT3_SRL, 0x00), ("sra", FUNCT3_SRL, 0x20), ("or", FUNCT3_OR, 0x00), ("and", FUNCT3_AND, 0x00),
+    ];
+    let i_ops = [
+        ("addi", FUNCT3_ADDI), ("slti", FUNCT3_SLTI), ("sltiu", FUNCT3_SLTIU),
+        ("xori", FUNCT3_XORI), ("ori", FUNCT3_ORI), ("andi", FUNCT3_ANDI),
+    ];
+    let shift_ops = [("slli", FUNCT3_SLLI, 0x00), ("srli", FUNCT3_SRLI, 0x00), ("srai", FUNCT3_SRLI, 0x20)];
+    let loads = [("lb", FUNCT3_LB), ("lh", FUNCT3_LH), ("lw", FUNCT3_LW), ("lbu", FUNCT3_LBU), ("lhu", FUNCT3_LHU)];
+    let stores = [("sb", FUNCT3_SB), ("sh", FUNCT3_SH), ("sw", FUNCT3_SW)];
+    let branches = [
+        ("beq", FUNCT3_BEQ), ("bne", FUNCT3_BNE), ("blt", FUNCT3_BLT),
+        ("bge", FUNCT3_BGE), ("bltu", FUNCT3_BLTU), ("bgeu", FUNCT3_BGEU),
+    ];
+    // Pseudo branches: mnemonic, base branch, swap operands, compare with zero.
+    let pseudo_branches = [
+        ("beqz", FUNCT3_BEQ, false), ("bnez", FUNCT3_BNE, false), ("bltz", FUNCT3_BLT, false),
+        ("bgez", FUNCT3_BGE, false), ("bgtz", FUNCT3_BLT, true), ("blez", FUNCT3_BGE, true),
+    ];
+    let swapped_branches = [("bgt", FUNCT3_BLT), ("ble", FUNCT3_BGE), ("bgtu", FUNCT3_BLTU), ("bleu", FUNCT3_BGEU)];
+
+    if let Some(&(_, funct3, funct7)) = r_ops.iter().find(|op| op.0 == mnemonic) {
+        arity(3)?;
+        return Ok(vec![r_type(funct7, reg(2)?, reg(1)?, funct3, reg(0)?)]);
+    }
+    if let Some(&(_, funct3)) = i_ops.iter().find(|op| op.0 == mnemonic) {
+        arity(3)?;
+        return Ok(vec![i_type(value(2)?, reg(1)?, funct3, reg(0)?, I_COMP_FORMAT)?]);
+    }
+    if let Some(&(_, funct3, funct7)) = shift_ops.iter().find(|op| op.0 == mnemonic) {
+        arity(3)?;
+        let shamt = value(2)?;
+        if !(0..32).contains(&shamt) {
+            return Err(format!("shift amount {} is out of range", shamt));
+        }
+        return Ok(vec![(funct7 << 25) | ((shamt as u32) << 20) | (reg(1)? << 15) | (funct3 << 12) | (reg(0)? << 7) | I_COMP_FORMAT]);
+    }
+    if let Some(&(_, funct3)) = loads.iter().find(|op| op.0 == mnemonic) {
+        arity(2)?;
+        let (offset, base) = memory_operand(operands[1], labels)?;
+        return Ok(vec![i_type(offset, base, funct3, reg(0)?, I_LOAD_FORMAT)?]);
+    }
+    if let Some(&(_, funct3)) = stores.iter().find(|op| op.0 == mnemonic) {
+        arity(2)?;
+        let (offset, base) = memory_operand(operands[1], labels)?;
+        return Ok(vec![s_type(offset, reg(0)?, base, funct3)?]);
+    }
+    if let Some(&(_, funct3)) = branches.iter().find(|op| op.0 == mnemonic) {
+        arity(3)?;
+        return Ok(vec![b_type(relative(2)?, reg(0)?, reg(1)?, funct3)?]);
+    }
+    if let Some(&(_, funct3)) = swapped_branches.iter().find(|op| op.0 == mnemonic) {
+        arity(3)?;
+        return Ok(vec![b_type(relative(2)?, reg(1)?, reg(0)?, funct3)?]);
+    }
+    if let Some(&(_, funct3, swap)) = pseudo_branches.iter().find(|op| op.0 == mnemonic) {
+        arity(2)?;
+        let (rs1, rs2) = if swap { (0, reg(0)?) } else { (reg(0)?, 0) };
+        return Ok(vec![b_type(relative(1)?, rs1, rs2, funct3)?]);
+    }
+    let word = match mnemonic {
+        "lui" | "auipc" => {
+            arity(2)?;
+            u_type(value(1)?, reg(0)?, if mnemonic == "lui" { U_FORMAT_LUI } else { U_FORMAT_AUIPC })?
+        }
+        "jal" if operands.len() == 1 => j_type(relative(0)?, 1)?,
+        "jal" => {
+            arity(2)?;
+            j_type(relative(1)?, reg(0)?)?
+        }
+        "jalr" => match operands.len() {
+            1 => i_type(0, reg(0)?, 0, 1, I_JALR_FORMAT)?,
+            2 => {
+                let (offset, base) = match register(operands[1]) {
+                    Ok(base) => (0, base),
+                    Err(_) => memory_operand(operands[1], labels)?,
+                };
+                i_type(offset, base, 0, reg(0)?, I_JALR_FORMAT)?
+            }
+            _ => {
+                arity(3)?;
+                i_type(value(2)?, reg(1)?, 0, reg(0)?, I_JALR_FORMAT)?
+            }
+        },
+        "ecall" => ECALL,
+        "ebreak" => EBREAK,
+        "fence" => (0xFF << 20) | MISC_MEM_FORMAT,
+        "fence.i" => (1 << 12) | MISC_MEM_FORMAT,
+        // Pseudo-instructions.
+        "nop" => NOP,
+        "li" => {
+            arity(2)?;
+            let rd = reg(0)?;
+            return match eval(operands[1], &HashMap::new()) {
+                Ok(constant) => Ok(li_sequence(rd, constant)),
+                // A label: always two words, as sized in the first pass.
+                Err(_) => {
+                    let target = value(1)?;
+                    let hi = ((target + 0x800) >> 12) & 0xFFFFF;
+                    Ok(vec![u_type(hi, rd, U_FORMAT_LUI)?, i_type(sign_extend12(target), rd, FUNCT3_ADDI, rd, I_COMP_FORMAT)?])
+                }
+            };
+        }
+        "la" => {
+            arity(2)?;
+            let rd = reg(0)?;
+            let offset = (relative(1)? as i32) as i64;
+            let hi = ((offset + 0x800) >> 12) & 0xFFFFF;
+            return Ok(vec![u_type(hi, rd, U_FORMAT_AUIPC)?, i_type(sign_extend12(offset), rd, FUNCT3_ADDI, rd, I_COMP_FORMAT)?]);
+        }
+        "mv" => {
+            arity(2)?;
+            i_type(0, reg(1)?, FUNCT3_ADDI, reg(0)?, I_COMP_FORMAT)?
+        }
+        "not" => {
+            arity(2)?;
+            i_type(-1, reg(1)?, FUNCT3_XORI, reg(0)?, I_COMP_FORMAT)?
+        }
+        "neg" => {
+            arity(2)?;
+            r_type(0x20, reg(1)?, 0, FUNCT3_ADD_SUB, reg(0)?)
+        }
+        "seqz" => {
+            arity(2)?;
+            i_type(1, reg(1)?, FUNCT3_SLTIU, reg(0)?, I_COMP_FORMAT)?
+        }
+        "snez" => {
+            arity(2)?;
+            r_type(0, reg(1)?, 0, FUNCT3_SLTU, reg(0)?)
+        }
+        "sltz" => {
+            arity(2)?;
+            r_type(0, 0, reg(1)?, FUNCT3_SLT, reg(0)?)
+        }
+        "sgtz" => {
+            arity(2)?;
+            r_type(0, reg(1)?, 0, FUNCT3_SLT, reg(0)?)
+        }
+        "j" | "tail" => {
+            arity(1)?;
+            j_type(relative(0)?, 0)?
+        }
+        "call" => {
+            arity(1)?;
+            j_type(relative(0)?, 1)?
+        }
+        "jr" => {
+            arity(1)?;
+            i_type(0, reg(0)?, 0, 0, I_JALR_FORMAT)?
+        }
+        "ret" => {
+            arity(0)?;
+            i_type(0, 1, 0, 0, I_JALR_FORMAT)?
+        }
+        _ => return Err(format!("unknown instruction '{}' (only RV32I is supported)", mnemonic)),
+    };
+    Ok(vec![word])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+
+    const BASE: u32 = 0x8000_0000;
+
+    /// Disassembly of `source` assembled at `BASE`, one line per word.
+    fn listing(source: &str) -> Vec<String> {
+        let assembly = assemble(source, BASE).unwrap();
+        assembly.words.iter().enumerate()
+            .map(|(i, &word)| disassemble(word, BASE + 4 * i as u32))
+            .collect()
+    }
+
+    /// What a `lui`/`auipc` + `addi` pair (or a lone `addi`) at `pc` leaves
+    /// in its destination register.
+    fn loaded_value(words: &[u32], pc: u32) -> u32 {
+        words.iter().fold(0, |value: u32, &word| match word & 0x7F {
+            U_FORMAT_LUI => word & 0xFFFF_F000,
+            U_FORMAT_AUIPC => pc.wrapping_add(word & 0xFFFF_F000),
+            _ => value.wrapping_add(((word as i32) >> 20) as u32),
+        })
+    }
+
+    #[test]
+    fn every_instruction_round_trips() {
+        let source = [
+            "add a0, a1, a2", "sub a0, a1, a2", "sll a0, a1, a2", "slt a0, a1, a2", "sltu a0, a1, a2",
+            "xor a0, a1, a2", "srl a0, a1, a2", "sra a0, a1, a2", "or a0, a1, a2", "and a0, a1, a2",
+            "addi a0, a1, -5", "slti a0, a1, 7", "sltiu a0, a1, 2", "xori a0, a1, 255", "ori a0, a1, 16",
+            "andi a0, a1, 15", "slli a0, a1, 3", "srli a0, a1, 31", "srai a0, a1, 1",
+            "lb a0, -4(sp)", "lh a0, 2(sp)", "lw a0, 2047(sp)", "lbu a0, 0(a1)", "lhu a0, -2048(a1)",
+            "sb a0, -2048(sp)", "sh a0, 6(sp)", "sw a0, 2047(sp)",
+            "beq a0, a1, 0x80000100", "bne a0, a1, 0x80000000", "blt a0, a1, 0x80000100",
+            "bge a0, a1, 0x80000100", "bltu a0, a1, 0x80000100", "bgeu a0, a1, 0x80000100",
+            "lui a0, 0x12345", "auipc a0, 0x1", "jal t0, 0x80000000", "jalr t0, 8(a0)",
+            "fence", "fence.i", "ecall", "ebreak",
+            // Pseudo-instructions, as the disassembler prints them back.
+            "nop", "li a0, -2048", "li a0, 2047", "mv a0, a1", "not a0, a1", "neg a0, a1", "seqz a0, a1",
+            "snez a0, a1", "sltz a0, a1", "sgtz a0, a1", "beqz a0, 0x80000100", "bnez a0, 0x80000000",
+            "bltz a0, 0x80000100", "bgez a0, 0x80000100", "bgtz a0, 0x80000100", "blez a0, 0x80000100",
+            "j 0x80000000", "jal 0x80000100", "jr a0", "jalr a0", "ret",
+        ];
+        assert_eq!(listing(&source.join("\n")), source);
+    }
+
+    #[test]
+    fn pseudo_instructions_expand_to_canonical_forms() {
+        let cases = [
+            ("bgt a0, a1, 0x80000100", "blt a1, a0, 0x80000100"),
+            ("ble a0, a1, 0x80000100", "bge a1, a0, 0x80000100"),
+            ("bgtu a0, a1, 0x80000100", "bltu a1, a0, 0x80000100"),
+            ("bleu a0, a1, 0x80000100", "bgeu a1, a0, 0x80000100"),
+            ("call 0x80000100", "jal 0x80000100"),
+            ("tail 0x80000100", "j 0x80000100"),
+            ("jal ra, 0x80000100", "jal 0x80000100"),
+            ("jalr ra, 0(a0)", "jalr a0"),
+            ("jalr zero, a0", "jr a0"),
+            ("addi a0, zero, 5", "li a0, 5"),
+            ("addi a0, a1, 0", "mv a0, a1"),
+            ("xori a0, a1, -1", "not a0, a1"),
+            ("sub a0, zero, a1", "neg a0, a1"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(listing(source), [expected], "{}", source);
+        }
+    }
+
+    #[test]
+    fn li_edge_values() {
+        // The low part is sign extended, so the upper part rounds up.
+        assert_eq!(listing("li a0, 0x7FFFF800"), ["lui a0, 0x80000", "addi a0, a0, -2048"]);
+        for value in [0x7FFF_F800u32, 0x7FFF_FFFF, 0x800, 0xFFFF_F7FF, 0x8000_0000, 0x1000, (-2049i32) as u32] {
+            let words = assemble(&format!("li a0, {}", value as i32), BASE).unwrap().words;
+            assert_eq!(loaded_value(&words, BASE), value, "li a0, {}", value as i32);
+        }
+        assert_eq!(listing("li a0, -2048"), ["li a0, -2048"]);
+        assert_eq!(listing("li a0, 2048"), ["lui a0, 0x1", "addi a0, a0, -2048"]);
+        assert_eq!(listing("li a0, 0x12345000"), ["lui a0, 0x12345"]);
+    }
+
+    #[test]
+    fn la_edge_values() {
+        // 0x7FFFF800 bytes ahead: auipc rounds up to 0x80000 and the addi
+        // takes 2048 back off.
+        let words = assemble("la a0, 0x7FFFF800", 0).unwrap().words;
+        assert_eq!(words.len(), 2);
+        assert_eq!(loaded_value(&words, 0), 0x7FFF_F800);
+        // 2048 bytes behind fits the addi alone.
+        let words = assemble("la a0, 0x7FFFF800", BASE).unwrap().words;
+        assert_eq!(listing("la a0, 0x7FFFF800"), ["auipc a0, 0x0", "addi a0, a0, -2048"]);
+        assert_eq!(loaded_value(&words, BASE), BASE - 2048);
+        let words = assemble("nop\nla a0, data\n.org 0x2000\ndata: .word 1", BASE).unwrap().words;
+        assert_eq!(loaded_value(&words[1..3], BASE + 4), BASE + 0x2000);
+    }
+
+    #[test]
+    fn labels_and_directives() {
+        let assembly = assemble("start: j end\n.word 0x12345678\n.byte 1, 2\n.align 2\nend: ret", BASE).unwrap();
+        assert_eq!(assembly.words, [0x00c0_006f, 0x1234_5678, 0x0000_0201, 0x0000_8067]);
+        assert_eq!(assembly.symbols.find("end"), Some(BASE + 12));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = assemble("nop\naddi a0, a1, 4096", BASE).err().unwrap();
+        assert!(error.starts_with("2: "), "{}", error);
+        assert!(assemble("mul a0, a1, a2", BASE).is_err());
+        assert!(assemble("beq a0, a1, 0x80000001", BASE).is_err());
+    }
+}
@@ -1,9 +1,137 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
 pub type Addr = u32;
 
+/// A memory mapped device. Devices that raise interrupts are handed an
+/// `IrqLine` when they are built; the hart never takes those interrupts,
+/// see `IrqController`.
 pub trait Device: Send {
     /// `size` must be 1, 2, or 4. Address is device-local (offset) in this impl.
     fn read(&mut self, size: u8, addr: Addr) -> u32;
     fn write(&mut self, size: u8, addr: Addr, value: u32) -> Result<(), ()>;
+    /// Reads without side effects, for the debugger. Only memories can do
+    /// that; MMIO devices keep the default and return None.
+    fn peek(&self, _size: u8, _addr: Addr) -> Option<u32> { None }
+    /// Advances the device by `cycles` CPU cycles. Called by the `Bus`
+    /// between instruction batches, so timers and FIFOs can make progress.
+    fn tick(&mut self, _cycles: u64) {}
+    /// Puts the device back in its power-on state.
+    fn reset(&mut self) {}
+    /// Serializes the device state, see `StateWriter`. Stateless devices
+    /// keep the default empty blob.
+    fn save(&self) -> Vec<u8> { Vec::new() }
+    /// Loads a blob produced by `save` on an identically configured device.
+    fn restore(&mut self, _state: &[u8]) -> Result<(), ()> { Ok(()) }
+    /// Bus masters (virtio queues, DMA engines) return true when they have
+    /// work that needs to access memory through the `Bus`.
+    fn dma_pending(&self) -> bool { false }
+    /// Called by the `Bus` with itself when `dma_pending` is true. The
+    /// device's own region is unmapped for the duration of the call.
+    fn dma(&mut self, _bus: &mut Bus) {}
+    /// Drops output to the host while set, used when re-executing for
+    /// reverse debugging.
+    fn set_muted(&mut self, _muted: bool) {}
+    /// Why re-executing this device can't be undone by restoring its
+    /// state, e.g. because it writes a host file.
+    fn replay_hazard(&self) -> Option<String> { None }
+}
+
+/// Level triggered interrupt lines, one bit per line. The hart doesn't take
+/// interrupts: nothing latches the lines into `mip` or traps on them, so a
+/// raised line is only observable (`info devices`, `-v`, the cosim library,
+/// the PLIC's pending and claim registers) and guests have to poll instead.
+#[derive(Clone, Default)]
+pub struct IrqController {
+    pending: Arc<AtomicU32>,
+}
+impl IrqController {
+    pub fn line(&self, irq: u32) -> IrqLine {
+        assert!(irq < 32, "IRQ line {} out of range", irq);
+        IrqLine { pending: Arc::clone(&self.pending), mask: 1 << irq }
+    }
+    pub fn pending(&self) -> u32 {
+        self.pending.load(Ordering::Relaxed)
+    }
+    /// Drops every line, devices drive them again on reset or restore.
+    pub fn clear(&self) {
+        self.pending.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Output side of an interrupt line, owned by the device driving it.
+pub struct IrqLine {
+    pending: Arc<AtomicU32>,
+    mask: u32,
+}
+impl IrqLine {
+    pub fn set(&self, level: bool) {
+        if level {
+            self.pending.fetch_or(self.mask, Ordering::Relaxed);
+        } else {
+            self.pending.fetch_and(!self.mask, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Little endian encoder for `Device::save`.
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+impl StateWriter {
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+    /// Length prefixed byte string.
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Decoder for blobs written by `StateWriter`, fields are read back in order.
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+}
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ()> {
+        if self.buf.len() < len {
+            return Err(());
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+    pub fn u32(&mut self) -> Result<u32, ()> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64, ()> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn bool(&mut self) -> Result<bool, ()> {
+        Ok(self.take(1)?[0] != 0)
+    }
+    pub fn bytes(&mut self) -> Result<&'a [u8], ()> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    /// Fails if the blob has trailing data, i.e. it came from another device.
+    pub fn finish(self) -> Result<(), ()> {
+        if self.buf.is_empty() { Ok(()) } else { Err(()) }
+    }
 }
 
 struct Region {
@@ -12,27 +140,42 @@ struct Region {
     device: Box<dyn Device>,
 }
 
+#[derive(Default)]
 pub struct Bus {
     regions: Vec<Region>,
+    irqs: IrqController,
 }
 
 impl Bus{
     pub fn new() -> Self {
-        Self { regions: Vec::new() }
+        Self::default()
     }
 
     pub fn add_region(&mut self, base: Addr, size: u32, device: Box<dyn Device>) {
         self.regions.push(Region { base, size, device });
     }
 
-    fn find_region(&mut self, addr: Addr) -> Option<&mut Region> {
-        self.regions.iter_mut()
-            .find(|region| addr >= region.base && addr < region.base + region.size)
-            .map(|v| v as _)
+    pub fn irq_line(&self, irq: u32) -> IrqLine {
+        self.irqs.line(irq)
+    }
+
+    /// The interrupt lines of this bus, for the interrupt controller.
+    pub fn irq_controller(&self) -> IrqController {
+        self.irqs.clone()
+    }
+
+    pub fn pending_irqs(&self) -> u32 {
+        self.irqs.pending()
+    }
+
+    fn find_region(&self, addr: Addr) -> Option<usize> {
+        self.regions.iter()
+            .position(|region| addr.wrapping_sub(region.base) < region.size)
     }
 
     pub fn read(&mut self, size: u8, addr: Addr) -> Result<u32, ()> {
-        if let Some(region) = self.find_region(addr) {
+        if let Some(index) = self.find_region(addr) {
+            let region = &mut self.regions[index];
             let offset = addr - region.base;
             Ok(region.device.read(size, offset))
         } else {
@@ -40,12 +183,92 @@ impl Bus{
         }
     }
 
+    /// Reads RAM or ROM without touching any device. None for unmapped
+    /// addresses and MMIO registers.
+    pub fn peek(&self, size: u8, addr: Addr) -> Option<u32> {
+        let region = &self.regions[self.find_region(addr)?];
+        region.device.peek(size, addr - region.base)
+    }
+
     pub fn write(&mut self, size: u8, addr: Addr, value: u32) -> Result<(), ()> {
-        if let Some(region) = self.find_region(addr) {
+        if let Some(index) = self.find_region(addr) {
+            let region = &mut self.regions[index];
             let offset = addr - region.base;
-            region.device.write(size, offset, value)
+            let result = region.device.write(size, offset, value);
+            if region.device.dma_pending() {
+                self.run_dma(index);
+            }
+            result
         } else {
             Err(())
         }
     }
-}
\ No newline at end of file
+
+    /// Byte-wise copy out of the address space, for bus masters.
+    pub fn read_bytes(&mut self, addr: Addr, buf: &mut [u8]) -> Result<(), ()> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read(1, addr.wrapping_add(i as u32))? as u8;
+        }
+        Ok(())
+    }
+
+    /// Byte-wise copy into the address space, for bus masters.
+    pub fn write_bytes(&mut self, addr: Addr, data: &[u8]) -> Result<(), ()> {
+        for (i, &byte) in data.iter().enumerate() {
+            self.write(1, addr.wrapping_add(i as u32), byte as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Advances every device by `cycles` and gives bus masters with pending
+    /// work (e.g. received console input) a chance to run. Called between
+    /// instruction batches.
+    pub fn tick(&mut self, cycles: u64) {
+        for index in 0..self.regions.len() {
+            self.regions[index].device.tick(cycles);
+            if self.regions[index].device.dma_pending() {
+                self.run_dma(index);
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.irqs.clear();
+        for region in &mut self.regions {
+            region.device.reset();
+        }
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        for region in &mut self.regions {
+            region.device.set_muted(muted);
+        }
+    }
+
+    pub fn replay_hazard(&self) -> Option<String> {
+        self.regions.iter().find_map(|region| region.device.replay_hazard())
+    }
+
+    /// One state blob per region, in mapping order.
+    pub fn save(&self) -> Vec<Vec<u8>> {
+        self.regions.iter().map(|region| region.device.save()).collect()
+    }
+
+    pub fn restore(&mut self, states: &[Vec<u8>]) -> Result<(), String> {
+        if states.len() != self.regions.len() {
+            return Err(format!("state has {} devices, the bus has {}", states.len(), self.regions.len()));
+        }
+        self.irqs.clear();
+        for (region, state) in self.regions.iter_mut().zip(states) {
+            region.device.restore(state)
+                .map_err(|_| format!("state does not match the device at 0x{:08X}", region.base))?;
+        }
+        Ok(())
+    }
+
+    fn run_dma(&mut self, index: usize) {
+        let mut region = self.regions.remove(index);
+        region.device.dma(self);
+        self.regions.insert(index, region);
+    }
+}
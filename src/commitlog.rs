@@ -0,0 +1,286 @@
+//! JSON commit log of the RISC-Y testbench, one record per retired
+//! instruction:
+//! ```text
+//! {"pc": 2147483648, "instr": 663, "event_t": "REGISTER_WRITE", "reg_changed": 5, "reg_val": 2147483648,
+//!  "status": {"decode": 10, "rename": 10, "issue": 12, "dispatch": 13, "resolve": 15, "commit": 17}},
+//! ```
+//! The testbench writes each record on a line of its own, separated by
+//! commas, and the file may or may not be wrapped in `[ ]`.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::eventlog::{LogHeader, LogReader, LogWriter, MAGIC, TOOL};
+use crate::types::{Event, EventType, PipelineTimes};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CommitEvent {
+    MemoryWrite,
+    MemoryRead,
+    RegisterWrite,
+    FlowChange,
+}
+
+/// Numbers are Verilog `%d`, so 32 bit values may come out signed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitRecord {
+    pub pc: i64,
+    pub instr: i64,
+    pub event_t: CommitEvent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_addr: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_val: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reg_changed: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reg_val: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_pc: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<PipelineTimes>,
+}
+
+impl CommitRecord {
+    /// The record's effect as events of the instruction retired on `cycle`,
+    /// its pipeline stamps included.
+    pub fn to_events(&self, cycle: u64, effects: &mut Vec<Event>) {
+        let (pc, opcode) = (self.pc as u32, self.instr as u32);
+        let word = |value: Option<i64>| value.unwrap_or(0) as u32;
+        // The testbench doesn't log access widths, the encoding has them.
+        let size = 1 << ((opcode >> 12) & 0x3);
+        let instr_type = match self.event_t {
+            CommitEvent::MemoryWrite => EventType::MemWrite { addr: word(self.mem_addr), value: word(self.mem_val), size },
+            CommitEvent::MemoryRead => EventType::MemRead { addr: word(self.mem_addr), value: word(self.mem_val), size },
+            CommitEvent::RegisterWrite => EventType::RegWrite { reg: self.reg_changed.unwrap_or(0), value: word(self.reg_val) },
+            CommitEvent::FlowChange => EventType::FlowChange { new_pc: word(self.new_pc) },
+        };
+        effects.push(Event { cycle, pc, opcode, instr_type });
+        if let Some(times) = self.status {
+            effects.push(Event { cycle, pc, opcode, instr_type: EventType::Pipeline(times) });
+        }
+    }
+
+    /// The record the testbench would write for an instruction: the store,
+    /// else the load, else the register write, else where it went.
+    pub fn from_events(effects: &[Event]) -> Self {
+        let first = &effects[0];
+        let mut record = Self {
+            pc: first.pc as i64,
+            instr: first.opcode as i64,
+            event_t: CommitEvent::FlowChange,
+            mem_addr: None,
+            mem_val: None,
+            reg_changed: None,
+            reg_val: None,
+            new_pc: Some(first.pc as i64),
+            status: None,
+        };
+        let mut rank = 0;
+        for effect in effects {
+            let (event_t, effect_rank) = match effect.instr_type {
+                EventType::MemWrite { .. } => (CommitEvent::MemoryWrite, 4),
+                EventType::MemRead { .. } => (CommitEvent::MemoryRead, 3),
+                EventType::RegWrite { .. } | EventType::FlowLink { .. } => (CommitEvent::RegisterWrite, 2),
+                EventType::FlowChange { .. } => (CommitEvent::FlowChange, 1),
+                EventType::Pipeline(times) => {
+                    record.status = Some(times);
+                    continue;
+                }
+                EventType::Trap { .. } | EventType::CsrWrite { .. } => continue,
+            };
+            if effect_rank <= rank {
+                continue;
+            }
+            rank = effect_rank;
+            record.event_t = event_t;
+            (record.mem_addr, record.mem_val, record.reg_changed, record.reg_val, record.new_pc) = match effect.instr_type {
+                EventType::MemWrite { addr, value, .. } | EventType::MemRead { addr, value, .. } => {
+                    (Some(addr as i64), Some(value as i64), None, None, None)
+                }
+                EventType::RegWrite { reg, value } => (None, None, Some(reg), Some(value as i64), None),
+                EventType::FlowLink { register, .. } => (None, None, Some(register), Some(first.pc.wrapping_add(4) as i64), None),
+                EventType::FlowChange { new_pc } => (None, None, None, None, Some(new_pc as i64)),
+                _ => unreachable!(),
+            };
+        }
+        record
+    }
+}
+
+pub struct CommitLogReader<R: BufRead> {
+    input: R,
+    line: String,
+    line_number: usize,
+    /// Instructions read so far, the cycle the next one is given.
+    retired: u64,
+}
+
+impl CommitLogReader<BufReader<File>> {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Ok(Self::new(BufReader::with_capacity(64 * 1024, File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> CommitLogReader<R> {
+    pub fn new(input: R) -> Self {
+        Self { input, line: String::new(), line_number: 0, retired: 0 }
+    }
+
+    /// The next record, None at the end of the log.
+    pub fn read_record(&mut self) -> io::Result<Option<CommitRecord>> {
+        loop {
+            self.line.clear();
+            if self.input.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            let text = self.line.trim().trim_start_matches('[').trim_end_matches(']').trim_end_matches(',').trim();
+            if text.is_empty() {
+                continue;
+            }
+            return serde_json::from_str(text)
+                .map(Some)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("line {}: {}", self.line_number, e)));
+        }
+    }
+
+    /// Like `LogReader::read_instruction`.
+    pub fn read_instruction(&mut self, effects: &mut Vec<Event>) -> io::Result<bool> {
+        effects.clear();
+        let Some(record) = self.read_record()? else {
+            return Ok(false);
+        };
+        record.to_events(self.retired, effects);
+        self.retired += 1;
+        Ok(true)
+    }
+}
+
+/// Whether `path` is a binary event log rather than a JSON commit log.
+pub fn is_event_log(path: &str) -> io::Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    let read = io::Read::read(&mut File::open(path)?, &mut magic)?;
+    Ok(read == magic.len() && magic == MAGIC)
+}
+
+/// `convert-log` subcommand: an event log becomes a JSON commit log and
+/// the other way around. Going to JSON keeps one effect per instruction,
+/// as the testbench does.
+pub fn convert(input: &str, output: &str) -> Result<(), Box<dyn Error>> {
+    let mut effects = Vec::new();
+    let mut count = 0;
+    if is_event_log(input)? {
+        let mut reader = LogReader::open(input)?;
+        let mut out = BufWriter::with_capacity(64 * 1024, File::create(output)?);
+        write!(out, "[")?;
+        while reader.read_instruction(&mut effects)? {
+            let separator = if count == 0 { "" } else { "," };
+            write!(out, "{}\n{}", separator, serde_json::to_string(&CommitRecord::from_events(&effects))?)?;
+            count += 1;
+        }
+        writeln!(out, "\n]")?;
+        out.flush()?;
+    } else {
+        let mut reader = CommitLogReader::open(input)?;
+        let mut writer = None;
+        while reader.read_instruction(&mut effects).map_err(|e| format!("{}: {}", input, e))? {
+            if writer.is_none() {
+                let mut header = LogHeader::new("rv32i", effects[0].pc, 0);
+                header.tool = format!("{} from {}", TOOL, input);
+                writer = Some(LogWriter::create(output, &header)?);
+            }
+            writer.as_mut().unwrap().write_instruction(&effects)?;
+            count += 1;
+        }
+        match writer.as_mut() {
+            Some(writer) => writer.flush()?,
+            None => return Err(format!("{}: no records", input).into()),
+        }
+    }
+    println!("Converted {} instructions from {} to {}", count, input, output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LW: u32 = 0x0045_2583; // lw a1, 4(a0)
+    const SB: u32 = 0x00b5_0023; // sb a1, 0(a0)
+
+    fn event(pc: u32, opcode: u32, instr_type: EventType) -> Event {
+        Event { cycle: 0, pc, opcode, instr_type }
+    }
+
+    #[test]
+    fn json_to_events() {
+        let times = PipelineTimes { decode: 10, rename: 10, issue: 12, dispatch: 13, resolve: 15, commit: 17 };
+        let text = concat!(
+            "[\n",
+            r#"{"pc": -2147483648, "instr": 4531587, "event_t": "MEMORY_READ", "mem_addr": -2147479548, "mem_val": -1,"#,
+            r#" "status": {"decode": 10, "rename": 10, "issue": 12, "dispatch": 13, "resolve": 15, "commit": 17}},"#, "\n",
+            "\n",
+            r#"{"pc": 2147483652, "instr": 11862051, "event_t": "MEMORY_WRITE", "mem_addr": 4096, "mem_val": 128}"#, "\n",
+            "]\n",
+        );
+        let mut reader = CommitLogReader::new(text.as_bytes());
+        let mut effects = Vec::new();
+        assert!(reader.read_instruction(&mut effects).unwrap());
+        assert_eq!(effects, [
+            event(0x8000_0000, LW, EventType::MemRead { addr: 0x8000_1004, value: 0xFFFF_FFFF, size: 4 }),
+            event(0x8000_0000, LW, EventType::Pipeline(times)),
+        ]);
+        assert!(reader.read_instruction(&mut effects).unwrap());
+        let store = Event { cycle: 1, ..event(0x8000_0004, SB, EventType::MemWrite { addr: 0x1000, value: 0x80, size: 1 }) };
+        assert_eq!(effects, [store]);
+        assert!(!reader.read_instruction(&mut effects).unwrap());
+    }
+
+    #[test]
+    fn bad_records_name_the_line() {
+        let mut reader = CommitLogReader::new("[\n{\"pc\": 0, \"instr\": 19, \"event_t\": \"JUMP\"}\n]".as_bytes());
+        let error = reader.read_record().err().unwrap();
+        assert!(error.to_string().starts_with("line 2: "), "{}", error);
+    }
+
+    #[test]
+    fn events_to_json() {
+        // A load also writes its register; the testbench logs the load.
+        let load = [
+            event(0x8000_0000, LW, EventType::MemRead { addr: 0x8000_1004, value: 7, size: 4 }),
+            event(0x8000_0000, LW, EventType::RegWrite { reg: 11, value: 7 }),
+        ];
+        assert_eq!(
+            serde_json::to_string(&CommitRecord::from_events(&load)).unwrap(),
+            r#"{"pc":2147483648,"instr":4531587,"event_t":"MEMORY_READ","mem_addr":2147487748,"mem_val":7}"#,
+        );
+        let call = [event(0x8000_0010, 0x0080_00ef, EventType::FlowLink { new_pc: 0x8000_0018, register: 1 })];
+        let record = CommitRecord::from_events(&call);
+        assert_eq!((record.event_t, record.reg_changed, record.reg_val), (CommitEvent::RegisterWrite, Some(1), Some(0x8000_0014)));
+        // Nothing the testbench logs: the instruction fell through.
+        let trap = [event(0x8000_0020, 0x0010_0073, EventType::Trap { cause: 3, tval: 0 })];
+        let record = CommitRecord::from_events(&trap);
+        assert_eq!((record.event_t, record.new_pc), (CommitEvent::FlowChange, Some(0x8000_0020)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let instructions = [
+            event(0x8000_0000, 0x0050_0513, EventType::RegWrite { reg: 10, value: 5 }),
+            event(0x8000_0004, SB, EventType::MemWrite { addr: 0x1000_0000, value: 0x41, size: 1 }),
+            event(0x8000_0008, LW, EventType::MemRead { addr: 0x8000_0ffc, value: 0x8000_0000, size: 4 }),
+            event(0x8000_000C, 0xfe05_02e3, EventType::FlowChange { new_pc: 0x7FFF_FFF0 }),
+        ];
+        for original in instructions {
+            let json = serde_json::to_string(&CommitRecord::from_events(&[original])).unwrap();
+            let mut effects = Vec::new();
+            serde_json::from_str::<CommitRecord>(&json).unwrap().to_events(0, &mut effects);
+            assert_eq!(effects, [original], "{}", json);
+        }
+    }
+}
@@ -4,10 +4,29 @@ pub const SCREEN_HEIGHT: usize = 480;
 
 // MMIO Base Addresses
 pub const UART0_BASE: u32 = 0x1000_0000;
-pub const SCREEN_CSR_ENABLE: u32 = 0x8800_2800;
 pub const SCREEN_CSR_ADDR: u32 = 0x8800_2800;
 pub const RAM_BASE: u32 = 0x8000_0000;
 pub const VGA_TEXT_MODE_BASE: u32 = 0x8800_0000;
+// Interrupt controllers at their usual SiFive addresses, mapped on every
+// board and described in the device tree
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x0001_0000;
+pub const PLIC_BASE: u32 = 0x0C00_0000;
+pub const PLIC_SIZE: u32 = 0x0400_0000;
+// Boot chain
+pub const BOOTROM_BASE: u32 = 0x0000_0000;
+pub const STAGE2_BASE: u32 = 0x0080_0000;
+pub const APP_IMAGE_BASE: u32 = 0x0100_0000;
+// User mode (--user): flat memory from 0, stack at the top
+pub const USER_MEM_SIZE: u32 = 0x1000_0000;
+pub const USER_STACK_SIZE: u32 = 0x0080_0000;
+// Devices are ticked on multiples of this many cycles, so a run is
+// reproducible no matter how it is split into batches.
+pub const DEVICE_QUANTUM: u64 = 1000;
+// In-memory checkpoints kept for reverse execution, oldest dropped first
+pub const MAX_CHECKPOINTS: usize = 16;
+// Core clock of the RISC-Y board, also its timebase (one tick per cycle)
+pub const BOARD_CLOCK_HZ: u32 = 10_000_000;
 
 
 /********* Opcode Formats *********/
@@ -19,9 +38,26 @@ pub const I_ENV_FORMAT :u32       = 0b1110011;
 pub const S_FORMAT :u32           = 0b0100011;
 pub const B_FORMAT :u32           = 0b1100011;
 pub const J_FORMAT :u32           = 0b1101111;
+pub const MISC_MEM_FORMAT :u32    = 0b0001111; // fence, fence.i
 pub const U_FORMAT_LUI :u32       = 0b0110111;   // U-format
 pub const U_FORMAT_AUIPC :u32     = 0b0010111; // U-format
 
+/********* I-ENV-format **********/
+pub const ECALL :u32              = 0x0000_0073;
+pub const EBREAK :u32             = 0x0010_0073;
+/// mcause of an instruction the hart doesn't implement (CSR accesses).
+pub const CAUSE_ILLEGAL_INSTRUCTION :u32 = 2;
+/// mcause of an ebreak.
+pub const CAUSE_BREAKPOINT :u32   = 3;
+/// mcause of an ecall from U-mode.
+pub const CAUSE_ECALL_U :u32      = 8;
+/// mcause of an ecall from M-mode.
+pub const CAUSE_ECALL_M :u32      = 11;
+/// Exit code of a hart stopped by a trap (an ecall/ebreak nothing
+/// services, a CSR access), the shell's code for a process killed by
+/// SIGTRAP. There is no trap vector to enter yet.
+pub const TRAP_EXIT_CODE :i32     = 128 + 5;
+
 
 /************ Funct3 *************/
 /*********** R-format ************/
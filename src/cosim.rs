@@ -0,0 +1,294 @@
+//! Lockstep co-simulation library: the emulator as the golden model inside
+//! an RTL testbench, stepped one instruction per commit of the core under
+//! test. The C interface is declared in `cosim/riscy_cosim.h`.
+
+use std::any::Any;
+use std::error::Error;
+use std::ffi::{c_char, CStr, CString};
+use std::io::Write;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc;
+
+use crate::constants::*;
+use crate::cpu::Cpu;
+use crate::loader::BootImage;
+use crate::platform::Platform;
+use crate::types::EventType;
+
+pub const RISCY_MEM_NONE: u8 = 0;
+pub const RISCY_MEM_READ: u8 = 1;
+pub const RISCY_MEM_WRITE: u8 = 2;
+
+/// What one retired instruction did, `riscy_commit_t` in the header.
+#[repr(C)]
+#[derive(Default)]
+pub struct RiscyCommit {
+    /// Instructions retired before this one.
+    pub cycle: u64,
+    pub pc: u32,
+    pub insn: u32,
+    pub next_pc: u32,
+    /// Register written, 0 for none.
+    pub rd: u8,
+    pub mem_op: u8,
+    pub mem_size: u8,
+    pub trapped: u8,
+    pub rd_value: u32,
+    pub mem_addr: u32,
+    /// Value loaded (before sign extension) or stored.
+    pub mem_value: u32,
+    pub trap_cause: u32,
+}
+
+pub struct Cosim {
+    platform: Platform,
+    cpu: Cpu,
+    uart_rx: mpsc::Receiver<char>,
+    /// Values the next loads from these addresses return instead of what
+    /// the bus has, oldest first.
+    forced_loads: Vec<(u32, u32)>,
+    /// Set when the core panicked, until the next load.
+    failed: bool,
+    error: CString,
+}
+
+impl Cosim {
+    fn new(platform: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let platform = match platform {
+            Some(path) => Platform::from_file(path)?,
+            None => Platform::risc_y(),
+        };
+        let (uart_tx, uart_rx) = mpsc::channel();
+        let board = platform.build(uart_tx)?;
+        let cpu = Cpu::new(board.bus, platform.reset_vector);
+        Ok(Self { platform, cpu, uart_rx, forced_loads: Vec::new(), failed: false, error: CString::default() })
+    }
+
+    fn load(&mut self, program: &str) -> Result<(), Box<dyn Error>> {
+        BootImage::new(program, &self.platform, false)?.load(&mut self.cpu)?;
+        self.forced_loads.clear();
+        self.failed = false;
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<RiscyCommit, String> {
+        if self.failed {
+            return Err("the core stopped on an error, load a program again".to_string());
+        }
+        if let Some(code) = self.cpu.exit_code() {
+            return Err(format!("the program exited (code {})", code));
+        }
+        let pc = self.cpu.pc();
+        let insn = self.cpu.peek_mem(4, pc).ok_or_else(|| format!("instruction fetch from unmapped 0x{:08x}", pc))?;
+        let forced = self.take_forced_load(insn);
+        let cpu = &mut self.cpu;
+        let events = catch_unwind(AssertUnwindSafe(|| cpu.run(1, false, true))).map_err(|panic| {
+            self.failed = true;
+            panic_message(panic)
+        })?;
+        let mut commit = RiscyCommit { cycle: self.cpu.cycles() - 1, pc, insn, ..Default::default() };
+        for event in &events {
+            match event.instr_type {
+                EventType::RegWrite { reg, value } if reg != 0 => (commit.rd, commit.rd_value) = (reg, value),
+                EventType::FlowLink { register, .. } if register != 0 => (commit.rd, commit.rd_value) = (register, pc.wrapping_add(4)),
+                EventType::MemRead { addr, value, size } => {
+                    (commit.mem_op, commit.mem_addr, commit.mem_value, commit.mem_size) = (RISCY_MEM_READ, addr, value, size);
+                }
+                EventType::MemWrite { addr, value, size } => {
+                    (commit.mem_op, commit.mem_addr, commit.mem_value, commit.mem_size) = (RISCY_MEM_WRITE, addr, value, size);
+                }
+                EventType::Trap { cause, .. } => (commit.trapped, commit.trap_cause) = (1, cause),
+                _ => {}
+            }
+        }
+        // A load that faulted produced no read, its trap is reported as is.
+        if let Some(value) = forced.filter(|_| commit.mem_op == RISCY_MEM_READ) {
+            let bits = commit.mem_size as u32 * 8;
+            let value = if bits < 32 { value & ((1 << bits) - 1) } else { value };
+            // lb and lh sign extend, funct3 bit 2 marks the unsigned loads.
+            let extended = if insn & (1 << 14) == 0 && bits < 32 {
+                (((value << (32 - bits)) as i32) >> (32 - bits)) as u32
+            } else {
+                value
+            };
+            let rd = (insn >> 7) & 0x1F;
+            self.cpu.write_reg(rd as usize, extended);
+            commit.mem_value = value;
+            if rd != 0 {
+                commit.rd_value = extended;
+            }
+        }
+        commit.next_pc = self.cpu.pc();
+        self.forward_uart();
+        Ok(commit)
+    }
+
+    /// The forced value for `insn` if it is a load from a forced address.
+    fn take_forced_load(&mut self, insn: u32) -> Option<u32> {
+        if insn & 0x7F != I_LOAD_FORMAT || self.forced_loads.is_empty() {
+            return None;
+        }
+        let imm = ((insn as i32) >> 20) as u32;
+        let addr = self.cpu.read_reg(((insn >> 15) & 0x1F) as usize).wrapping_add(imm);
+        let index = self.forced_loads.iter().position(|&(forced, _)| forced == addr)?;
+        Some(self.forced_loads.remove(index).1)
+    }
+
+    /// Program output goes to the testbench's stdout.
+    fn forward_uart(&mut self) {
+        let text: String = self.uart_rx.try_iter().collect();
+        if !text.is_empty() {
+            print!("{}", text);
+            let _ = std::io::stdout().flush();
+        }
+    }
+
+    fn set_error(&mut self, message: String) {
+        self.error = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    panic.downcast_ref::<String>().cloned()
+        .or_else(|| panic.downcast_ref::<&str>().map(|text| text.to_string()))
+        .unwrap_or_else(|| "the core panicked".to_string())
+}
+
+/// Builds the board described by the platform file, the RISC-Y board for
+/// NULL. Returns NULL on failure, with the reason printed to stderr.
+///
+/// # Safety
+/// `platform` is NULL or a NUL terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn riscy_cosim_create(platform: *const c_char) -> Option<Box<Cosim>> {
+    // SAFETY: checked for NULL, the caller passes a C string.
+    let platform = (!platform.is_null()).then(|| unsafe { CStr::from_ptr(platform) }.to_string_lossy().into_owned());
+    match Cosim::new(platform.as_deref()) {
+        Ok(sim) => Some(Box::new(sim)),
+        Err(e) => {
+            eprintln!("riscy_cosim_create: {}", e);
+            None
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_destroy(sim: Option<Box<Cosim>>) {
+    drop(sim);
+}
+
+/// Resets the machine and loads a hex, assembly or ELF program like the
+/// emulator does. Returns 0 on success.
+///
+/// # Safety
+/// `program` is a NUL terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn riscy_cosim_load(sim: Option<&mut Cosim>, program: *const c_char) -> i32 {
+    let Some(sim) = sim else { return -1 };
+    if program.is_null() {
+        return -1;
+    }
+    // SAFETY: checked for NULL, the caller passes a C string.
+    let program = unsafe { CStr::from_ptr(program) }.to_string_lossy().into_owned();
+    match sim.load(&program) {
+        Ok(()) => 0,
+        Err(e) => {
+            sim.set_error(format!("{}: {}", program, e));
+            -1
+        }
+    }
+}
+
+/// Executes one instruction and describes it in `commit`, which may be
+/// NULL. Returns 0, or -1 when the instruction could not be executed.
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_step(sim: Option<&mut Cosim>, commit: Option<&mut RiscyCommit>) -> i32 {
+    let Some(sim) = sim else { return -1 };
+    match sim.step() {
+        Ok(retired) => {
+            if let Some(commit) = commit {
+                *commit = retired;
+            }
+            0
+        }
+        Err(e) => {
+            sim.set_error(e);
+            -1
+        }
+    }
+}
+
+/// Reason for the last failed call, "" if none.
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_last_error(sim: Option<&Cosim>) -> *const c_char {
+    sim.map_or(c"".as_ptr(), |sim| sim.error.as_ptr())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_pc(sim: Option<&Cosim>) -> u32 {
+    sim.map_or(0, |sim| sim.cpu.pc())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_set_pc(sim: Option<&mut Cosim>, pc: u32) {
+    if let Some(sim) = sim {
+        sim.cpu.set_pc(pc);
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_reg(sim: Option<&Cosim>, reg: u32) -> u32 {
+    sim.map_or(0, |sim| sim.cpu.read_reg(reg as usize & 0x1F))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_set_reg(sim: Option<&mut Cosim>, reg: u32, value: u32) {
+    if let Some(sim) = sim {
+        sim.cpu.write_reg(reg as usize & 0x1F, value);
+    }
+}
+
+/// Reads through the bus, device side effects included. `size` is 1, 2
+/// or 4. Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_read_mem(sim: Option<&mut Cosim>, addr: u32, size: u8, value: Option<&mut u32>) -> i32 {
+    match (sim.and_then(|sim| sim.cpu.bus_mut().read(size, addr).ok()), value) {
+        (Some(read), Some(value)) => {
+            *value = read;
+            0
+        }
+        _ => -1,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_write_mem(sim: Option<&mut Cosim>, addr: u32, size: u8, value: u32) -> i32 {
+    match sim.and_then(|sim| sim.cpu.poke_mem(size, addr, value)) {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+/// Drives interrupt line `line` (0 to 31) as the RTL's interrupt sources
+/// do. This core doesn't take interrupts yet, only the pending set changes.
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_set_irq(sim: Option<&mut Cosim>, line: u32, level: i32) {
+    if let Some(sim) = sim && line < 32 {
+        sim.cpu.bus_mut().irq_line(line).set(level != 0);
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_pending_irqs(sim: Option<&Cosim>) -> u32 {
+    sim.map_or(0, |sim| sim.cpu.pending_irqs())
+}
+
+/// The next load from `addr` returns `value` instead of what the device
+/// has, for MMIO reads the RTL saw differently (timers, status registers).
+/// The device is still read, so its side effects happen.
+#[unsafe(no_mangle)]
+pub extern "C" fn riscy_cosim_force_load(sim: Option<&mut Cosim>, addr: u32, value: u32) {
+    if let Some(sim) = sim {
+        sim.forced_loads.push((addr, value));
+    }
+}
@@ -2,6 +2,9 @@ use colored::*;
 
 // cpu.rs
 use crate::bus::Bus;
+use crate::linux_user::LinuxUser;
+use crate::snapshot::Snapshot;
+use crate::semihosting::{Semihosting, SEMIHOSTING_ENTRY, SEMIHOSTING_EXIT};
 
 use crate::constants::*;
 use crate::types::*;
@@ -11,6 +14,11 @@ pub struct Cpu {
     pc: u32,
     bus: Bus,
     cycles: u64,
+    semihosting: Option<Semihosting>,
+    linux_user: Option<LinuxUser>,
+    exit_code: Option<i32>,
+    /// Cycle count the devices were last brought up to.
+    device_cycles: u64,
 }
 
 
@@ -19,16 +27,36 @@ const OPCODE_MASK: u32 = 0x7F;
 
 impl Cpu {
     pub fn new(bus: Bus, pc: u32) -> Self {
-        let mut regs = [0; 32];
-        // hack for matching emulation program....
-        regs[1]=0x00000728;
         Self {
-            regs,
+            regs: Self::initial_regs(),
             pc,
             bus,
             cycles: 0,
+            semihosting: None,
+            linux_user: None,
+            exit_code: None,
+            device_cycles: 0,
         }
     }
+    fn initial_regs() -> [u32; 32] {
+        let mut regs = [0; 32];
+        // hack for matching emulation program....
+        regs[1]=0x00000728;
+        regs
+    }
+    /// Power-on state for the hart and every device. Memory contents are
+    /// left to the loader.
+    pub fn reset(&mut self, pc: u32) {
+        self.regs = Self::initial_regs();
+        self.pc = pc;
+        self.cycles = 0;
+        self.device_cycles = 0;
+        self.exit_code = None;
+        self.bus.reset();
+    }
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
     // Used for debugging
     #[allow(dead_code)]
     pub fn read_mem(&mut self, size: u8, addr: u32) -> u32 {
@@ -45,14 +73,100 @@ impl Cpu {
             self.regs[reg]
         }
     }
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+    }
+    /// Memory read for the debugger, without device side effects. None
+    /// for unmapped addresses and MMIO registers.
+    pub fn peek_mem(&self, size: u8, addr: u32) -> Option<u32> {
+        self.bus.peek(size, addr)
+    }
+    pub fn poke_mem(&mut self, size: u8, addr: u32, value: u32) -> Option<()> {
+        self.bus.write(size, addr, value).ok()
+    }
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { regs: self.regs, pc: self.pc, cycles: self.cycles, devices: self.bus.save() }
+    }
+    /// Loads a snapshot taken on the same platform.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        self.bus.restore(&snapshot.devices)?;
+        self.regs = snapshot.regs;
+        self.pc = snapshot.pc;
+        self.cycles = snapshot.cycles;
+        self.device_cycles = snapshot.cycles - snapshot.cycles % DEVICE_QUANTUM;
+        self.exit_code = None;
+        Ok(())
+    }
+    /// Executes up to `count` instructions, stopping early if the program
+    /// exits. Devices are ticked on every DEVICE_QUANTUM boundary.
+    pub fn run(&mut self, count: u64, verbose: bool, instr_log: bool) -> Vec<Event> {
+        let end = self.cycles + count;
+        let mut event_log = Vec::new();
+        while self.cycles < end && self.exit_code.is_none() {
+            let boundary = (self.cycles / DEVICE_QUANTUM + 1) * DEVICE_QUANTUM;
+            event_log.extend(self.tick(verbose, boundary.min(end) - self.cycles, instr_log));
+            if self.cycles.is_multiple_of(DEVICE_QUANTUM) {
+                self.bus.tick(self.cycles - self.device_cycles);
+                self.device_cycles = self.cycles;
+            }
+        }
+        event_log
+    }
+    /// Services the `slli/ebreak/srai` sequence on the host instead of trapping.
+    pub fn enable_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(semihosting);
+    }
+    /// Turns ECALLs into Linux syscalls serviced by the host (--user).
+    pub fn enable_linux_user(&mut self, linux_user: LinuxUser) {
+        self.linux_user = Some(linux_user);
+    }
+    /// Why instructions can have effects on the host (semihosting or
+    /// syscalls, devices writing host files) which a checkpoint can't roll
+    /// back, if they can.
+    pub fn replay_hazard(&self) -> Option<String> {
+        if self.semihosting.is_some() {
+            return Some("--semihosting, replaying would repeat its host calls".to_string());
+        }
+        if self.linux_user.is_some() {
+            return Some("--user, replaying would repeat its syscalls".to_string());
+        }
+        self.bus.replay_hazard()
+    }
+    /// Silences device output to the host, see `Device::set_muted`.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.bus.set_muted(muted);
+    }
+    /// Set once the program asked to exit (semihosting SYS_EXIT, exit()).
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+    /// Interrupt lines currently raised. Only reported, the hart never
+    /// takes them.
+    pub fn pending_irqs(&self) -> u32 {
+        self.bus.pending_irqs()
+    }
     // Not very usefull, using it to avoid writting to x0.
     // can probably also be used for later register modifications.
-    fn write_reg(&mut self, reg: usize, value: u32) {
+    pub fn write_reg(&mut self, reg: usize, value: u32) {
         if reg != 0 {
             self.regs[reg] = value;
         }
     }
 
+    /// An `ebreak` wrapped in `slli x0,x0,0x1f` and `srai x0,x0,7`.
+    fn is_semihosting_call(&mut self, instruction: u32) -> bool {
+        instruction == EBREAK
+            && self.semihosting.is_some()
+            && self.bus.read(4, self.pc.wrapping_sub(4)) == Ok(SEMIHOSTING_ENTRY)
+            && self.bus.read(4, self.pc.wrapping_add(4)) == Ok(SEMIHOSTING_EXIT)
+    }
+
     // probably better way to do this
     fn sign_extend(&self, value: u32, bits: u32) -> u32 {
         let shift = 32 - bits;
@@ -144,6 +258,7 @@ impl Cpu {
                     }
                     if instr_log {
                     event_log.push(Event {
+                        cycle: self.cycles,
                         pc: self.pc,
                         opcode: instruction,
                         instr_type: EventType::RegWrite{reg: _rd as u8, value: self.regs[_rd as usize]},
@@ -210,6 +325,7 @@ impl Cpu {
                     }
                     if instr_log {
                     event_log.push(Event {
+                        cycle: self.cycles,
                         pc: self.pc,
                         opcode: instruction,
                         instr_type: EventType::RegWrite{reg: _rd as u8, value: self.regs[_rd as usize]},
@@ -219,75 +335,34 @@ impl Cpu {
                 I_LOAD_FORMAT => {
                     let imm = self.sign_extend((instruction >> 20) & 0xFFF, 12);
                     let addr = self.read_reg(rs1 as usize).wrapping_add(imm);
-                    match funct3 {
-                        FUNCT3_LB => {
-                            // if verbose {mnemonic = format!("lb {}, {}({})", REGISTER_NAMES[_rd as usize],  imm as i32, REGISTER_NAMES[rs1 as usize]);}
-                            match self.bus.read(1, addr) {
-                                Ok(byte) => {
-                                    let temp = ((byte as i8) as i32) as u32; // sign-extend
-                                    self.write_reg(_rd as usize, temp);
-                                },
-                                Err(_) => {
-                                    // panic!("Cycle: {} Memory read error at address: 0x{:08X} from {}", self.cycles, addr, mnemonic.bold().underline());
-                                }
-                            }
-                        }
-                        FUNCT3_LH => {
-                            // if verbose {mnemonic = format!("lh {}, {}({})", REGISTER_NAMES[_rd as usize],  imm as i32, REGISTER_NAMES[rs1 as usize]);}
-                            match self.bus.read(2, addr) {
-                                Ok(halfword) => {
-                                    let temp = ((halfword as i16) as i32) as u32; // sign-extend
-                                    self.write_reg(_rd as usize, temp);
-                                },
-                                Err(_) => {
-                                    // panic!("Cycle: {} Memory read error at address: 0x{:08X} from {}", self.cycles, addr, mnemonic.bold().underline());
-                                }
-                            }
-                        }
-                        FUNCT3_LW => {
-                            // if verbose {mnemonic = format!("lw {}, {}({})", REGISTER_NAMES[_rd as usize],  imm as i32, REGISTER_NAMES[rs1 as usize]);}
-                            match self.bus.read(4, addr) {
-                                Ok(word) => {
-                                    self.write_reg(_rd as usize, word);
-                                },
-                                Err(_) => {
-                                    // panic!("Cycle: {} Memory read error at address: 0x{:08X} from {}", self.cycles, addr, mnemonic.bold().underline());
-                                }
-                            }
-                        }
-                        FUNCT3_LBU => {
-                            // if verbose {mnemonic = format!("lbu {}, {}({})", REGISTER_NAMES[_rd as usize],  imm as i32, REGISTER_NAMES[rs1 as usize]);}
-                            match self.bus.read(1, addr) {
-                                Ok(byte) => {
-                                    let temp = byte; // zero-extend
-                                    self.write_reg(_rd as usize, temp);
-                                },
-                                Err(_) => {
-                                    // panic!("Cycle: {} Memory read error at address: 0x{:08X} from {}", self.cycles, addr, mnemonic.bold().underline());
-                                }
-                            }
-                        }
-                        FUNCT3_LHU => {
-                            // if verbose {mnemonic = format!("lhu {}, {}({})", REGISTER_NAMES[_rd as usize],  imm as i32, REGISTER_NAMES[rs1 as usize]);}
-                            match self.bus.read(2, addr) {
-                                Ok(halfword) => {
-                                    let temp = halfword; // zero-extend
-                                    self.write_reg(_rd as usize, temp);
-                                },
-                                Err(_) => {
-                                    // panic!("Cycle: {} Memory read error at address: 0x{:08X} from {}", self.cycles, addr, mnemonic.bold().underline());
-                                }
-                            }
-                        }
+                    let (size, signed) = match funct3 {
+                        FUNCT3_LB => (1, true),
+                        FUNCT3_LH => (2, true),
+                        FUNCT3_LW => (4, false),
+                        FUNCT3_LBU => (1, false),
+                        FUNCT3_LHU => (2, false),
                         _ => {
                             panic!("Unknown funct3 in I-LOAD-format: 0b{:03b}", funct3);
                         }
+                    };
+                    // A failed read leaves rd untouched.
+                    let loaded = self.bus.read(size, addr).ok();
+                    if let Some(value) = loaded {
+                        let temp = if signed { self.sign_extend(value, size as u32 * 8) } else { value };
+                        self.write_reg(_rd as usize, temp);
                     }
                     if instr_log {
                     event_log.push(Event {
+                        cycle: self.cycles,
                         pc: self.pc,
                         opcode: instruction,
-                        instr_type: EventType::MemRead { addr, value: self.regs[_rd as usize] }
+                        instr_type: EventType::RegWrite{reg: _rd as u8, value: self.regs[_rd as usize]},
+                    });
+                    event_log.push(Event {
+                        cycle: self.cycles,
+                        pc: self.pc,
+                        opcode: instruction,
+                        instr_type: EventType::MemRead { addr, value: loaded.unwrap_or(0), size },
                     });
                     }
                 }
@@ -296,6 +371,7 @@ impl Cpu {
                     let imm_11_5 = (instruction >> 25) & 0x7F;
                     let imm = self.sign_extend((imm_11_5 << 5) | imm_4_0, 12);
                     let addr = self.read_reg(rs1 as usize).wrapping_add(imm);
+                    let size = 1u8 << (funct3 & 0x3);
                     match funct3 {
                         FUNCT3_SB => {
                             let value = self.read_reg(rs2 as usize) & 0xFF;
@@ -333,9 +409,10 @@ impl Cpu {
                     }
                     if instr_log {
                     event_log.push(Event {
+                        cycle: self.cycles,
                         pc: self.pc,
                         opcode: instruction,
-                        instr_type: EventType::MemWrite { addr, value: self.read_reg(rs2 as usize) }
+                        instr_type: EventType::MemWrite { addr, value: self.read_reg(rs2 as usize) & (u32::MAX >> (32 - 8 * size as u32)), size }
                     });
                     }
                 }
@@ -346,14 +423,8 @@ impl Cpu {
                     let imm_10_5 = (instruction >> 25) & 0x3F;
                     let imm_12 = (instruction >> 31) & 0x1;
                     let imm = self.sign_extend((imm_12 << 12) | (imm_11 << 11) | (imm_10_5 << 5) | (imm_4_1 << 1), 13);
+                    let pc = self.pc;
                     let address = self.pc.wrapping_add(imm);
-                    if  instr_log {
-                        event_log.push(Event {
-                            pc: self.pc,
-                            opcode: instruction,
-                            instr_type: EventType::FlowChange{new_pc: address},
-                        });
-                    }
                     match funct3 {
                         FUNCT3_BEQ => {
                             // if verbose {mnemonic = format!("beq {} , {}, to 0x{:08X}", rs1, rs2, address);}
@@ -407,6 +478,14 @@ impl Cpu {
                             panic!("Unknown funct3 in B-format: 0b{:03b}", funct3);
                         }
                     }
+                    if instr_log {
+                        event_log.push(Event {
+                            cycle: self.cycles,
+                            pc,
+                            opcode: instruction,
+                            instr_type: EventType::FlowChange{new_pc: if pc_changed { address } else { pc.wrapping_add(4) }},
+                        });
+                    }
                 }
                 U_FORMAT_LUI => {
                     // if verbose {mnemonic = "lui".to_string();}
@@ -414,6 +493,7 @@ impl Cpu {
                     self.write_reg(_rd as usize, imm);
                     if instr_log {
                     event_log.push(Event {
+                        cycle: self.cycles,
                         pc: self.pc,
                         opcode: instruction,
                         instr_type: EventType::RegWrite{reg: _rd as u8, value: self.regs[_rd as usize]},
@@ -427,6 +507,7 @@ impl Cpu {
                     self.write_reg(_rd as usize, temp);
                     if instr_log {
                     event_log.push(Event {
+                        cycle: self.cycles,
                         pc: self.pc,
                         opcode: instruction,
                         instr_type: EventType::RegWrite{reg: _rd as u8, value: self.regs[_rd as usize]},
@@ -445,10 +526,10 @@ impl Cpu {
                     self.write_reg(_rd as usize, value);
                     if instr_log {
                         event_log.push(Event {
+                            cycle: self.cycles,
                             pc: self.pc,
                             opcode: instruction,
-                            instr_type: EventType::RegWrite { reg: (_rd as u8), value }    
-                            // instr_type: EventType::FlowLink{new_pc: self.pc, register: _rd as u8},
+                            instr_type: EventType::FlowLink{new_pc: addr, register: _rd as u8},
                         });
                     }
                     self.pc = addr;
@@ -463,25 +544,71 @@ impl Cpu {
                     self.write_reg(_rd as usize, value);
                     if instr_log {
                         event_log.push(Event {
+                            cycle: self.cycles,
                             pc: self.pc,
                             opcode: instruction,
-                            instr_type: EventType::RegWrite { reg: (_rd as u8), value }    
-                            // instr_type: EventType::FlowLink{new_pc: self.pc, register: _rd as u8},
+                            instr_type: EventType::FlowLink{new_pc: addr, register: _rd as u8},
                         });
                     }
-
-                    // if instr_log {
-                    //     event_log.push(Event {
-                    //         pc: self.pc,
-                    //         opcode: instruction,
-                    //         instr_type: EventType::FlowLink{new_pc: self.pc, register: _rd as u8},
-                    //     });
-                    // }
                     self.pc = addr;
                 }
                 I_ENV_FORMAT => {
                     // if verbose {mnemonic = "ecall/ebreak".to_string();}
-                    unimplemented!("ECALL/EBREAK not implemented");
+                    let nr = self.read_reg(17);
+                    let args = [10, 11, 12, 13, 14, 15].map(|reg| self.read_reg(reg));
+                    let (value, exit_code) = if let (ECALL, Some(linux_user)) = (instruction, self.linux_user.as_mut()) {
+                        // The host plays the kernel the ecall traps into.
+                        if instr_log {
+                            event_log.push(Event {
+                                cycle: self.cycles,
+                                pc: self.pc,
+                                opcode: instruction,
+                                instr_type: EventType::Trap { cause: CAUSE_ECALL_U, tval: 0 },
+                            });
+                        }
+                        linux_user.syscall(nr, args, &mut self.bus)
+                    } else if self.is_semihosting_call(instruction) {
+                        let (op, param) = (self.read_reg(10), self.read_reg(11));
+                        self.semihosting.as_mut().unwrap().call(op, param, &mut self.bus)
+                    } else {
+                        // Nothing services it and there is no trap vector,
+                        // so the hart stops on the instruction.
+                        if instr_log {
+                            let (cause, tval) = match instruction {
+                                EBREAK => (CAUSE_BREAKPOINT, 0),
+                                ECALL => (CAUSE_ECALL_M, 0),
+                                _ => (CAUSE_ILLEGAL_INSTRUCTION, instruction),
+                            };
+                            event_log.push(Event {
+                                cycle: self.cycles,
+                                pc: self.pc,
+                                opcode: instruction,
+                                instr_type: EventType::Trap { cause, tval },
+                            });
+                        }
+                        self.exit_code = Some(TRAP_EXIT_CODE);
+                        self.cycles += 1;
+                        break;
+                    };
+                    self.write_reg(10, value);
+                    if instr_log {
+                        event_log.push(Event {
+                            cycle: self.cycles,
+                            pc: self.pc,
+                            opcode: instruction,
+                            instr_type: EventType::RegWrite { reg: 10, value },
+                        });
+                    }
+                    if exit_code.is_some() {
+                        self.exit_code = exit_code;
+                        self.pc += 4;
+                        self.cycles += 1;
+                        break;
+                    }
+                }
+                MISC_MEM_FORMAT => {
+                    // fence and fence.i: a single hart without caches already
+                    // sees its loads, stores and fetches in program order.
                 }
                 0x0 => {
                     // if verbose {mnemonic = "NOP".to_string();}
@@ -0,0 +1,565 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::constants::*;
+use crate::cpu::Cpu;
+use crate::disasm::disassemble_with;
+use crate::elf::Symbols;
+use crate::platform::parse_u32;
+use crate::timetravel::{Hit, TimeTravel};
+use crate::types::{DebugState, DisasmLine, Event};
+
+const HELP: &str = "\
+step [n]             execute n instructions (default 1), Enter repeats the last command
+continue             run until a breakpoint, a watchpoint or Ctrl-C
+break <loc>          stop when the PC reaches loc, without argument list breakpoints
+watch <addr>         stop when the word at addr changes
+delete [loc]         remove the breakpoint or watchpoint at loc, or all of them
+x/<n><w|h|b> <addr>  dump n words, halfwords or bytes
+regs                 show the registers
+set reg <reg> <val>  write a register (or `set pc <val>`)
+disas [loc] [n]      disassemble n instructions (default 8) at loc or the PC
+until [loc]          run to loc, or until the PC moves past the current one in this function
+finish               run until the current function returns
+bs [n]               step back n instructions
+bc [loc]             run backwards to the last breakpoint, watchpoint or loc hit
+info devices         show the memory map and pending interrupts
+info breakpoints     list breakpoints and watchpoints
+quit                 stop the emulator
+Locations are numbers (0x80000000) or symbols with an optional offset (main+8).";
+
+/// Line editor on its own thread. After each line it waits until the CPU
+/// thread acknowledges the command, so output and prompt don't interleave.
+struct Console {
+    lines: mpsc::Receiver<String>,
+    ack: mpsc::Sender<()>,
+}
+impl Console {
+    fn spawn() -> Self {
+        let (line_tx, lines) = mpsc::channel();
+        let (ack, ack_rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let Ok(mut editor) = DefaultEditor::new() else {
+                let _ = line_tx.send("quit".to_string());
+                return;
+            };
+            loop {
+                let line = match editor.readline("(riscy) ") {
+                    Ok(line) => {
+                        if !line.trim().is_empty() {
+                            let _ = editor.add_history_entry(line.as_str());
+                        }
+                        line
+                    }
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(_) => "quit".to_string(),
+                };
+                if line_tx.send(line).is_err() || ack_rx.recv().is_err() {
+                    break;
+                }
+            }
+        });
+        Self { lines, ack }
+    }
+}
+
+enum RunMode {
+    Step(u64),
+    Continue,
+    /// Stop when the PC reaches the address.
+    Until(u32),
+    /// Stop when the PC moves past the address in the same function.
+    UntilPast(u32),
+    Finish,
+}
+
+pub enum Poll {
+    /// Waiting for a command, the caller can block on other events.
+    Paused,
+    /// Executed something, call again.
+    Busy,
+    Quit,
+}
+
+/// Run control of `cpu_thread`. Commands come from the `Console` in
+/// interactive mode and from the GUI panels; the CPU thread calls `poll`
+/// in its loop so it keeps serving GUI frame requests while paused.
+pub struct Debugger {
+    console: Option<Console>,
+    /// The console is blocked until the current command finishes.
+    awaiting_ack: bool,
+    symbols: Symbols,
+    memory_map: Vec<String>,
+    breakpoints: Vec<u32>,
+    /// Watched address and the last value seen there.
+    watchpoints: Vec<(u32, u32)>,
+    mode: Option<RunMode>,
+    /// Set when a run starts, so it can leave a breakpoint it is stopped on.
+    resuming: bool,
+    /// Calls minus returns since `finish` or `until` started.
+    depth: i64,
+    last_command: String,
+    time_travel: TimeTravel,
+    interrupt: Arc<AtomicBool>,
+    /// Whether executed events are collected for `--log` and `-v`.
+    instr_log: bool,
+}
+
+impl Debugger {
+    /// Without a console the guest starts running right away and only the
+    /// GUI can stop it.
+    pub fn new(symbols: Symbols, memory_map: Vec<String>, checkpoint_interval: u64, instr_log: bool, console: bool) -> Self {
+        let interrupt = Arc::new(AtomicBool::new(false));
+        if console {
+            let flag = Arc::clone(&interrupt);
+            if let Err(e) = ctrlc::set_handler(move || flag.store(true, Ordering::Relaxed)) {
+                println!("Ctrl-C will not interrupt the guest: {}", e);
+            }
+        }
+        Self {
+            console: console.then(Console::spawn),
+            awaiting_ack: false,
+            symbols,
+            memory_map,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            mode: (!console).then_some(RunMode::Continue),
+            resuming: false,
+            depth: 0,
+            last_command: "step".to_string(),
+            time_travel: TimeTravel::new(checkpoint_interval.max(1), MAX_CHECKPOINTS),
+            interrupt,
+            instr_log,
+        }
+    }
+
+    /// Handles pending commands, or runs at most `max` instructions while
+    /// the guest is running. Executed events go to `log`.
+    pub fn poll(&mut self, cpu: &mut Cpu, max: u64, log: &mut dyn FnMut(Vec<Event>)) -> Poll {
+        if self.mode.is_none() {
+            let Some(console) = &self.console else { return Poll::Paused };
+            return match console.lines.try_recv() {
+                Ok(line) => {
+                    self.awaiting_ack = true;
+                    if !self.execute(cpu, &line) {
+                        return Poll::Quit;
+                    }
+                    if self.mode.is_none() {
+                        self.acknowledge();
+                    }
+                    Poll::Busy
+                }
+                Err(mpsc::TryRecvError::Empty) => Poll::Paused,
+                Err(mpsc::TryRecvError::Disconnected) => Poll::Quit,
+            };
+        }
+        // Checkpoints are only useful to go back from the console.
+        if self.console.is_some() {
+            self.time_travel.record(cpu);
+        }
+        if self.interrupt.swap(false, Ordering::Relaxed) {
+            self.stop(cpu, "Interrupted".to_string());
+            return Poll::Busy;
+        }
+        // Plain `continue` without stop conditions runs whole batches.
+        if matches!(self.mode, Some(RunMode::Continue)) && self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            log(cpu.run(max, false, self.instr_log));
+            self.resuming = false;
+            if let Some(code) = cpu.exit_code() {
+                self.stop(cpu, format!("Program exited with code {}", code));
+            }
+            return Poll::Busy;
+        }
+        for _ in 0..max {
+            if let Some(reason) = self.stop_before(cpu) {
+                self.stop(cpu, reason);
+                break;
+            }
+            self.track_calls(cpu);
+            let pc = cpu.pc();
+            log(cpu.run(1, false, self.instr_log));
+            self.resuming = false;
+            if let Some(reason) = self.stop_after(cpu, pc) {
+                self.stop(cpu, reason);
+            }
+            if self.mode.is_none() {
+                break;
+            }
+        }
+        Poll::Busy
+    }
+
+    fn acknowledge(&mut self) {
+        if let Some(console) = &self.console && self.awaiting_ack {
+            let _ = console.ack.send(());
+        }
+        self.awaiting_ack = false;
+    }
+
+    /// Turns event collection on or off for the next `poll`, so the trace
+    /// only costs something inside its cycle window.
+    pub fn collect_events(&mut self, enabled: bool) {
+        self.instr_log = enabled;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.mode.is_some()
+    }
+
+    pub fn pause(&mut self, cpu: &mut Cpu) {
+        if self.is_running() {
+            self.stop(cpu, "Paused".to_string());
+        }
+    }
+
+    pub fn resume(&mut self, cpu: &Cpu) {
+        if !self.is_running() {
+            self.start(cpu, RunMode::Continue);
+        }
+    }
+
+    pub fn step(&mut self, cpu: &Cpu, count: u64) {
+        if !self.is_running() && count > 0 {
+            self.start(cpu, RunMode::Step(count));
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, location: &str) -> Result<u32, String> {
+        let addr = self.location(location)?;
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+        Ok(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.retain(|&bp| bp != addr);
+    }
+
+    /// Called after the machine was reset or rebuilt: the checkpoints belong
+    /// to the previous run. Breakpoints are kept.
+    pub fn machine_changed(&mut self, cpu: &mut Cpu) {
+        self.time_travel.clear();
+        for (addr, value) in self.watchpoints.iter_mut() {
+            *value = cpu.peek_mem(4, *addr).unwrap_or(*value);
+        }
+    }
+
+    /// New symbols and memory map after the program was reloaded from disk.
+    pub fn set_program(&mut self, symbols: Symbols, memory_map: Vec<String>) {
+        self.symbols = symbols;
+        self.memory_map = memory_map;
+    }
+
+    /// Snapshot of the hart for the GUI panels.
+    pub fn state(&self, cpu: &mut Cpu, memory_addr: u32, memory_len: u32) -> DebugState {
+        let pc = cpu.pc();
+        DebugState {
+            regs: std::array::from_fn(|i| cpu.read_reg(i)),
+            pc,
+            cycles: cpu.cycles(),
+            running: self.is_running(),
+            exit_code: cpu.exit_code(),
+            disassembly: self.disassembly(cpu, pc.wrapping_sub(16 * 4), 32),
+            breakpoints: self.breakpoints.iter().map(|&addr| (addr, self.symbols.describe(addr))).collect(),
+            memory_addr,
+            memory: (0..memory_len).map(|i| cpu.peek_mem(1, memory_addr.wrapping_add(i)).map(|b| b as u8)).collect(),
+        }
+    }
+
+    fn disassembly(&self, cpu: &mut Cpu, start: u32, count: u32) -> Vec<DisasmLine> {
+        (0..count)
+            .map(|i| start.wrapping_add(i * 4))
+            .filter_map(|addr| {
+                let word = cpu.peek_mem(4, addr)?;
+                Some(DisasmLine { addr, word, label: self.symbols.describe(addr), text: disassemble_with(word, addr, &self.symbols) })
+            })
+            .collect()
+    }
+
+    fn stop(&mut self, cpu: &mut Cpu, reason: String) {
+        self.mode = None;
+        println!("{}", reason);
+        self.print_current(cpu);
+        self.acknowledge();
+    }
+
+    fn print_current(&self, cpu: &mut Cpu) {
+        let pc = cpu.pc();
+        match cpu.peek_mem(4, pc) {
+            Some(instruction) => println!("{}: {}", self.symbols.describe(pc), disassemble_with(instruction, pc, &self.symbols)),
+            None => println!("{}: <unmapped>", self.symbols.describe(pc)),
+        }
+    }
+
+    /// Conditions checked with the PC pointing at the next instruction.
+    fn stop_before(&self, cpu: &Cpu) -> Option<String> {
+        let pc = cpu.pc();
+        if !self.resuming && self.breakpoints.contains(&pc) {
+            return Some(format!("Breakpoint at {}", self.symbols.describe(pc)));
+        }
+        match self.mode {
+            Some(RunMode::Until(target)) if pc == target => Some("Reached".to_string()),
+            Some(RunMode::UntilPast(start)) if self.depth < 0 || (self.depth == 0 && pc > start) => Some("Reached".to_string()),
+            Some(RunMode::Finish) if self.depth < 0 => Some("Returned".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Conditions checked after the instruction at `pc` executed.
+    fn stop_after(&mut self, cpu: &mut Cpu, pc: u32) -> Option<String> {
+        if let Some(code) = cpu.exit_code() {
+            return Some(format!("Program exited with code {}", code));
+        }
+        for (addr, value) in self.watchpoints.iter_mut() {
+            let new = cpu.peek_mem(4, *addr).unwrap_or(*value);
+            if new != *value {
+                let message = format!("Watchpoint 0x{:08x}: 0x{:08x} -> 0x{:08x}, written by {}", addr, value, new, self.symbols.describe(pc));
+                *value = new;
+                return Some(message);
+            }
+        }
+        if let Some(RunMode::Step(remaining)) = self.mode.as_mut() {
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.mode = None;
+                self.print_current(cpu);
+                self.acknowledge();
+            }
+        }
+        None
+    }
+
+    /// Keeps `depth` up to date for `finish` and `until` by spotting calls
+    /// (jal/jalr linking ra) and returns (jalr zero, 0(ra)).
+    fn track_calls(&mut self, cpu: &mut Cpu) {
+        if !matches!(self.mode, Some(RunMode::Finish | RunMode::UntilPast(_))) {
+            return;
+        }
+        let Some(instruction) = cpu.peek_mem(4, cpu.pc()) else { return };
+        let opcode = instruction & 0x7F;
+        let rd = (instruction >> 7) & 0x1F;
+        if (opcode == J_FORMAT || opcode == I_JALR_FORMAT) && rd == 1 {
+            self.depth += 1;
+        } else if instruction == 0x0000_8067 {
+            self.depth -= 1;
+        }
+    }
+
+    fn start(&mut self, cpu: &Cpu, mode: RunMode) {
+        if cpu.exit_code().is_some() {
+            println!("The program has exited");
+            return;
+        }
+        self.mode = Some(mode);
+        self.resuming = true;
+        self.depth = 0;
+    }
+
+    /// Parses `123`, `0x8000_0000`, `main` or `main+8`.
+    fn location(&self, text: &str) -> Result<u32, String> {
+        if let Ok(value) = parse_u32(text) {
+            return Ok(value);
+        }
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, parse_u32(offset)?),
+            None => (text, 0),
+        };
+        self.symbols.find(name)
+            .map(|addr| addr.wrapping_add(offset))
+            .ok_or_else(|| format!("No symbol '{}'", name))
+    }
+
+    /// Runs one command line, returns false on `quit`.
+    fn execute(&mut self, cpu: &mut Cpu, line: &str) -> bool {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, operands) = words.split_first().map_or(("", &[][..]), |(command, rest)| (*command, rest));
+        if command == "q" || command == "quit" {
+            return false;
+        }
+        if let Err(e) = self.command(cpu, command, operands) {
+            println!("{}", e);
+        }
+        true
+    }
+
+    fn command(&mut self, cpu: &mut Cpu, command: &str, operands: &[&str]) -> Result<(), String> {
+        let operand = |index: usize| operands.get(index).copied();
+        match command {
+            "s" | "step" | "si" | "stepi" => {
+                let count = operand(0).map(parse_u32).transpose()?.unwrap_or(1).max(1);
+                self.start(cpu, RunMode::Step(count as u64));
+            }
+            "c" | "continue" => self.start(cpu, RunMode::Continue),
+            "b" | "break" => match operand(0) {
+                Some(text) => {
+                    let addr = self.add_breakpoint(text)?;
+                    println!("Breakpoint at {}", self.symbols.describe(addr));
+                }
+                None => self.print_breakpoints(),
+            },
+            "watch" => {
+                let addr = self.location(operand(0).ok_or("Usage: watch <addr>")?)?;
+                let value = cpu.peek_mem(4, addr)
+                    .ok_or_else(|| format!("0x{:08x} is not RAM or ROM, device registers can't be watched", addr))?;
+                self.watchpoints.retain(|&(watched, _)| watched != addr);
+                self.watchpoints.push((addr, value));
+                println!("Watchpoint at 0x{:08x} (currently 0x{:08x})", addr, value);
+            }
+            "d" | "delete" => match operand(0) {
+                Some(text) => {
+                    let addr = self.location(text)?;
+                    self.breakpoints.retain(|&bp| bp != addr);
+                    self.watchpoints.retain(|&(watched, _)| watched != addr);
+                }
+                None => {
+                    self.breakpoints.clear();
+                    self.watchpoints.clear();
+                }
+            },
+            "regs" => self.print_registers(cpu),
+            "set" => self.set(cpu, operands)?,
+            "disas" | "disassemble" => {
+                let start = operand(0).map(|text| self.location(text)).transpose()?.unwrap_or(cpu.pc());
+                let count = operand(1).map(parse_u32).transpose()?.unwrap_or(8);
+                for line in self.disassembly(cpu, start, count) {
+                    let marker = if line.addr == cpu.pc() { "=>" } else { "  " };
+                    println!("{} {}: {:08x}  {}", marker, line.label, line.word, line.text);
+                }
+            }
+            "until" | "u" => match operand(0) {
+                Some(text) => {
+                    let target = self.location(text)?;
+                    self.start(cpu, RunMode::Until(target));
+                }
+                None => self.start(cpu, RunMode::UntilPast(cpu.pc())),
+            },
+            "finish" => self.start(cpu, RunMode::Finish),
+            "bs" => {
+                let count = operand(0).map(parse_u32).transpose()?.unwrap_or(1);
+                self.time_travel.reverse_step(cpu, count as u64)?;
+                self.after_time_travel(cpu);
+            }
+            "bc" => {
+                let target = operand(0).map(|text| self.location(text)).transpose()?;
+                let mut breakpoints = self.breakpoints.clone();
+                breakpoints.extend(target);
+                let watched: Vec<u32> = self.watchpoints.iter().map(|&(addr, _)| addr).collect();
+                match self.time_travel.reverse_continue(cpu, &breakpoints, &watched)? {
+                    Some(Hit::Breakpoint(pc)) => println!("Breakpoint at {}", self.symbols.describe(pc)),
+                    Some(Hit::Watchpoint { addr, old, new, pc }) => println!(
+                        "Watchpoint 0x{:08x}: 0x{:08x} -> 0x{:08x}, written by {}", addr, old, new, self.symbols.describe(pc)),
+                    None => println!("Reached the oldest checkpoint"),
+                }
+                self.after_time_travel(cpu);
+            }
+            "info" => match operand(0) {
+                Some("devices") => {
+                    for line in &self.memory_map {
+                        println!("  {}", line);
+                    }
+                    println!("Pending IRQs: 0x{:08X}", cpu.pending_irqs());
+                }
+                Some("breakpoints" | "watchpoints") => self.print_breakpoints(),
+                Some("registers") => self.print_registers(cpu),
+                _ => return Err("Usage: info devices|breakpoints|registers".to_string()),
+            },
+            "help" | "h" => println!("{}", HELP),
+            _ if command.starts_with("x/") || command == "x" => self.examine(cpu, &command[1..], operand(0))?,
+            _ => return Err(format!("Unknown command '{}', try 'help'", command)),
+        }
+        Ok(())
+    }
+
+    fn after_time_travel(&mut self, cpu: &mut Cpu) {
+        for (addr, value) in self.watchpoints.iter_mut() {
+            *value = cpu.peek_mem(4, *addr).unwrap_or(*value);
+        }
+        println!("cycle {}", cpu.cycles());
+        self.print_current(cpu);
+    }
+
+    fn print_breakpoints(&self) {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            println!("No breakpoints or watchpoints");
+        }
+        for &addr in &self.breakpoints {
+            println!("  break {}", self.symbols.describe(addr));
+        }
+        for &(addr, value) in &self.watchpoints {
+            println!("  watch 0x{:08x} = 0x{:08x}", addr, value);
+        }
+    }
+
+    fn print_registers(&self, cpu: &Cpu) {
+        for (i, &name) in REGISTER_NAMES.iter().enumerate() {
+            let reg_data = cpu.read_reg(i);
+            println!("x{:<2} ({:>4}): 0x{:08X} ({})", i, name, reg_data, reg_data as i32);
+        }
+        println!("pc       : {}", self.symbols.describe(cpu.pc()));
+        println!("cycle    : {}", cpu.cycles());
+    }
+
+    fn set(&mut self, cpu: &mut Cpu, operands: &[&str]) -> Result<(), String> {
+        match operands {
+            ["pc", value] => cpu.set_pc(self.location(value)?),
+            ["reg", name, value] => {
+                let reg = register_index(name).ok_or_else(|| format!("Unknown register '{}'", name))?;
+                let value = parse_u32(value).or_else(|_| value.parse::<i32>().map(|v| v as u32).map_err(|e| e.to_string()))?;
+                cpu.write_reg(reg, value);
+            }
+            _ => return Err("Usage: set reg <reg> <value> | set pc <loc>".to_string()),
+        }
+        Ok(())
+    }
+
+    /// `x/<count><unit> <addr>`, `format` is what follows the `x`.
+    fn examine(&self, cpu: &mut Cpu, format: &str, addr: Option<&str>) -> Result<(), String> {
+        let format = format.trim_start_matches('/');
+        let digits = format.find(|c: char| !c.is_ascii_digit()).unwrap_or(format.len());
+        let count = if digits == 0 { 1 } else { format[..digits].parse::<u32>().map_err(|e| e.to_string())? };
+        let size: u8 = match &format[digits..] {
+            "" | "w" => 4,
+            "h" => 2,
+            "b" => 1,
+            unit => return Err(format!("Unknown unit '{}', use w, h or b", unit)),
+        };
+        let start = self.location(addr.ok_or("Usage: x/<n><w|h|b> <addr>")?)?;
+        let per_line = 16 / size as u32;
+        for i in 0..count {
+            let addr = start.wrapping_add(i * size as u32);
+            if i % per_line == 0 {
+                if i != 0 {
+                    println!();
+                }
+                print!("{}:", self.symbols.describe(addr));
+            }
+            match cpu.peek_mem(size, addr) {
+                Some(value) => print!(" 0x{:0width$x}", value, width = size as usize * 2),
+                None => {
+                    print!(" <unmapped or MMIO>");
+                    break;
+                }
+            }
+        }
+        println!();
+        Ok(())
+    }
+}
+
+/// `x5`, `t0` or `fp`.
+fn register_index(name: &str) -> Option<usize> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(index) = name.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()) {
+        return (index < 32).then_some(index);
+    }
+    REGISTER_NAMES.iter().position(|&reg| reg == name)
+}
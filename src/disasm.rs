@@ -0,0 +1,283 @@
+use std::error::Error;
+use std::fs;
+
+use crate::constants::*;
+use crate::asm;
+use crate::elf::{ElfImage, Symbols};
+use crate::loader::parse_hex_file;
+
+fn reg(index: u32) -> &'static str {
+    REGISTER_NAMES[index as usize & 0x1F]
+}
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+/// Address a branch or `jal` at `pc` transfers to.
+pub fn target(instruction: u32, pc: u32) -> Option<u32> {
+    let offset = match instruction & 0x7F {
+        B_FORMAT => sign_extend(
+            ((instruction >> 19) & 0x1000) | ((instruction << 4) & 0x800)
+                | ((instruction >> 20) & 0x7E0) | ((instruction >> 7) & 0x1E),
+            13,
+        ),
+        J_FORMAT => sign_extend(
+            ((instruction >> 11) & 0x100000) | (instruction & 0xFF000)
+                | ((instruction >> 9) & 0x800) | ((instruction >> 20) & 0x7FE),
+            21,
+        ),
+        _ => return None,
+    };
+    Some(pc.wrapping_add(offset as u32))
+}
+
+/// Decodes one RV32I instruction at `pc` into the canonical assembly GNU
+/// objdump prints, pseudo-instructions (`li`, `mv`, `ret`, `beqz`...)
+/// included. Branch and jump targets are printed as absolute addresses.
+pub fn disassemble(instruction: u32, pc: u32) -> String {
+    let rd_index = (instruction >> 7) & 0x1F;
+    let rs1_index = (instruction >> 15) & 0x1F;
+    let rs2_index = (instruction >> 20) & 0x1F;
+    let (rd, rs1, rs2) = (reg(rd_index), reg(rs1_index), reg(rs2_index));
+    let funct3 = (instruction >> 12) & 0x7;
+    let funct7 = instruction >> 25;
+    let imm_i = sign_extend(instruction >> 20, 12);
+    let unknown = || format!(".word 0x{:08x}", instruction);
+    match instruction & 0x7F {
+        R_FORMAT => {
+            match (funct3, funct7) {
+                (FUNCT3_ADD_SUB, 0x20) if rs1_index == 0 => return format!("neg {}, {}", rd, rs2),
+                (FUNCT3_SLT, 0x00) if rs2_index == 0 => return format!("sltz {}, {}", rd, rs1),
+                (FUNCT3_SLT, 0x00) if rs1_index == 0 => return format!("sgtz {}, {}", rd, rs2),
+                (FUNCT3_SLTU, 0x00) if rs1_index == 0 => return format!("snez {}, {}", rd, rs2),
+                _ => {}
+            }
+            let mnemonic = match (funct3, funct7) {
+                (FUNCT3_ADD_SUB, 0x00) => "add",
+                (FUNCT3_ADD_SUB, 0x20) => "sub",
+                (FUNCT3_SLL, 0x00) => "sll",
+                (FUNCT3_SLT, 0x00) => "slt",
+                (FUNCT3_SLTU, 0x00) => "sltu",
+                (FUNCT3_XOR, 0x00) => "xor",
+                (FUNCT3_SRL, 0x00) => "srl",
+                (FUNCT3_SRL, 0x20) => "sra",
+                (FUNCT3_OR, 0x00) => "or",
+                (FUNCT3_AND, 0x00) => "and",
+                _ => return unknown(),
+            };
+            format!("{} {}, {}, {}", mnemonic, rd, rs1, rs2)
+        }
+        I_COMP_FORMAT => {
+            let shamt = (instruction >> 20) & 0x1F;
+            match funct3 {
+                FUNCT3_ADDI if instruction == 0x0000_0013 => "nop".to_string(),
+                FUNCT3_ADDI if rs1_index == 0 => format!("li {}, {}", rd, imm_i),
+                FUNCT3_ADDI if imm_i == 0 => format!("mv {}, {}", rd, rs1),
+                FUNCT3_ADDI => format!("addi {}, {}, {}", rd, rs1, imm_i),
+                FUNCT3_SLTI => format!("slti {}, {}, {}", rd, rs1, imm_i),
+                FUNCT3_SLTIU if imm_i == 1 => format!("seqz {}, {}", rd, rs1),
+                FUNCT3_SLTIU => format!("sltiu {}, {}, {}", rd, rs1, imm_i),
+                FUNCT3_XORI if imm_i == -1 => format!("not {}, {}", rd, rs1),
+                FUNCT3_XORI => format!("xori {}, {}, {}", rd, rs1, imm_i),
+                FUNCT3_ORI => format!("ori {}, {}, {}", rd, rs1, imm_i),
+                FUNCT3_ANDI => format!("andi {}, {}, {}", rd, rs1, imm_i),
+                FUNCT3_SLLI if funct7 == 0x00 => format!("slli {}, {}, {}", rd, rs1, shamt),
+                FUNCT3_SRLI if funct7 == 0x20 => format!("srai {}, {}, {}", rd, rs1, shamt),
+                FUNCT3_SRLI if funct7 == 0x00 => format!("srli {}, {}, {}", rd, rs1, shamt),
+                _ => unknown(),
+            }
+        }
+        I_LOAD_FORMAT => {
+            let mnemonic = match funct3 {
+                FUNCT3_LB => "lb",
+                FUNCT3_LH => "lh",
+                FUNCT3_LW => "lw",
+                FUNCT3_LBU => "lbu",
+                FUNCT3_LHU => "lhu",
+                _ => return unknown(),
+            };
+            format!("{} {}, {}({})", mnemonic, rd, imm_i, rs1)
+        }
+        S_FORMAT => {
+            let mnemonic = match funct3 {
+                FUNCT3_SB => "sb",
+                FUNCT3_SH => "sh",
+                FUNCT3_SW => "sw",
+                _ => return unknown(),
+            };
+            let imm = sign_extend(((instruction >> 20) & 0xFE0) | ((instruction >> 7) & 0x1F), 12);
+            format!("{} {}, {}({})", mnemonic, rs2, imm, rs1)
+        }
+        B_FORMAT => {
+            let target = target(instruction, pc).unwrap_or_default();
+            // Comparisons against zero have their own mnemonics, with the
+            // operands swapped for bgtz/blez.
+            let zero_form = match (funct3, rs1_index, rs2_index) {
+                (FUNCT3_BEQ, _, 0) => Some(("beqz", rs1)),
+                (FUNCT3_BNE, _, 0) => Some(("bnez", rs1)),
+                (FUNCT3_BLT, _, 0) => Some(("bltz", rs1)),
+                (FUNCT3_BGE, _, 0) => Some(("bgez", rs1)),
+                (FUNCT3_BLT, 0, _) => Some(("bgtz", rs2)),
+                (FUNCT3_BGE, 0, _) => Some(("blez", rs2)),
+                _ => None,
+            };
+            if let Some((mnemonic, operand)) = zero_form {
+                return format!("{} {}, 0x{:08x}", mnemonic, operand, target);
+            }
+            let mnemonic = match funct3 {
+                FUNCT3_BEQ => "beq",
+                FUNCT3_BNE => "bne",
+                FUNCT3_BLT => "blt",
+                FUNCT3_BGE => "bge",
+                FUNCT3_BLTU => "bltu",
+                FUNCT3_BGEU => "bgeu",
+                _ => return unknown(),
+            };
+            format!("{} {}, {}, 0x{:08x}", mnemonic, rs1, rs2, target)
+        }
+        U_FORMAT_LUI => format!("lui {}, 0x{:x}", rd, instruction >> 12),
+        U_FORMAT_AUIPC => format!("auipc {}, 0x{:x}", rd, instruction >> 12),
+        J_FORMAT => {
+            let target = target(instruction, pc).unwrap_or_default();
+            match rd_index {
+                0 => format!("j 0x{:08x}", target),
+                1 => format!("jal 0x{:08x}", target),
+                _ => format!("jal {}, 0x{:08x}", rd, target),
+            }
+        }
+        I_JALR_FORMAT if funct3 == 0 => match (rd_index, rs1_index, imm_i) {
+            (0, 1, 0) => "ret".to_string(),
+            (0, _, 0) => format!("jr {}", rs1),
+            (1, _, 0) => format!("jalr {}", rs1),
+            _ => format!("jalr {}, {}({})", rd, imm_i, rs1),
+        },
+        MISC_MEM_FORMAT => match funct3 {
+            0 => "fence".to_string(),
+            1 => "fence.i".to_string(),
+            _ => unknown(),
+        },
+        I_ENV_FORMAT => match instruction {
+            ECALL => "ecall".to_string(),
+            EBREAK => "ebreak".to_string(),
+            _ => unknown(),
+        },
+        _ => unknown(),
+    }
+}
+
+/// `disassemble` with the symbol a branch or jump lands on, e.g.
+/// `jal 0x80000018 <func>`.
+pub fn disassemble_with(instruction: u32, pc: u32, symbols: &Symbols) -> String {
+    let text = disassemble(instruction, pc);
+    match target(instruction, pc).and_then(|addr| symbols.label(addr)) {
+        Some(label) => format!("{} {}", text, label),
+        None => text,
+    }
+}
+
+/// `disasm` subcommand: objdump style listing of a hex, assembly or ELF
+/// program. Hex and assembly files are listed from `base`, ELF files by
+/// executable segment. ELF symbols and assembly labels are shown.
+pub fn print_listing(path: &str, base: u32) -> Result<(), Box<dyn Error>> {
+    let file = fs::read(path)?;
+    let (sections, symbols) = if file.starts_with(b"\x7fELF") {
+        let image = ElfImage::parse(&file)?;
+        let sections = image.segments.into_iter()
+            .filter(|segment| segment.executable)
+            .map(|segment| (segment.vaddr, segment.data))
+            .collect::<Vec<_>>();
+        (sections, image.symbols)
+    } else if asm::is_source(path) {
+        let assembly = asm::assemble_file(path, base)?;
+        (vec![(base, assembly.words.iter().flat_map(|word| word.to_le_bytes()).collect())], assembly.symbols)
+    } else {
+        let words = parse_hex_file(path)?;
+        (vec![(base, words.iter().flat_map(|word| word.to_le_bytes()).collect())], Symbols::default())
+    };
+    for (start, data) in sections {
+        println!("\nDisassembly of 0x{:08x}..0x{:08x}:", start, start as usize + data.len());
+        for (i, chunk) in data.chunks(4).enumerate() {
+            let addr = start + (i * 4) as u32;
+            if let Some((name, 0)) = symbols.lookup(addr) {
+                println!("\n{:08x} <{}>:", addr, name);
+            }
+            let mut bytes = [0; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            let word = u32::from_le_bytes(bytes);
+            println!("{:8x}:\t{:08x}\t{}", addr, word, disassemble_with(word, addr, &symbols));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::Symbol;
+
+    const PC: u32 = 0x8000_0010;
+
+    #[test]
+    fn known_encodings() {
+        // Words and text as GNU objdump shows them.
+        let cases = [
+            (0x0050_0513, "li a0, 5"),
+            (0x0015_0513, "addi a0, a0, 1"),
+            (0x0000_0013, "nop"),
+            (0x0000_8067, "ret"),
+            (0x40b5_0533, "sub a0, a0, a1"),
+            (0x00a5_a023, "sw a0, 0(a1)"),
+            (0xffc1_2503, "lw a0, -4(sp)"),
+            (0xfff5_4513, "not a0, a0"),
+            (0x8000_05b7, "lui a1, 0x80000"),
+            (0x0000_0517, "auipc a0, 0x0"),
+            (0x4015_5513, "srai a0, a0, 1"),
+            (0x0ff0_000f, "fence"),
+            (0x0000_100f, "fence.i"),
+            (0x0000_0073, "ecall"),
+            (0x0010_0073, "ebreak"),
+            (0xfe05_02e3, "beqz a0, 0x7ffffff4"),
+            (0x0000_006f, "j 0x80000010"),
+            (0x0080_00ef, "jal 0x80000018"),
+        ];
+        for (word, text) in cases {
+            assert_eq!(disassemble(word, PC), text, "0x{:08x}", word);
+        }
+    }
+
+    #[test]
+    fn unknown_words() {
+        // csrw cycle, zero: no Zicsr, and an opcode RV32I doesn't have.
+        assert_eq!(disassemble(0xc000_1073, PC), ".word 0xc0001073");
+        assert_eq!(disassemble(0x0000_007f, PC), ".word 0x0000007f");
+        assert_eq!(disassemble(0x0000_3003, PC), ".word 0x00003003");
+    }
+
+    #[test]
+    fn branch_targets() {
+        assert_eq!(target(0xfe05_02e3, PC), Some(0x7FFF_FFF4));
+        assert_eq!(target(0x0080_00ef, PC), Some(0x8000_0018));
+        assert_eq!(target(0x0000_8067, PC), None);
+    }
+
+    #[test]
+    fn targets_are_labelled() {
+        let symbols = Symbols::new(vec![Symbol { name: "func".to_string(), addr: 0x8000_0014, size: 8 }]);
+        assert_eq!(disassemble_with(0x0080_00ef, PC, &symbols), "jal 0x80000018 <func+4>");
+        assert_eq!(disassemble_with(0x0050_0513, PC, &symbols), "li a0, 5");
+    }
+
+    #[test]
+    fn listing_assembles_back() {
+        // Every word the disassembler can name reassembles to itself.
+        let words = [0x0050_0513, 0x40b5_0533, 0xffc1_2503, 0x00a5_a023, 0xfe05_02e3, 0x0080_00ef, 0x8000_05b7, 0x0ff0_000f];
+        for (i, &word) in words.iter().enumerate() {
+            let pc = PC + 4 * i as u32;
+            let text = disassemble(word, pc);
+            let source = format!(".org 0x{:x}\n{}", pc - PC, text);
+            assert_eq!(asm::assemble(&source, PC).unwrap().words.last(), Some(&word), "{}", text);
+        }
+    }
+}
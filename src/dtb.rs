@@ -0,0 +1,307 @@
+use crate::constants::*;
+use crate::platform::Platform;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+const FDT_HEADER_SIZE: usize = 40;
+
+const CPU_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+
+// Local interrupt numbers of the hart, the cells of cpu-intc.
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+/// Minimal flattened device tree writer (DTSpec v0.4, version 17 blobs).
+struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    reserved: Vec<(u64, u64)>,
+}
+
+impl FdtWriter {
+    fn new() -> Self {
+        Self { structure: Vec::new(), strings: Vec::new(), reserved: Vec::new() }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        // Reuse an identical, already interned property name.
+        let mut start = 0;
+        for chunk in self.strings.split(|&b| b == 0) {
+            if chunk == name.as_bytes() {
+                return start as u32;
+            }
+            start += chunk.len() + 1;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+    }
+
+    fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+
+    fn prop(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+
+    fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    fn prop_u32(&mut self, name: &str, value: u32) {
+        self.prop(name, &value.to_be_bytes());
+    }
+
+    fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.prop(name, &bytes);
+    }
+
+    fn prop_str(&mut self, name: &str, value: &str) {
+        self.prop_strs(name, &[value]);
+    }
+
+    fn prop_strs(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.prop(name, &bytes);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.push_u32(FDT_END);
+        let rsvmap_offset = FDT_HEADER_SIZE;
+        let rsvmap_size = (self.reserved.len() + 1) * 16;
+        let struct_offset = rsvmap_offset + rsvmap_size;
+        let strings_offset = struct_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            struct_offset as u32,
+            strings_offset as u32,
+            rsvmap_offset as u32,
+            17, // version
+            16, // last compatible version
+            0,  // boot cpuid
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        for (addr, size) in self.reserved.iter().chain(std::iter::once(&(0, 0))) {
+            blob.extend_from_slice(&addr.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// Where the boot flow puts the framebuffer and the DTB inside RAM.
+pub struct DtbLayout {
+    pub framebuffer: Option<u32>,
+    pub dtb_addr: u32,
+}
+
+impl DtbLayout {
+    /// Carves the framebuffer (if the board has a screen) and then the DTB
+    /// out of the top of RAM, leaving the bottom of RAM to the program.
+    pub fn new(platform: &Platform, dtb_size: usize) -> Self {
+        let mut top = platform.ram.base as u64 + platform.ram.size as u64;
+        let fb_size = (SCREEN_WIDTH * SCREEN_HEIGHT * 2) as u64;
+        // Small boards keep all of their RAM rather than losing most of it to the screen.
+        let framebuffer = platform.screen_csr_base().filter(|_| platform.ram.size as u64 >= 4 * fb_size).map(|_| {
+            top = (top - fb_size) & !0xFFF;
+            top as u32
+        });
+        let dtb_addr = ((top - dtb_size as u64) & !0x7) as u32;
+        Self { framebuffer, dtb_addr }
+    }
+}
+
+/// Generates the device tree for `platform`. Sizes do not depend on
+/// the addresses, so callers may build once to size the layout and rebuild.
+pub fn generate(platform: &Platform, layout: &DtbLayout, dtb_size: u32) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    fdt.reserved.push((layout.dtb_addr as u64, dtb_size as u64));
+
+    fdt.begin_node("");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 1);
+    fdt.prop_str("compatible", "riscy,board");
+    fdt.prop_str("model", &platform.name);
+
+    fdt.begin_node("chosen");
+    if let Some(uart) = platform.find_device("uart_ns16550a") {
+        fdt.prop_str("stdout-path", &format!("/soc/serial@{:x}", uart.base));
+    }
+    if let Some((base, size)) = platform.app_image() {
+        fdt.prop_cells("riscy,app-image", &[base, size]);
+    }
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    fdt.prop_u32("timebase-frequency", platform.clock_hz);
+    fdt.begin_node("cpu@0");
+    fdt.prop_str("device_type", "cpu");
+    fdt.prop_u32("reg", 0);
+    fdt.prop_str("status", "okay");
+    fdt.prop_str("compatible", "riscv");
+    fdt.prop_str("riscv,isa", &platform.isa.to_ascii_lowercase());
+    fdt.prop_str("mmu-type", "riscv,none");
+    fdt.begin_node("interrupt-controller");
+    fdt.prop_u32("#interrupt-cells", 1);
+    fdt.prop_empty("interrupt-controller");
+    fdt.prop_str("compatible", "riscv,cpu-intc");
+    fdt.prop_u32("phandle", CPU_INTC_PHANDLE);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", platform.ram.base));
+    fdt.prop_str("device_type", "memory");
+    fdt.prop_cells("reg", &[platform.ram.base, platform.ram.size]);
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 1);
+    fdt.prop_str("compatible", "simple-bus");
+    fdt.prop_empty("ranges");
+
+    fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    fdt.prop_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.prop_cells("reg", &[CLINT_BASE, CLINT_SIZE]);
+    fdt.prop_cells("interrupts-extended", &[CPU_INTC_PHANDLE, IRQ_M_SOFT, CPU_INTC_PHANDLE, IRQ_M_TIMER]);
+    fdt.end_node();
+
+    // Device irq numbers are PLIC sources, 0 means none.
+    fdt.begin_node(&format!("interrupt-controller@{:x}", PLIC_BASE));
+    fdt.prop_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    fdt.prop_cells("reg", &[PLIC_BASE, PLIC_SIZE]);
+    fdt.prop_u32("#address-cells", 0);
+    fdt.prop_u32("#interrupt-cells", 1);
+    fdt.prop_empty("interrupt-controller");
+    fdt.prop_cells("interrupts-extended", &[CPU_INTC_PHANDLE, IRQ_M_EXT, CPU_INTC_PHANDLE, IRQ_S_EXT]);
+    fdt.prop_u32("riscv,ndev", 31);
+    fdt.prop_u32("phandle", PLIC_PHANDLE);
+    fdt.end_node();
+
+    for dev in &platform.devices {
+        match dev.kind.as_str() {
+            "uart_ns16550a" => {
+                fdt.begin_node(&format!("serial@{:x}", dev.base));
+                fdt.prop_str("compatible", "ns16550a");
+                fdt.prop_cells("reg", &[dev.base, dev.size]);
+                fdt.prop_u32("clock-frequency", platform.clock_hz);
+            }
+            "vga_text_mode" => {
+                fdt.begin_node(&format!("display@{:x}", dev.base));
+                fdt.prop_str("compatible", "riscy,vga-text-mode");
+                fdt.prop_cells("reg", &[dev.base, dev.size]);
+            }
+            "screen_csr" => {
+                fdt.begin_node(&format!("display-csr@{:x}", dev.base));
+                fdt.prop_str("compatible", "riscy,screen-csr");
+                fdt.prop_cells("reg", &[dev.base, dev.size]);
+            }
+            "spi_sd" => {
+                fdt.begin_node(&format!("spi@{:x}", dev.base));
+                fdt.prop_str("compatible", "riscy,spi");
+                fdt.prop_cells("reg", &[dev.base, dev.size]);
+                fdt.prop_u32("#address-cells", 1);
+                fdt.prop_u32("#size-cells", 0);
+                fdt.begin_node("mmc@0");
+                fdt.prop_str("compatible", "mmc-spi-slot");
+                fdt.prop_u32("reg", 0);
+                fdt.prop_u32("spi-max-frequency", 25_000_000);
+                fdt.prop_cells("voltage-ranges", &[3300, 3300]);
+                fdt.end_node();
+            }
+            "dma" => {
+                fdt.begin_node(&format!("dma-controller@{:x}", dev.base));
+                fdt.prop_str("compatible", "riscy,dma");
+                fdt.prop_cells("reg", &[dev.base, dev.size]);
+            }
+            "virtio_blk" | "virtio_console" => {
+                fdt.begin_node(&format!("virtio_mmio@{:x}", dev.base));
+                fdt.prop_str("compatible", "virtio,mmio");
+                fdt.prop_cells("reg", &[dev.base, dev.size]);
+            }
+            _ => {
+                fdt.begin_node(&format!("{}@{:x}", dev.name(), dev.base));
+                fdt.prop_str("compatible", &format!("riscy,{}", dev.kind.replace('_', "-")));
+                fdt.prop_cells("reg", &[dev.base, dev.size]);
+            }
+        }
+        if let Some(irq) = dev.irq {
+            fdt.prop_u32("interrupt-parent", PLIC_PHANDLE);
+            fdt.prop_u32("interrupts", irq);
+        }
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    if let Some(fb) = layout.framebuffer {
+        let size = (SCREEN_WIDTH * SCREEN_HEIGHT * 2) as u32;
+        fdt.reserved.push((fb as u64, size as u64));
+        fdt.begin_node(&format!("framebuffer@{:x}", fb));
+        fdt.prop_str("compatible", "simple-framebuffer");
+        fdt.prop_cells("reg", &[fb, size]);
+        fdt.prop_u32("width", SCREEN_WIDTH as u32);
+        fdt.prop_u32("height", SCREEN_HEIGHT as u32);
+        fdt.prop_u32("stride", (SCREEN_WIDTH * 2) as u32);
+        fdt.prop_str("format", "r5g6b5");
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// Builds the final blob and its placement in one go.
+pub fn build(platform: &Platform) -> (Vec<u8>, DtbLayout) {
+    let size = generate(platform, &DtbLayout::new(platform, 0), 0).len();
+    let layout = DtbLayout::new(platform, size);
+    let blob = generate(platform, &layout, size as u32);
+    debug_assert_eq!(blob.len(), size);
+    (blob, layout)
+}
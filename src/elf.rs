@@ -0,0 +1,205 @@
+use std::error::Error;
+use std::fs;
+
+use crate::bus::Bus;
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+
+pub struct Segment {
+    pub vaddr: u32,
+    pub memsz: u32,
+    pub data: Vec<u8>,
+    pub executable: bool,
+}
+
+#[derive(Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+/// Function and object symbols from `.symtab`, sorted by address.
+#[derive(Default, Clone)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.addr);
+        Self { symbols }
+    }
+
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.addr)
+    }
+
+    /// The symbol containing `addr` and the offset into it, falling back to
+    /// the closest symbol below for entries without a size.
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let index = self.symbols.partition_point(|symbol| symbol.addr <= addr).checked_sub(1)?;
+        let symbol = &self.symbols[index];
+        if symbol.size != 0 && addr - symbol.addr >= symbol.size {
+            return None;
+        }
+        Some((&symbol.name, addr - symbol.addr))
+    }
+
+    /// `<main+16>` style label.
+    pub fn label(&self, addr: u32) -> Option<String> {
+        self.lookup(addr).map(|(name, offset)| match offset {
+            0 => format!("<{}>", name),
+            _ => format!("<{}+{}>", name, offset),
+        })
+    }
+
+    /// `0x80000010 <main+16>` style label.
+    pub fn describe(&self, addr: u32) -> String {
+        match self.label(addr) {
+            Some(label) => format!("0x{:08x} {}", addr, label),
+            None => format!("0x{:08x}", addr),
+        }
+    }
+}
+
+/// A statically linked RV32 executable, ready to be copied into memory.
+pub struct ElfImage {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    /// Address of the program headers once loaded, for AT_PHDR.
+    pub phdr: u32,
+    pub phent: u32,
+    pub phnum: u32,
+    pub symbols: Symbols,
+}
+
+impl ElfImage {
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(file: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let u16_at = |offset: usize| -> Result<u16, String> {
+            file.get(offset..offset + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .ok_or_else(|| "truncated ELF file".to_string())
+        };
+        let u32_at = |offset: usize| -> Result<u32, String> {
+            file.get(offset..offset + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| "truncated ELF file".to_string())
+        };
+        if file.get(0..4) != Some(b"\x7fELF".as_slice()) {
+            return Err("not an ELF file".into());
+        }
+        if file.get(4..6) != Some([ELFCLASS32, ELFDATA2LSB].as_slice()) {
+            return Err("only little endian ELF32 files are supported".into());
+        }
+        if u16_at(16)? != ET_EXEC {
+            return Err("only statically linked executables are supported".into());
+        }
+        if u16_at(18)? != EM_RISCV {
+            return Err("not a RISC-V executable".into());
+        }
+        let entry = u32_at(24)?;
+        let phoff = u32_at(28)? as usize;
+        let phent = u16_at(42)? as u32;
+        let phnum = u16_at(44)? as u32;
+
+        let mut segments = Vec::new();
+        let mut phdr = None;
+        for i in 0..phnum as usize {
+            let header = phoff + i * phent as usize;
+            let kind = u32_at(header)?;
+            let offset = u32_at(header + 4)?;
+            let vaddr = u32_at(header + 8)?;
+            let filesz = u32_at(header + 16)?;
+            let memsz = u32_at(header + 20)?;
+            let flags = u32_at(header + 24)?;
+            match kind {
+                PT_PHDR => phdr = Some(vaddr),
+                PT_LOAD => {
+                    let end = offset.checked_add(filesz).ok_or("segment extends past the end of the file")?;
+                    let data = file.get(offset as usize..end as usize)
+                        .ok_or("segment extends past the end of the file")?;
+                    // The headers are usually part of the first loaded segment.
+                    if phdr.is_none() && (offset as usize..end as usize).contains(&phoff) {
+                        phdr = Some(vaddr + (phoff as u32 - offset));
+                    }
+                    segments.push(Segment { vaddr, memsz, data: data.to_vec(), executable: flags & PF_X != 0 });
+                }
+                _ => {}
+            }
+        }
+        let symbols = Self::parse_symbols(file)?;
+        Ok(Self { entry, segments, phdr: phdr.unwrap_or(0), phent, phnum, symbols })
+    }
+
+    fn parse_symbols(file: &[u8]) -> Result<Symbols, String> {
+        let slice = |offset: usize, len: usize| file.get(offset..offset + len).ok_or_else(|| "truncated ELF file".to_string());
+        let u16_at = |offset: usize| slice(offset, 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let u32_at = |offset: usize| slice(offset, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let shoff = u32_at(32)? as usize;
+        let shentsize = u16_at(46)? as usize;
+        let shnum = u16_at(48)? as usize;
+        let section = |index: usize| shoff + index * shentsize;
+
+        let mut symbols = Vec::new();
+        for index in 0..shnum {
+            if u32_at(section(index) + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let offset = u32_at(section(index) + 16)? as usize;
+            let size = u32_at(section(index) + 20)? as usize;
+            let strtab = u32_at(section(u32_at(section(index) + 24)? as usize) + 16)? as usize;
+            for entry in (offset..offset + size).step_by(16) {
+                let info = slice(entry + 12, 1)?[0];
+                if !matches!(info & 0xF, STT_NOTYPE | STT_FUNC | STT_OBJECT) || u16_at(entry + 14)? == SHN_UNDEF {
+                    continue;
+                }
+                let name_start = strtab + u32_at(entry)? as usize;
+                let name = file.get(name_start..)
+                    .and_then(|tail| tail.split(|&b| b == 0).next())
+                    .ok_or("symbol name outside of the file")?;
+                // Skip local labels (.L*) and mapping symbols ($x, $d).
+                if name.is_empty() || name[0] == b'.' || name[0] == b'$' {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    addr: u32_at(entry + 4)?,
+                    size: u32_at(entry + 8)?,
+                });
+            }
+        }
+        Ok(Symbols::new(symbols))
+    }
+
+    /// First address past the highest segment, where the heap starts.
+    pub fn end(&self) -> u32 {
+        self.segments.iter().map(|s| s.vaddr.saturating_add(s.memsz)).max().unwrap_or(0)
+    }
+
+    /// Copies every segment into memory, zero filling the .bss part.
+    pub fn load(&self, bus: &mut Bus) -> Result<(), String> {
+        for segment in &self.segments {
+            let mut data = segment.data.clone();
+            data.resize(segment.memsz.max(data.len() as u32) as usize, 0);
+            bus.write_bytes(segment.vaddr, &data)
+                .map_err(|_| format!("segment at 0x{:08X} is outside of memory", segment.vaddr))?;
+        }
+        Ok(())
+    }
+}
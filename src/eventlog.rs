@@ -0,0 +1,430 @@
+//! Binary event log written by `--log`, one record per instruction effect.
+//!
+//! Layout, all integers little endian:
+//! ```text
+//! header  magic "RVEVTLOG", version u16, header length u16 (bytes that
+//!         follow it), start pc u32, start cycle u64, ISA and tool
+//!         version as u8 length + UTF-8
+//! record  tag u8, then pc u32, opcode u32 and cycle skip unless the tag
+//!         has CONTINUED set, then the payload of the event kind:
+//!         0 RegWrite   reg u8, value u32
+//!         1 MemRead    addr u32, value u32, size u8
+//!         2 MemWrite   addr u32, value u32, size u8
+//!         3 FlowChange new_pc u32
+//!         4 FlowLink   new_pc u32, register u8
+//!         5 Trap       cause u32, tval u32
+//!         6 CsrWrite   csr u16, value u32
+//!         7 Pipeline   decode, rename, issue, dispatch, resolve and
+//!                      commit cycle, u64 each
+//! ```
+//! Instructions with several effects write one record per effect; all but
+//! the first carry CONTINUED and share its pc, opcode and cycle.
+//!
+//! The cycle skip is how far the instruction's cycle is
+//! from the one after the previous instruction (the start cycle for the
+//! first), as a zigzag LEB128 varint: one byte of 0 when nothing happened
+//! in between, negative after a reset or going back with `bs`/`bc`.
+
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+
+use crate::types::{Event, EventType, PipelineTimes};
+
+pub const MAGIC: [u8; 8] = *b"RVEVTLOG";
+/// Bumped on every change to the layout above. Other versions are rejected.
+pub const VERSION: u16 = 1;
+pub const TOOL: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Tag bit for a further effect of the previous record's instruction.
+const CONTINUED: u8 = 0x80;
+/// Tag, pc, opcode, the longest cycle skip and the largest payload.
+const MAX_RECORD: usize = 1 + 8 + 10 + 48;
+const READ_BUFFER: usize = 1 << 20;
+
+const TAG_REG_WRITE: u8 = 0;
+const TAG_MEM_READ: u8 = 1;
+const TAG_MEM_WRITE: u8 = 2;
+const TAG_FLOW_CHANGE: u8 = 3;
+const TAG_FLOW_LINK: u8 = 4;
+const TAG_TRAP: u8 = 5;
+const TAG_CSR_WRITE: u8 = 6;
+const TAG_PIPELINE: u8 = 7;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogHeader {
+    pub version: u16,
+    pub isa: String,
+    pub start_pc: u32,
+    /// Cycle of the first instruction, non-zero after `--restore`.
+    pub start_cycle: u64,
+    /// Name and version of the program that wrote the log.
+    pub tool: String,
+}
+
+impl LogHeader {
+    pub fn new(isa: &str, start_pc: u32, start_cycle: u64) -> Self {
+        Self { version: VERSION, isa: isa.to_string(), start_pc, start_cycle, tool: TOOL.to_string() }
+    }
+
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut fields = Vec::new();
+        fields.extend_from_slice(&self.start_pc.to_le_bytes());
+        fields.extend_from_slice(&self.start_cycle.to_le_bytes());
+        for text in [&self.isa, &self.tool] {
+            let bytes = &text.as_bytes()[..text.len().min(u8::MAX as usize)];
+            fields.push(bytes.len() as u8);
+            fields.extend_from_slice(bytes);
+        }
+        out.write_all(&MAGIC)?;
+        out.write_all(&self.version.to_le_bytes())?;
+        out.write_all(&(fields.len() as u16).to_le_bytes())?;
+        out.write_all(&fields)
+    }
+
+    fn read<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid("not an event log, or one written before the format was versioned"));
+        }
+        let version = read_u16(input)?;
+        if version != VERSION {
+            return Err(invalid(&format!("event log format version {} is not supported, expected {}", version, VERSION)));
+        }
+        let mut fields = vec![0; read_u16(input)? as usize];
+        input.read_exact(&mut fields)?;
+        // Fields a later revision appends to the header are skipped.
+        let mut fields = fields.as_slice();
+        let start_pc = read_u32(&mut fields)?;
+        let start_cycle = read_u64(&mut fields)?;
+        let isa = read_text(&mut fields)?;
+        let tool = read_text(&mut fields)?;
+        Ok(Self { version, isa, start_pc, start_cycle, tool })
+    }
+}
+
+pub struct LogWriter<W: Write> {
+    out: W,
+    /// Cycle the cycle skip of the next instruction is counted from.
+    next_cycle: u64,
+}
+
+impl LogWriter<BufWriter<File>> {
+    pub fn create(path: &str, header: &LogHeader) -> io::Result<Self> {
+        Self::new(BufWriter::with_capacity(64 * 1024, File::create(path)?), header)
+    }
+}
+
+impl<W: Write> LogWriter<W> {
+    pub fn new(mut out: W, header: &LogHeader) -> io::Result<Self> {
+        header.write(&mut out)?;
+        Ok(Self { out, next_cycle: header.start_cycle })
+    }
+
+    /// Writes a batch from `Cpu::run`, which always ends on an instruction
+    /// boundary: the events of one cycle are one instruction's effects.
+    pub fn write(&mut self, events: &[Event]) -> io::Result<()> {
+        for effects in events.chunk_by(|a, b| a.cycle == b.cycle) {
+            self.write_instruction(effects)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every effect of one instruction.
+    pub fn write_instruction(&mut self, effects: &[Event]) -> io::Result<()> {
+        let Some(first) = effects.first() else {
+            return Ok(());
+        };
+        let skip = first.cycle.wrapping_sub(self.next_cycle) as i64;
+        self.next_cycle = first.cycle.wrapping_add(1);
+        for (i, event) in effects.iter().enumerate() {
+            self.write_record(event, (i == 0).then_some(skip))?;
+        }
+        Ok(())
+    }
+
+    /// `skip` is None for the CONTINUED records.
+    fn write_record(&mut self, event: &Event, skip: Option<i64>) -> io::Result<()> {
+        let times;
+        let (tag, payload): (u8, &[&[u8]]) = match &event.instr_type {
+            EventType::RegWrite { reg, value } => (TAG_REG_WRITE, &[&[*reg], &value.to_le_bytes()]),
+            EventType::MemRead { addr, value, size } => (TAG_MEM_READ, &[&addr.to_le_bytes(), &value.to_le_bytes(), &[*size]]),
+            EventType::MemWrite { addr, value, size } => (TAG_MEM_WRITE, &[&addr.to_le_bytes(), &value.to_le_bytes(), &[*size]]),
+            EventType::FlowChange { new_pc } => (TAG_FLOW_CHANGE, &[&new_pc.to_le_bytes()]),
+            EventType::FlowLink { new_pc, register } => (TAG_FLOW_LINK, &[&new_pc.to_le_bytes(), &[*register]]),
+            EventType::Trap { cause, tval } => (TAG_TRAP, &[&cause.to_le_bytes(), &tval.to_le_bytes()]),
+            EventType::CsrWrite { csr, value } => (TAG_CSR_WRITE, &[&csr.to_le_bytes(), &value.to_le_bytes()]),
+            EventType::Pipeline(stages) => {
+                times = [stages.decode, stages.rename, stages.issue, stages.dispatch, stages.resolve, stages.commit]
+                    .map(u64::to_le_bytes).concat();
+                (TAG_PIPELINE, &[&times])
+            }
+        };
+        match skip {
+            None => self.out.write_all(&[tag | CONTINUED])?,
+            Some(skip) => {
+                self.out.write_all(&[tag])?;
+                self.out.write_all(&event.pc.to_le_bytes())?;
+                self.out.write_all(&event.opcode.to_le_bytes())?;
+                let mut zigzag = ((skip << 1) ^ (skip >> 63)) as u64;
+                while zigzag >= 0x80 {
+                    self.out.write_all(&[zigzag as u8 | 0x80])?;
+                    zigzag >>= 7;
+                }
+                self.out.write_all(&[zigzag as u8])?;
+            }
+        }
+        for field in payload {
+            self.out.write_all(field)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Iterates over the events of a log.
+pub struct LogReader<R: Read> {
+    input: R,
+    /// Records are parsed straight out of this buffer, `buffer[start..end]`
+    /// is still unread.
+    buffer: Box<[u8]>,
+    start: usize,
+    end: usize,
+    header: LogHeader,
+    /// pc, opcode and cycle of the last instruction read.
+    current: Option<(u32, u32, u64)>,
+}
+
+impl LogReader<File> {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+    }
+}
+
+impl<R: Read> LogReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let header = LogHeader::read(&mut input)?;
+        Ok(Self { input, buffer: vec![0; READ_BUFFER].into_boxed_slice(), start: 0, end: 0, header, current: None })
+    }
+
+    pub fn header(&self) -> &LogHeader {
+        &self.header
+    }
+
+    /// The next event, None at the end of the log.
+    pub fn read_event(&mut self) -> io::Result<Option<Event>> {
+        self.refill()?;
+        let unread = &self.buffer[self.start..self.end];
+        let Some(&tag) = unread.first() else {
+            return Ok(None);
+        };
+        let kind = tag & !CONTINUED;
+        let payload = match kind {
+            TAG_REG_WRITE | TAG_FLOW_LINK => 5,
+            TAG_MEM_READ | TAG_MEM_WRITE => 9,
+            TAG_FLOW_CHANGE => 4,
+            TAG_TRAP => 8,
+            TAG_CSR_WRITE => 6,
+            TAG_PIPELINE => 48,
+            other => return Err(invalid(&format!("unknown event tag {}", other))),
+        };
+        let truncated = || invalid("log ends in the middle of a record");
+        let next_cycle = self.current.map_or(self.header.start_cycle, |(_, _, cycle)| cycle.wrapping_add(1));
+        let (at, skip) = if tag & CONTINUED != 0 {
+            (1, 0)
+        } else {
+            let varint = unread.get(9..).ok_or_else(truncated)?;
+            let length = varint.iter().position(|byte| byte & 0x80 == 0).ok_or_else(truncated)? + 1;
+            if length > 10 {
+                return Err(invalid("cycle skip is longer than 64 bits"));
+            }
+            let zigzag = varint[..length].iter().rev().fold(0u64, |value, byte| value << 7 | (byte & 0x7F) as u64);
+            (9 + length, (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64))
+        };
+        let record = unread.get(..at + payload).ok_or_else(truncated)?;
+        let u32_at = |offset: usize| u32::from_le_bytes(record[offset..offset + 4].try_into().unwrap());
+        let (pc, opcode, cycle) = if tag & CONTINUED != 0 {
+            self.current.ok_or_else(|| invalid("continued record without an instruction"))?
+        } else {
+            (u32_at(1), u32_at(5), next_cycle.wrapping_add(skip as u64))
+        };
+        let instr_type = match kind {
+            TAG_REG_WRITE => EventType::RegWrite { reg: record[at], value: u32_at(at + 1) },
+            TAG_MEM_READ => EventType::MemRead { addr: u32_at(at), value: u32_at(at + 4), size: record[at + 8] },
+            TAG_MEM_WRITE => EventType::MemWrite { addr: u32_at(at), value: u32_at(at + 4), size: record[at + 8] },
+            TAG_FLOW_CHANGE => EventType::FlowChange { new_pc: u32_at(at) },
+            TAG_FLOW_LINK => EventType::FlowLink { new_pc: u32_at(at), register: record[at + 4] },
+            TAG_TRAP => EventType::Trap { cause: u32_at(at), tval: u32_at(at + 4) },
+            TAG_CSR_WRITE => EventType::CsrWrite { csr: u16::from_le_bytes([record[at], record[at + 1]]), value: u32_at(at + 2) },
+            _ => {
+                let u64_at = |stage: usize| u64::from_le_bytes(record[at + stage * 8..at + stage * 8 + 8].try_into().unwrap());
+                EventType::Pipeline(PipelineTimes {
+                    decode: u64_at(0),
+                    rename: u64_at(1),
+                    issue: u64_at(2),
+                    dispatch: u64_at(3),
+                    resolve: u64_at(4),
+                    commit: u64_at(5),
+                })
+            }
+        };
+        self.start += record.len();
+        self.current = Some((pc, opcode, cycle));
+        Ok(Some(Event { cycle, pc, opcode, instr_type }))
+    }
+
+    /// Tops the buffer up to at least a whole record, unless the log ends.
+    fn refill(&mut self) -> io::Result<()> {
+        if self.end - self.start >= MAX_RECORD {
+            return Ok(());
+        }
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        while self.end < MAX_RECORD {
+            match self.input.read(&mut self.buffer[self.end..]) {
+                Ok(0) => break,
+                Ok(read) => self.end += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces `effects` with every event of the next retired instruction,
+    /// false at the end of the log.
+    pub fn read_instruction(&mut self, effects: &mut Vec<Event>) -> io::Result<bool> {
+        effects.clear();
+        while let Some(event) = self.read_event()? {
+            effects.push(event);
+            self.refill()?;
+            if self.start == self.end || self.buffer[self.start] & CONTINUED == 0 {
+                break;
+            }
+        }
+        Ok(!effects.is_empty())
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_event().transpose()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_array<const N: usize, R: Read>(input: &mut R) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    Ok(read_array::<1, R>(input)?[0])
+}
+
+fn read_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    Ok(u16::from_le_bytes(read_array(input)?))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(input)?))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(read_array(input)?))
+}
+
+fn read_text<R: Read>(input: &mut R) -> io::Result<String> {
+    let mut text = vec![0; read_u8(input)? as usize];
+    input.read_exact(&mut text)?;
+    String::from_utf8(text).map_err(|_| invalid("header text is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(cycle: u64, pc: u32, instr_type: EventType) -> Event {
+        Event { cycle, pc, opcode: 0x0000_0013, instr_type }
+    }
+
+    fn write_log(header: &LogHeader, events: &[Event]) -> Vec<u8> {
+        let mut writer = LogWriter::new(Vec::new(), header).unwrap();
+        writer.write(events).unwrap();
+        writer.flush().unwrap();
+        writer.out
+    }
+
+    fn read_log(bytes: &[u8]) -> Vec<Vec<Event>> {
+        let mut reader = LogReader::new(bytes).unwrap();
+        let mut instructions = Vec::new();
+        let mut effects = Vec::new();
+        while reader.read_instruction(&mut effects).unwrap() {
+            instructions.push(effects.clone());
+        }
+        instructions
+    }
+
+    #[test]
+    fn round_trip() {
+        let times = PipelineTimes { decode: 1, rename: 2, issue: 3, dispatch: 4, resolve: 5, commit: u64::MAX };
+        let instructions = vec![
+            // Several effects: all but the first are CONTINUED records.
+            vec![
+                event(100, 0x8000_0000, EventType::RegWrite { reg: 10, value: 0xDEAD_BEEF }),
+                event(100, 0x8000_0000, EventType::Pipeline(times)),
+            ],
+            vec![event(101, 0x8000_0004, EventType::MemWrite { addr: 0x8000_1000, value: 0x80, size: 1 })],
+            // Cycles skipped forward, then backward.
+            vec![
+                event(105, 0x8000_0008, EventType::MemRead { addr: 0x1000_0005, value: 0x60, size: 1 }),
+                event(105, 0x8000_0008, EventType::RegWrite { reg: 11, value: 0x60 }),
+                event(105, 0x8000_0008, EventType::CsrWrite { csr: 0x341, value: 0x8000_0008 }),
+            ],
+            vec![event(103, 0x8000_000C, EventType::FlowLink { new_pc: 0x8000_0100, register: 1 })],
+            vec![event(104, 0x8000_0100, EventType::FlowChange { new_pc: 0x8000_0010 })],
+            // Needs a multi byte skip.
+            vec![event(1 << 40, 0x8000_0010, EventType::Trap { cause: 3, tval: 0 })],
+        ];
+        let header = LogHeader::new("rv32i", 0x8000_0000, 100);
+        let bytes = write_log(&header, &instructions.concat());
+        assert_eq!(LogReader::new(bytes.as_slice()).unwrap().header(), &header);
+        assert_eq!(read_log(&bytes), instructions);
+    }
+
+    #[test]
+    fn consecutive_cycles_take_one_byte() {
+        let header = LogHeader::new("rv32i", 0, 0);
+        let empty = write_log(&header, &[]).len();
+        let events: Vec<Event> = (0..10).map(|cycle| event(cycle, 4 * cycle as u32, EventType::FlowChange { new_pc: 0 })).collect();
+        assert_eq!(write_log(&header, &events).len(), empty + 10 * (1 + 8 + 1 + 4));
+    }
+
+    #[test]
+    fn rejects_bad_logs() {
+        let header = LogHeader::new("rv32i", 0, 0);
+        let bytes = write_log(&header, &[event(0, 0, EventType::Trap { cause: 2, tval: 0 })]);
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(LogReader::new(truncated).unwrap().read_event().is_err());
+
+        let mut continued = write_log(&header, &[]);
+        continued.extend_from_slice(&[TAG_FLOW_CHANGE | CONTINUED, 0, 0, 0, 0]);
+        assert!(LogReader::new(continued.as_slice()).unwrap().read_event().is_err());
+
+        let mut future = LogHeader::new("rv32i", 0, 0);
+        future.version = VERSION + 1;
+        assert!(LogReader::new(write_log(&future, &[]).as_slice()).is_err());
+        assert!(LogReader::new(b"not a log at all".as_slice()).is_err());
+    }
+}
@@ -3,8 +3,9 @@ use std::time::Duration;
 use eframe::egui::RichText;
 use eframe::egui::{self, TextureHandle, Color32};
 
-use crate::types::{Ctrl, CtrlMessage, ScreenMsg, ScreenType};
-use crate::constants::*;
+use risv_emulator::types::{Ctrl, CtrlMessage, DebugState, ScreenMsg, ScreenType};
+use risv_emulator::constants::*;
+use crate::gui_debug::DebugPanels;
 
 pub struct GUIApp {
     pub tex: Option<TextureHandle>,
@@ -13,6 +14,8 @@ pub struct GUIApp {
     pub screen_rx: Option<mpsc::Receiver<ScreenMsg>>,
     pub uart_rx: Option<mpsc::Receiver<char>>,
     pub ctrl_tx: Option<mpsc::Sender<CtrlMessage>>,
+    pub state_rx: Option<mpsc::Receiver<DebugState>>,
+    pub debug: DebugPanels,
     rgba: Vec<u8>,
     uart_buffer: String,
 }
@@ -79,6 +82,16 @@ impl eframe::App for GUIApp {
         }
         tex.set(img, egui::TextureOptions::NEAREST); // update each frame
 
+        if let Some(rx) = &self.state_rx {
+            self.debug.receive(rx);
+        }
+        if let Some(tx) = &self.ctrl_tx {
+            self.debug.request(tx);
+            self.debug.shortcuts(ctx, tx);
+            egui::TopBottomPanel::top("toolbar").show(ctx, |ui| self.debug.toolbar(ui, tx));
+            self.debug.show(ctx, tx);
+        }
+
         egui::CentralPanel::default()
             .frame(
                 egui::Frame {
@@ -109,6 +122,10 @@ impl eframe::App for GUIApp {
                         );
                     });
             });
+        if ctx.input(|i| i.key_pressed(egui::Key::F5))
+            && let Some(tx) = &self.ctrl_tx {
+            let _ = tx.send(CtrlMessage { command: Ctrl::SaveSnapshot });
+        }
         // Cap the framerate to ~30 FPS. No reason to stress the cpu thread
         ctx.request_repaint_after(Duration::from_millis(33)); // ~30 FPS
     }
@@ -132,6 +149,8 @@ impl Default for GUIApp {
             h,
             screen_rx: None,
             ctrl_tx: None,
+            state_rx: None,
+            debug: DebugPanels::new(RAM_BASE),
             uart_rx: None,
             rgba: black,
             uart_buffer: String::new(),
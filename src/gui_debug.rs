@@ -0,0 +1,350 @@
+use std::sync::mpsc;
+
+use eframe::egui::{self, Color32, Key, KeyboardShortcut, Modifiers, RichText};
+
+use risv_emulator::constants::REGISTER_NAMES;
+use risv_emulator::platform::parse_u32;
+use risv_emulator::types::{Ctrl, CtrlMessage, DebugState};
+
+const MEMORY_ROWS: u32 = 16;
+const MEMORY_COLUMNS: u32 = 16;
+const CHANGED: Color32 = Color32::from_rgb(255, 200, 60);
+const CURRENT: Color32 = Color32::from_rgb(120, 200, 255);
+const BREAKPOINT: Color32 = Color32::from_rgb(230, 70, 70);
+const MIN_SPEED: f64 = 0.1;
+/// The top of the speed slider runs unthrottled.
+const MAX_SPEED: f64 = 10.0;
+
+const RUN_PAUSE: KeyboardShortcut = KeyboardShortcut::new(Modifiers::NONE, Key::F8);
+const STEP: KeyboardShortcut = KeyboardShortcut::new(Modifiers::NONE, Key::F10);
+const RESET: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::R);
+const RELOAD: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), Key::R);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Placement {
+    Hidden,
+    /// In the side panel next to the screen.
+    Docked,
+    /// In its own movable window.
+    Floating,
+}
+
+#[derive(Clone, Copy)]
+enum Panel {
+    Registers,
+    Disassembly,
+    Memory,
+    Breakpoints,
+}
+impl Panel {
+    const ALL: [Panel; 4] = [Panel::Registers, Panel::Disassembly, Panel::Memory, Panel::Breakpoints];
+
+    fn title(self) -> &'static str {
+        match self {
+            Panel::Registers => "Registers",
+            Panel::Disassembly => "Disassembly",
+            Panel::Memory => "Memory",
+            Panel::Breakpoints => "Breakpoints",
+        }
+    }
+}
+
+fn send(tx: &mpsc::Sender<CtrlMessage>, command: Ctrl) {
+    let _ = tx.send(CtrlMessage { command });
+}
+
+/// Debugger toolbar and panels of the GUI, fed by `DebugState` replies.
+pub struct DebugPanels {
+    state: Option<DebugState>,
+    /// Registers as of the previous distinct state, for change highlighting.
+    previous_regs: [u32; 32],
+    changed: [bool; 32],
+    placements: [Placement; 4],
+    step_count: u64,
+    memory_addr: u32,
+    memory_addr_text: String,
+    /// Byte being edited in the memory viewer and the text typed so far.
+    editing: Option<(u32, String)>,
+    breakpoint_text: String,
+    clock_hz: u32,
+    /// Speed slider position, `MAX_SPEED` for unthrottled.
+    speed: f64,
+}
+
+impl DebugPanels {
+    pub fn new(memory_addr: u32) -> Self {
+        Self {
+            state: None,
+            previous_regs: [0; 32],
+            changed: [false; 32],
+            placements: [Placement::Docked, Placement::Docked, Placement::Hidden, Placement::Docked],
+            step_count: 1,
+            memory_addr,
+            memory_addr_text: format!("0x{:08X}", memory_addr),
+            editing: None,
+            breakpoint_text: String::new(),
+            clock_hz: 0,
+            speed: MAX_SPEED,
+        }
+    }
+
+    /// Core clock and the speed the CPU thread starts at, for the slider.
+    pub fn set_speed(&mut self, clock_hz: u32, speed: Option<f64>) {
+        self.clock_hz = clock_hz;
+        self.speed = speed.map_or(MAX_SPEED, |speed| speed.clamp(MIN_SPEED, MAX_SPEED));
+    }
+
+    /// Asks the CPU thread for a fresh state; the reply is picked up by
+    /// `receive` on a later frame.
+    pub fn request(&self, tx: &mpsc::Sender<CtrlMessage>) {
+        send(tx, Ctrl::RequestState { memory_addr: self.memory_addr, memory_len: MEMORY_ROWS * MEMORY_COLUMNS });
+    }
+
+    pub fn receive(&mut self, rx: &mpsc::Receiver<DebugState>) {
+        while let Ok(state) = rx.try_recv() {
+            let advanced = self.state.as_ref().is_none_or(|old| old.cycles != state.cycles);
+            if advanced {
+                if let Some(old) = &self.state {
+                    self.previous_regs = old.regs;
+                }
+                self.changed = std::array::from_fn(|i| state.regs[i] != self.previous_regs[i]);
+            }
+            self.state = Some(state);
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.state.as_ref().is_some_and(|state| state.running)
+    }
+
+    fn run_pause(&self, tx: &mpsc::Sender<CtrlMessage>) {
+        send(tx, if self.running() { Ctrl::Pause } else { Ctrl::Resume });
+    }
+
+    /// Run control keys, handled wherever the focus is.
+    pub fn shortcuts(&self, ctx: &egui::Context, tx: &mpsc::Sender<CtrlMessage>) {
+        // Reload first: Ctrl+R would also match Ctrl+Shift+R.
+        if ctx.input_mut(|i| i.consume_shortcut(&RELOAD)) {
+            send(tx, Ctrl::Reload);
+        }
+        if ctx.input_mut(|i| i.consume_shortcut(&RESET)) {
+            send(tx, Ctrl::Reset);
+        }
+        if ctx.input_mut(|i| i.consume_shortcut(&RUN_PAUSE)) {
+            self.run_pause(tx);
+        }
+        if ctx.input_mut(|i| i.consume_shortcut(&STEP)) && !self.running() {
+            send(tx, Ctrl::Step(self.step_count));
+        }
+    }
+
+    pub fn toolbar(&mut self, ui: &mut egui::Ui, tx: &mpsc::Sender<CtrlMessage>) {
+        let shortcut = |shortcut: &KeyboardShortcut| ui.ctx().format_shortcut(shortcut);
+        let (run_pause, step, reset, reload) = (shortcut(&RUN_PAUSE), shortcut(&STEP), shortcut(&RESET), shortcut(&RELOAD));
+        ui.horizontal(|ui| {
+            let running = self.running();
+            let label = if running { "⏸ Pause" } else { "▶ Run" };
+            if ui.button(label).on_hover_text(run_pause).clicked() {
+                self.run_pause(tx);
+            }
+            ui.add_enabled_ui(!running, |ui| {
+                if ui.button("Step").on_hover_text(step).clicked() {
+                    send(tx, Ctrl::Step(self.step_count));
+                }
+                ui.add(egui::DragValue::new(&mut self.step_count).range(1..=1_000_000).suffix(" instr"));
+            });
+            if ui.button("⟲ Reset").on_hover_text(format!("Reload the program and reset the devices ({})", reset)).clicked() {
+                send(tx, Ctrl::Reset);
+            }
+            if ui.button("⟳ Reload").on_hover_text(format!("Rebuild the machine from the files on disk ({})", reload)).clicked() {
+                send(tx, Ctrl::Reload);
+            }
+            ui.separator();
+            let slider = egui::Slider::new(&mut self.speed, MIN_SPEED..=MAX_SPEED)
+                .logarithmic(true)
+                .text("speed")
+                .custom_formatter(|speed, _| if speed >= MAX_SPEED { "unthrottled".to_string() } else { format!("{:.2}x", speed) });
+            let hover = format!("Multiple of the {} MHz core clock", self.clock_hz as f64 / 1e6);
+            if ui.add(slider).on_hover_text(hover).changed() {
+                send(tx, Ctrl::SetSpeed((self.speed < MAX_SPEED).then_some(self.speed)));
+            }
+            ui.menu_button("Panels", |ui| {
+                for panel in Panel::ALL {
+                    let placement = &mut self.placements[panel as usize];
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{:<12}", panel.title()));
+                        ui.radio_value(placement, Placement::Hidden, "Hidden");
+                        ui.radio_value(placement, Placement::Docked, "Docked");
+                        ui.radio_value(placement, Placement::Floating, "Floating");
+                    });
+                }
+            });
+            ui.separator();
+            if let Some(state) = &self.state {
+                let status = match (state.exit_code, state.running) {
+                    (Some(code), _) => format!("exited ({})", code),
+                    (None, true) => "running".to_string(),
+                    (None, false) => "paused".to_string(),
+                };
+                ui.label(RichText::new(format!("{}  pc 0x{:08X}  cycle {}", status, state.pc, state.cycles)).monospace());
+            }
+        });
+    }
+
+    /// Shows the docked side panel and the floating windows. Call before
+    /// the central panel so the dock takes its space from it.
+    pub fn show(&mut self, ctx: &egui::Context, tx: &mpsc::Sender<CtrlMessage>) {
+        if self.placements.contains(&Placement::Docked) {
+            egui::SidePanel::right("debug_dock")
+                .resizable(true)
+                .default_width(380.0)
+                .show(ctx, |ui| {
+                    egui::ScrollArea::vertical().show(ui, |ui| {
+                        for panel in Panel::ALL {
+                            if self.placements[panel as usize] == Placement::Docked {
+                                egui::CollapsingHeader::new(panel.title())
+                                    .default_open(true)
+                                    .show(ui, |ui| self.panel(ui, panel, tx));
+                            }
+                        }
+                    });
+                });
+        }
+        for panel in Panel::ALL {
+            if self.placements[panel as usize] != Placement::Floating {
+                continue;
+            }
+            let mut open = true;
+            egui::Window::new(panel.title())
+                .open(&mut open)
+                .resizable(true)
+                .show(ctx, |ui| self.panel(ui, panel, tx));
+            if !open {
+                self.placements[panel as usize] = Placement::Hidden;
+            }
+        }
+    }
+
+    fn panel(&mut self, ui: &mut egui::Ui, panel: Panel, tx: &mpsc::Sender<CtrlMessage>) {
+        let Some(state) = self.state.take() else {
+            ui.label("Waiting for the CPU thread...");
+            return;
+        };
+        match panel {
+            Panel::Registers => self.registers(ui, &state),
+            Panel::Disassembly => self.disassembly(ui, &state, tx),
+            Panel::Memory => self.memory(ui, &state, tx),
+            Panel::Breakpoints => self.breakpoints(ui, &state, tx),
+        }
+        self.state = Some(state);
+    }
+
+    fn registers(&self, ui: &mut egui::Ui, state: &DebugState) {
+        egui::Grid::new(ui.id().with("registers")).striped(true).show(ui, |ui| {
+            for row in 0..16 {
+                for i in [row, row + 16] {
+                    let color = if self.changed[i] { CHANGED } else { ui.visuals().text_color() };
+                    ui.label(RichText::new(format!("x{:<2} {:>4}", i, REGISTER_NAMES[i])).monospace());
+                    ui.label(RichText::new(format!("{:08X}", state.regs[i])).monospace().color(color));
+                }
+                ui.end_row();
+            }
+        });
+        ui.label(RichText::new(format!("pc 0x{:08X}   cycle {}", state.pc, state.cycles)).monospace());
+    }
+
+    fn disassembly(&self, ui: &mut egui::Ui, state: &DebugState, tx: &mpsc::Sender<CtrlMessage>) {
+        for line in &state.disassembly {
+            let has_breakpoint = state.breakpoints.iter().any(|&(addr, _)| addr == line.addr);
+            ui.horizontal(|ui| {
+                let marker = RichText::new(if has_breakpoint { "●" } else { "○" })
+                    .color(if has_breakpoint { BREAKPOINT } else { Color32::GRAY });
+                if ui.small_button(marker).on_hover_text("Toggle breakpoint").clicked() {
+                    if has_breakpoint {
+                        send(tx, Ctrl::RemoveBreakpoint(line.addr));
+                    } else {
+                        send(tx, Ctrl::AddBreakpoint(format!("0x{:08X}", line.addr)));
+                    }
+                }
+                let text = RichText::new(format!("{} {:08x}  {}", line.label, line.word, line.text)).monospace();
+                ui.label(if line.addr == state.pc { text.color(CURRENT).strong() } else { text });
+            });
+        }
+    }
+
+    fn memory(&mut self, ui: &mut egui::Ui, state: &DebugState, tx: &mpsc::Sender<CtrlMessage>) {
+        ui.horizontal(|ui| {
+            ui.label("Address");
+            let response = ui.add(egui::TextEdit::singleline(&mut self.memory_addr_text).desired_width(100.0));
+            let go = ui.button("Go").clicked() || (response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)));
+            if go && let Ok(addr) = parse_u32(&self.memory_addr_text) {
+                self.memory_addr = addr;
+            }
+            if ui.button("PC").clicked() {
+                self.memory_addr = state.pc & !0xF;
+                self.memory_addr_text = format!("0x{:08X}", self.memory_addr);
+            }
+        });
+        ui.label(RichText::new("Click a byte to edit it, -- is unmapped or a device register").small());
+        ui.spacing_mut().item_spacing.x = 4.0;
+        for row in 0..MEMORY_ROWS {
+            let row_addr = state.memory_addr.wrapping_add(row * MEMORY_COLUMNS);
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(format!("{:08X}", row_addr)).monospace().color(Color32::GRAY));
+                let mut ascii = String::new();
+                for column in 0..MEMORY_COLUMNS {
+                    let addr = row_addr.wrapping_add(column);
+                    let byte = state.memory.get((row * MEMORY_COLUMNS + column) as usize).copied().flatten();
+                    ascii.push(byte.filter(|b| b.is_ascii_graphic()).map_or('.', |b| b as char));
+                    self.memory_byte(ui, addr, byte, tx);
+                }
+                ui.label(RichText::new(ascii).monospace());
+            });
+        }
+    }
+
+    fn memory_byte(&mut self, ui: &mut egui::Ui, addr: u32, byte: Option<u8>, tx: &mpsc::Sender<CtrlMessage>) {
+        if let Some((editing, text)) = &mut self.editing && *editing == addr {
+            let response = ui.add(egui::TextEdit::singleline(text).desired_width(18.0).char_limit(2).font(egui::TextStyle::Monospace));
+            response.request_focus();
+            if response.lost_focus() {
+                if ui.input(|i| i.key_pressed(egui::Key::Enter)) && let Ok(value) = u8::from_str_radix(text.trim(), 16) {
+                    send(tx, Ctrl::WriteMemory { addr, value });
+                }
+                self.editing = None;
+            }
+            return;
+        }
+        let text = byte.map_or("--".to_string(), |b| format!("{:02X}", b));
+        let mut label = ui.add(egui::Label::new(RichText::new(text).monospace()).sense(egui::Sense::click()));
+        if byte.is_none() {
+            // Device registers aren't read, that could change their state.
+            label = label.on_hover_text("Unmapped or MMIO, not read");
+        }
+        if byte.is_some() && label.clicked() {
+            self.editing = Some((addr, String::new()));
+        }
+    }
+
+    fn breakpoints(&mut self, ui: &mut egui::Ui, state: &DebugState, tx: &mpsc::Sender<CtrlMessage>) {
+        if state.breakpoints.is_empty() {
+            ui.label("No breakpoints");
+        }
+        for (addr, label) in &state.breakpoints {
+            ui.horizontal(|ui| {
+                if ui.small_button("✖").on_hover_text("Remove").clicked() {
+                    send(tx, Ctrl::RemoveBreakpoint(*addr));
+                }
+                ui.label(RichText::new(label).monospace());
+            });
+        }
+        ui.horizontal(|ui| {
+            let response = ui.add(egui::TextEdit::singleline(&mut self.breakpoint_text).hint_text("address or symbol").desired_width(160.0));
+            let add = ui.button("Add").clicked() || (response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)));
+            if add && !self.breakpoint_text.trim().is_empty() {
+                send(tx, Ctrl::AddBreakpoint(self.breakpoint_text.trim().to_string()));
+                self.breakpoint_text.clear();
+            }
+        });
+    }
+}
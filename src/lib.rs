@@ -0,0 +1,31 @@
+//! The emulator core, shared by the `RISV_Emulator` binary and the
+//! `libriscy_cosim` co-simulation library (see `cosim`).
+
+// Devices fail accesses with `Err(())` and the bus turns that into a fault,
+// there is nothing more to say about the error.
+#![allow(clippy::result_unit_err)]
+
+pub mod asm;
+pub mod bus;
+pub mod commitlog;
+pub mod constants;
+pub mod cosim;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod dtb;
+pub mod elf;
+pub mod eventlog;
+pub mod linux_user;
+pub mod loader;
+pub mod pacer;
+pub mod peripherals;
+pub mod platform;
+pub mod semihosting;
+pub mod snapshot;
+pub mod spikelog;
+pub mod timetravel;
+pub mod trace;
+pub mod tracediff;
+pub mod types;
+pub mod vcd;
@@ -0,0 +1,486 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bus::{Addr, Bus};
+use crate::constants::USER_MEM_SIZE;
+use crate::elf::ElfImage;
+
+// RV32 Linux syscall numbers (asm-generic table, 64-bit time only).
+const SYS_GETCWD: u32 = 17;
+const SYS_FCNTL: u32 = 25;
+const SYS_IOCTL: u32 = 29;
+const SYS_UNLINKAT: u32 = 35;
+const SYS_FACCESSAT: u32 = 48;
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_LLSEEK: u32 = 62;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_READV: u32 = 65;
+const SYS_WRITEV: u32 = 66;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_SET_TID_ADDRESS: u32 = 96;
+const SYS_FUTEX: u32 = 98;
+const SYS_SET_ROBUST_LIST: u32 = 99;
+const SYS_SCHED_YIELD: u32 = 124;
+const SYS_SIGALTSTACK: u32 = 132;
+const SYS_RT_SIGACTION: u32 = 134;
+const SYS_RT_SIGPROCMASK: u32 = 135;
+const SYS_UNAME: u32 = 160;
+const SYS_GETPID: u32 = 172;
+const SYS_GETPPID: u32 = 173;
+const SYS_GETUID: u32 = 174;
+const SYS_GETEUID: u32 = 175;
+const SYS_GETGID: u32 = 176;
+const SYS_GETEGID: u32 = 177;
+const SYS_GETTID: u32 = 178;
+const SYS_BRK: u32 = 214;
+const SYS_MUNMAP: u32 = 215;
+const SYS_MMAP2: u32 = 222;
+const SYS_MPROTECT: u32 = 226;
+const SYS_MADVISE: u32 = 233;
+const SYS_GETRANDOM: u32 = 278;
+const SYS_STATX: u32 = 291;
+const SYS_CLOCK_GETTIME64: u32 = 403;
+
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const ENOTTY: i32 = 25;
+const ESPIPE: i32 = 29;
+const ERANGE: i32 = 34;
+const ENOSYS: i32 = 38;
+
+const AT_FDCWD: u32 = -100i32 as u32;
+const AT_REMOVEDIR: u32 = 0x200;
+const AT_EMPTY_PATH: u32 = 0x1000;
+
+const O_ACCMODE: u32 = 0o3;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const TCGETS: u32 = 0x5401;
+const TIOCGWINSZ: u32 = 0x5413;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const PAGE_SIZE: u32 = 4096;
+
+// Auxiliary vector entries handed to the C runtime.
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_HWCAP: u32 = 16;
+const AT_CLKTCK: u32 = 17;
+const AT_RANDOM: u32 = 25;
+
+const HWCAP_I: u32 = 1 << (b'I' - b'A');
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Linux personality for `--user` mode: ECALLs become host syscalls.
+pub struct LinuxUser {
+    fds: Vec<Option<Fd>>,
+    brk_start: Addr,
+    brk: Addr,
+    /// mmap hands out memory downwards from here, towards the heap.
+    mmap_top: Addr,
+    start: Instant,
+    seed: u64,
+}
+
+impl LinuxUser {
+    pub fn new(brk_start: Addr, mmap_top: Addr) -> Self {
+        let brk_start = brk_start.next_multiple_of(PAGE_SIZE);
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |t| t.as_nanos() as u64) | 1;
+        Self {
+            fds: vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)],
+            brk_start,
+            brk: brk_start,
+            mmap_top,
+            start: Instant::now(),
+            seed,
+        }
+    }
+
+    /// Runs syscall `nr` with arguments a0-a5. Returns the value for a0,
+    /// and the exit code once the program called exit.
+    pub fn syscall(&mut self, nr: u32, args: [u32; 6], bus: &mut Bus) -> (u32, Option<i32>) {
+        match nr {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                let _ = io::stdout().flush();
+                (0, Some(args[0] as i32))
+            }
+            _ => match self.dispatch(nr, args, bus) {
+                Ok(value) => (value, None),
+                Err(errno) => ((-errno) as u32, None),
+            },
+        }
+    }
+
+    fn dispatch(&mut self, nr: u32, a: [u32; 6], bus: &mut Bus) -> Result<u32, i32> {
+        let result = match nr {
+            SYS_READ => {
+                let mut data = vec![0u8; guest_len(a[1], a[2])?];
+                let read = self.read(a[0], &mut data)?;
+                write_mem(bus, a[1], &data[..read])?;
+                read as u32
+            }
+            SYS_WRITE => {
+                let data = read_mem(bus, a[1], a[2])?;
+                self.write(a[0], &data)? as u32
+            }
+            SYS_READV | SYS_WRITEV => {
+                let mut total = 0;
+                for i in 0..a[2] {
+                    let iov = a[1].wrapping_add(8 * i);
+                    let base = read_word(bus, iov)?;
+                    let len = read_word(bus, iov.wrapping_add(4))?;
+                    let done = if nr == SYS_READV {
+                        let mut data = vec![0u8; guest_len(base, len)?];
+                        let read = self.read(a[0], &mut data)?;
+                        write_mem(bus, base, &data[..read])?;
+                        read
+                    } else {
+                        self.write(a[0], &read_mem(bus, base, len)?)?
+                    };
+                    total += done as u32;
+                    if done < len as usize {
+                        break;
+                    }
+                }
+                total
+            }
+            SYS_OPENAT => {
+                let path = self.path(bus, a[0], a[1])?;
+                let mut options = OpenOptions::new();
+                match a[2] & O_ACCMODE {
+                    0 => options.read(true),
+                    1 => options.write(true),
+                    _ => options.read(true).write(true),
+                };
+                options.append(a[2] & O_APPEND != 0).truncate(a[2] & O_TRUNC != 0);
+                if a[2] & O_CREAT != 0 {
+                    if a[2] & O_EXCL != 0 { options.create_new(true) } else { options.create(true) };
+                }
+                let file = options.open(path).map_err(errno)?;
+                self.insert_fd(Fd::File(file))
+            }
+            SYS_CLOSE => {
+                self.fds.get_mut(a[0] as usize).and_then(Option::take).ok_or(EBADF)?;
+                0
+            }
+            SYS_LLSEEK => {
+                let offset = ((a[1] as u64) << 32 | a[2] as u64) as i64;
+                let position = match a[4] {
+                    0 => SeekFrom::Start(offset as u64),
+                    1 => SeekFrom::Current(offset),
+                    2 => SeekFrom::End(offset),
+                    _ => return Err(EINVAL),
+                };
+                let position = match self.fd(a[0])? {
+                    Fd::File(file) => file.seek(position).map_err(errno)?,
+                    _ => return Err(ESPIPE),
+                };
+                write_mem(bus, a[3], &position.to_le_bytes())?;
+                0
+            }
+            SYS_STATX => {
+                let path = read_cstr(bus, a[1])?;
+                let metadata = if path.is_empty() && a[2] & AT_EMPTY_PATH != 0 {
+                    match self.fd(a[0])? {
+                        Fd::File(file) => Some(file.metadata().map_err(errno)?),
+                        _ => None, // stdio looks like a terminal
+                    }
+                } else {
+                    Some(fs::metadata(self.path(bus, a[0], a[1])?).map_err(errno)?)
+                };
+                write_mem(bus, a[4], &statx(metadata.as_ref()))?;
+                0
+            }
+            SYS_IOCTL => match (self.fd(a[0])?, a[1]) {
+                (Fd::Stdin, TCGETS | TIOCGWINSZ) if io::stdin().is_terminal() => 0,
+                (Fd::Stdout, TCGETS | TIOCGWINSZ) if io::stdout().is_terminal() => 0,
+                (Fd::Stderr, TCGETS | TIOCGWINSZ) if io::stderr().is_terminal() => 0,
+                _ => return Err(ENOTTY),
+            },
+            SYS_FCNTL => {
+                self.fd(a[0])?;
+                0
+            }
+            SYS_GETCWD => {
+                let cwd = std::env::current_dir().map_err(errno)?;
+                let mut cwd = cwd.to_string_lossy().into_owned().into_bytes();
+                cwd.push(0);
+                if cwd.len() > a[1] as usize {
+                    return Err(ERANGE);
+                }
+                write_mem(bus, a[0], &cwd)?;
+                cwd.len() as u32
+            }
+            SYS_UNLINKAT => {
+                let path = self.path(bus, a[0], a[1])?;
+                if a[2] & AT_REMOVEDIR != 0 { fs::remove_dir(path) } else { fs::remove_file(path) }.map_err(errno)?;
+                0
+            }
+            SYS_FACCESSAT => {
+                let path = self.path(bus, a[0], a[1])?;
+                fs::metadata(path).map_err(errno)?;
+                0
+            }
+            SYS_BRK => {
+                if a[0] >= self.brk_start && a[0] <= self.mmap_top {
+                    if a[0] > self.brk {
+                        // The area may hold data from before an earlier shrink.
+                        write_mem(bus, self.brk, &vec![0u8; (a[0] - self.brk) as usize])?;
+                    }
+                    self.brk = a[0];
+                }
+                self.brk
+            }
+            SYS_MMAP2 => {
+                let len = a[1].checked_next_multiple_of(PAGE_SIZE).ok_or(ENOMEM)?;
+                let base = if a[3] & MAP_FIXED != 0 {
+                    write_mem(bus, a[0], &vec![0u8; guest_len(a[0], len)?])?;
+                    a[0]
+                } else {
+                    if self.mmap_top - self.brk < len {
+                        return Err(ENOMEM);
+                    }
+                    self.mmap_top -= len;
+                    self.mmap_top
+                };
+                if a[3] & MAP_ANONYMOUS == 0 {
+                    let Fd::File(file) = self.fd(a[4])? else { return Err(EBADF) };
+                    file.seek(SeekFrom::Start(a[5] as u64 * PAGE_SIZE as u64)).map_err(errno)?;
+                    let mut data = Vec::new();
+                    file.take(guest_len(base, a[1])? as u64).read_to_end(&mut data).map_err(errno)?;
+                    write_mem(bus, base, &data)?;
+                }
+                base
+            }
+            // Memory is flat and never reclaimed.
+            SYS_MUNMAP | SYS_MPROTECT | SYS_MADVISE => 0,
+            SYS_GETRANDOM => {
+                let data: Vec<u8> = (0..guest_len(a[0], a[1])?).map(|_| self.random() as u8).collect();
+                write_mem(bus, a[0], &data)?;
+                data.len() as u32
+            }
+            SYS_CLOCK_GETTIME64 => {
+                let (secs, nanos) = match a[0] {
+                    0 => {
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                        (now.as_secs(), now.subsec_nanos())
+                    }
+                    _ => {
+                        let elapsed = self.start.elapsed();
+                        (elapsed.as_secs(), elapsed.subsec_nanos())
+                    }
+                };
+                write_mem(bus, a[1], &secs.to_le_bytes())?;
+                write_mem(bus, a[1].wrapping_add(8), &(nanos as u64).to_le_bytes())?;
+                0
+            }
+            SYS_UNAME => {
+                let mut utsname = vec![0u8; 6 * 65];
+                for (i, field) in ["Linux", "riscy", "6.1.0", "#1", "riscv32"].iter().enumerate() {
+                    utsname[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                write_mem(bus, a[0], &utsname)?;
+                0
+            }
+            SYS_GETPID | SYS_GETTID | SYS_SET_TID_ADDRESS => 1,
+            SYS_GETPPID | SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => 0,
+            // Single threaded and no signal delivery.
+            SYS_FUTEX | SYS_SET_ROBUST_LIST | SYS_SCHED_YIELD | SYS_SIGALTSTACK
+            | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => 0,
+            _ => {
+                eprintln!("Unsupported syscall {}", nr);
+                return Err(ENOSYS);
+            }
+        };
+        Ok(result)
+    }
+
+    fn fd(&mut self, fd: u32) -> Result<&mut Fd, i32> {
+        self.fds.get_mut(fd as usize).and_then(|slot| slot.as_mut()).ok_or(EBADF)
+    }
+
+    fn insert_fd(&mut self, fd: Fd) -> u32 {
+        let slot = self.fds.iter().position(|slot| slot.is_none()).unwrap_or(self.fds.len());
+        if slot == self.fds.len() {
+            self.fds.push(None);
+        }
+        self.fds[slot] = Some(fd);
+        slot as u32
+    }
+
+    fn read(&mut self, fd: u32, data: &mut [u8]) -> Result<usize, i32> {
+        match self.fd(fd)? {
+            Fd::Stdin => io::stdin().read(data),
+            Fd::File(file) => file.read(data),
+            _ => return Err(EBADF),
+        }.map_err(errno)
+    }
+
+    fn write(&mut self, fd: u32, data: &[u8]) -> Result<usize, i32> {
+        match self.fd(fd)? {
+            Fd::Stdout => io::stdout().write(data),
+            Fd::Stderr => io::stderr().write(data),
+            Fd::File(file) => file.write(data),
+            Fd::Stdin => return Err(EBADF),
+        }.map_err(errno)
+    }
+
+    /// Paths are used as is on the host; only AT_FDCWD is supported as base.
+    fn path(&self, bus: &mut Bus, dirfd: u32, addr: Addr) -> Result<String, i32> {
+        let path = read_cstr(bus, addr)?;
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(EINVAL);
+        }
+        Ok(path)
+    }
+
+    fn random(&mut self) -> u64 {
+        // xorshift64, good enough for hash seeds and stack canaries
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+
+    /// Lays out argc, argv, envp and the auxiliary vector below `stack_top`
+    /// the way the kernel does and returns the initial stack pointer.
+    pub fn setup_stack(&mut self, bus: &mut Bus, image: &ElfImage, args: &[String], env: &[String], stack_top: Addr) -> Result<Addr, String> {
+        let fault = |_| "the stack is outside of memory".to_string();
+        let mut sp = stack_top;
+        let mut push_str = |bus: &mut Bus, s: &str| -> Result<Addr, ()> {
+            sp -= s.len() as u32 + 1;
+            bus.write_bytes(sp, s.as_bytes())?;
+            bus.write(1, sp + s.len() as u32, 0)?;
+            Ok(sp)
+        };
+        let argv: Vec<Addr> = args.iter().map(|s| push_str(bus, s)).collect::<Result<_, _>>().map_err(fault)?;
+        let envp: Vec<Addr> = env.iter().map(|s| push_str(bus, s)).collect::<Result<_, _>>().map_err(fault)?;
+        let random: Vec<u8> = (0..16).map(|_| self.random() as u8).collect();
+        let random_addr = (sp - 16) & !0xF;
+        bus.write_bytes(random_addr, &random).map_err(fault)?;
+
+        let auxv = [
+            (AT_PHDR, image.phdr),
+            (AT_PHENT, image.phent),
+            (AT_PHNUM, image.phnum),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, image.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, HWCAP_I),
+            (AT_CLKTCK, 100),
+            (AT_RANDOM, random_addr),
+            (AT_NULL, 0),
+        ];
+        let mut words = vec![args.len() as u32];
+        words.extend(&argv);
+        words.push(0);
+        words.extend(&envp);
+        words.push(0);
+        for (key, value) in auxv {
+            words.extend([key, value]);
+        }
+        let sp = (random_addr - 4 * words.len() as u32) & !0xF;
+        for (i, &word) in words.iter().enumerate() {
+            bus.write(4, sp + 4 * i as u32, word).map_err(fault)?;
+        }
+        Ok(sp)
+    }
+}
+
+/// `struct statx` as filled in by the kernel, with the fields libc looks at.
+fn statx(metadata: Option<&fs::Metadata>) -> [u8; 256] {
+    let mut buf = [0u8; 256];
+    let (mode, size, mtime) = match metadata {
+        Some(m) => {
+            let mode = if m.is_dir() { S_IFDIR | 0o755 } else { S_IFREG | 0o644 };
+            let mtime = m.modified().ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |t| t.as_secs());
+            (mode, m.len(), mtime)
+        }
+        None => (S_IFCHR | 0o620, 0, 0),
+    };
+    buf[0..4].copy_from_slice(&0x7FFu32.to_le_bytes());   // stx_mask: basic stats
+    buf[4..8].copy_from_slice(&PAGE_SIZE.to_le_bytes());  // stx_blksize
+    buf[16..20].copy_from_slice(&1u32.to_le_bytes());     // stx_nlink
+    buf[28..30].copy_from_slice(&(mode as u16).to_le_bytes());
+    buf[40..48].copy_from_slice(&size.to_le_bytes());
+    buf[48..56].copy_from_slice(&size.div_ceil(512).to_le_bytes());
+    for time in [64, 96, 112] {                            // atime, ctime, mtime
+        buf[time..time + 8].copy_from_slice(&mtime.to_le_bytes());
+    }
+    buf
+}
+
+fn read_word(bus: &mut Bus, addr: Addr) -> Result<u32, i32> {
+    bus.read(4, addr).map_err(|_| EFAULT)
+}
+
+/// How much of a `len` byte guest buffer at `addr` lies in guest memory.
+/// Lengths come from the program, so they are clamped before sizing host
+/// buffers. A buffer starting past the end of memory is EFAULT.
+fn guest_len(addr: Addr, len: u32) -> Result<usize, i32> {
+    if len > 0 && addr >= USER_MEM_SIZE {
+        return Err(EFAULT);
+    }
+    Ok(len.min(USER_MEM_SIZE.saturating_sub(addr)) as usize)
+}
+
+fn read_mem(bus: &mut Bus, addr: Addr, len: u32) -> Result<Vec<u8>, i32> {
+    let mut data = vec![0u8; guest_len(addr, len)?];
+    bus.read_bytes(addr, &mut data).map_err(|_| EFAULT)?;
+    Ok(data)
+}
+
+fn write_mem(bus: &mut Bus, addr: Addr, data: &[u8]) -> Result<(), i32> {
+    bus.write_bytes(addr, data).map_err(|_| EFAULT)
+}
+
+fn read_cstr(bus: &mut Bus, addr: Addr) -> Result<String, i32> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = bus.read(1, addr.wrapping_add(bytes.len() as u32)).map_err(|_| EFAULT)? as u8;
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+    }
+    String::from_utf8(bytes).map_err(|_| ENOENT)
+}
+
+fn errno(error: io::Error) -> i32 {
+    error.raw_os_error().unwrap_or(EINVAL)
+}
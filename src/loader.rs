@@ -0,0 +1,133 @@
+use std::error::Error;
+use std::fs;
+
+use crate::asm;
+use crate::bus::Addr;
+use crate::cpu::Cpu;
+use crate::dtb;
+use crate::elf::{ElfImage, Symbols};
+use crate::platform::Platform;
+
+pub fn parse_hex_file(file_path: &str) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(file_path)?;
+    let tokens = contents.split_whitespace();
+    let nums: Vec<u32> = tokens
+        .map(|s| {
+            let s = s.trim();
+            let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+            let s_no_underscores: String = s.chars().filter(|&c| c != '_').collect();
+            u32::from_str_radix(&s_no_underscores, 16).unwrap()
+        })
+        .collect();
+    Ok(nums)
+}
+
+enum Program {
+    Hex(Vec<u32>),
+    Elf(ElfImage),
+    /// Copied into RAM by the boot chain.
+    None,
+}
+
+/// Everything the loader puts in memory and registers at power-on, kept so
+/// that a reset can load the machine again without touching the disk.
+pub struct BootImage {
+    program: Program,
+    ram_base: Addr,
+    dtb: Vec<u8>,
+    dtb_addr: Addr,
+    /// Screen CSR and the framebuffer address programmed into it.
+    framebuffer: Option<(Addr, Addr)>,
+    entry: Addr,
+    app_image: Option<(Addr, u32)>,
+    /// From the ELF symbol table or the assembly labels.
+    symbols: Symbols,
+}
+
+impl BootImage {
+    /// Reads the program for `platform`. ELF files are loaded by segment,
+    /// assembly sources are assembled for the start of RAM, and anything
+    /// else is parsed as hex words placed there.
+    pub fn new(path: &str, platform: &Platform, boot_from_rom: bool) -> Result<Self, Box<dyn Error>> {
+        let file = fs::read(path)?;
+        let mut symbols = Symbols::default();
+        let program = if boot_from_rom {
+            Program::None
+        } else if file.starts_with(b"\x7fELF") {
+            let mut image = ElfImage::parse(&file)?;
+            symbols = std::mem::take(&mut image.symbols);
+            Program::Elf(image)
+        } else if asm::is_source(path) {
+            let assembly = asm::assemble_file(path, platform.ram.base)?;
+            symbols = assembly.symbols;
+            Program::Hex(assembly.words)
+        } else {
+            Program::Hex(parse_hex_file(path)?)
+        };
+        let (dtb, layout) = dtb::build(platform);
+        let entry = match &program {
+            Program::Elf(image) => image.entry,
+            _ => platform.reset_vector,
+        };
+        Ok(Self {
+            program,
+            ram_base: platform.ram.base,
+            dtb,
+            dtb_addr: layout.dtb_addr,
+            framebuffer: platform.screen_csr_base().zip(layout.framebuffer),
+            entry,
+            app_image: platform.app_image(),
+            symbols,
+        })
+    }
+
+    pub fn dtb(&self) -> &[u8] {
+        &self.dtb
+    }
+
+    /// True when the program runs into the device tree placed after it.
+    pub fn overlaps_dtb(&self) -> bool {
+        let end = match &self.program {
+            Program::Hex(words) => self.ram_base as u64 + (words.len() * 4) as u64,
+            Program::Elf(image) => image.end() as u64,
+            Program::None => 0,
+        };
+        end > self.dtb_addr as u64
+    }
+
+    pub fn dtb_addr(&self) -> Addr {
+        self.dtb_addr
+    }
+
+    pub fn take_symbols(&mut self) -> Symbols {
+        std::mem::take(&mut self.symbols)
+    }
+
+    /// Resets the CPU and devices, then loads the program and device tree
+    /// and sets up the boot registers the way OpenSBI/Linux expect them.
+    pub fn load(&self, cpu: &mut Cpu) -> Result<(), String> {
+        cpu.reset(self.entry);
+        let bus = cpu.bus_mut();
+        match &self.program {
+            Program::Hex(words) => {
+                for (i, &value) in words.iter().enumerate() {
+                    bus.write(4, self.ram_base + (i * 4) as u32, value)
+                        .map_err(|_| "the program does not fit in RAM".to_string())?;
+                }
+            }
+            Program::Elf(image) => image.load(bus)?,
+            Program::None => {}
+        }
+        bus.write_bytes(self.dtb_addr, &self.dtb).map_err(|_| "failed to place the device tree".to_string())?;
+        if let Some((csr, fb)) = self.framebuffer {
+            bus.write(4, csr + 4, fb).map_err(|_| "failed to program the framebuffer address".to_string())?;
+        }
+        cpu.write_reg(10, 0);             // a0 = hartid
+        cpu.write_reg(11, self.dtb_addr); // a1 = device tree
+        if let Some((base, size)) = self.app_image {
+            cpu.write_reg(12, base);      // a2 = application image
+            cpu.write_reg(13, size);      // a3 = application image size
+        }
+        Ok(())
+    }
+}
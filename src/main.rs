@@ -1,211 +1,656 @@
 use std::{fs};
+use std::error::Error;
+use std::io::{BufWriter, Write};
 
-mod bus;
-mod peripherals;
-mod cpu;
-mod constants;
-mod types;
-
-use peripherals::{Ram, UartNs16550a, TextMode};
-use peripherals::ScreenHandle;
-use bus::Bus;
-use cpu::Cpu;
-use constants::*;
-use types::{Ctrl, CtrlMessage, ScreenMsg, ScreenType};
-use crate::{bus::Device, peripherals::{ScreenCsr}};
-use std::time::Instant;
-use std::io;
-use clap::{ArgAction, Parser};
+use risv_emulator::{asm, commitlog, disasm, trace, tracediff};
+use risv_emulator::peripherals::ScreenHandle;
+use risv_emulator::cpu::Cpu;
+use risv_emulator::constants::*;
+use risv_emulator::types::{Ctrl, CtrlMessage, DebugState, ScreenMsg, ScreenType};
+use risv_emulator::platform::{parse_u32, BootChain, Platform};
+use risv_emulator::loader::BootImage;
+use risv_emulator::semihosting::Semihosting;
+use risv_emulator::elf::{ElfImage, Symbols};
+use risv_emulator::linux_user::LinuxUser;
+use risv_emulator::snapshot::Snapshot;
+use risv_emulator::debugger::{Debugger, Poll};
+use risv_emulator::pacer::Pacer;
+use risv_emulator::trace::{TraceFilter, Tracer};
+use risv_emulator::eventlog::{LogHeader, LogReader, LogWriter};
+use risv_emulator::spikelog::{SpikeWriter, PRIV_MACHINE, PRIV_USER};
+use risv_emulator::vcd::{VcdWriter, Watch};
+use risv_emulator::types::Event;
+use risv_emulator::bus::Bus;
+use risv_emulator::peripherals::Ram;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 // thread stuff
 use std::sync::mpsc;
 use std::thread;
 use eframe::egui::{self};
-use std::fs::File;
-use std::io::{BufWriter};
 
 mod gui_app;
+mod gui_debug;
 use gui_app::GUIApp;
 
 #[derive(Parser, Debug)]
 #[command(name = "RISC-V Emulator", version, about = "A simple RISC-V emulator in Rust", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    program: String,
-    /// Verbose (-v, -vv, -vvv)
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Hex file, assembly source (.s) or ELF executable when not booting
+    /// from --bootrom.
+    #[arg(required = true)]
+    program: Option<String>,
+    /// Arguments passed to the program in --user mode.
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    program_args: Vec<String>,
+    /// Print an execution trace (cycle, PC, encoding, disassembly and the
+    /// register or memory effect of every instruction) and the registers
+    /// at the end.
     #[arg(short, long, action = ArgAction::Count)]
     verbose: u8,
+    /// Only trace instructions with a PC in START..END (either bound optional).
+    #[arg(long, value_name = "START..END", value_parser = trace::parse_range)]
+    trace_pc: Option<Range<u64>>,
+    /// Only trace instructions executed on cycles START..END.
+    #[arg(long, value_name = "START..END", value_parser = trace::parse_range)]
+    trace_cycles: Option<Range<u64>>,
 
     #[arg(short, long, default_value_t = 0)]
     limit : u64,
     /// Log instructions to a file
-    /// Log instructions to a file
     #[arg(long, default_value = None)]
     log: Option<String>,
+    /// Format of the --log file.
+    #[arg(long, value_enum, default_value_t = LogFormat::Binary, requires = "log")]
+    log_format: LogFormat,
+    /// Dump the PC, retired instruction and registers as a VCD waveform,
+    /// one timestep per retired instruction.
+    #[arg(long, default_value = None)]
+    vcd: Option<String>,
+    /// Add a memory word or device register to the --vcd dump, as an
+    /// address or DEVICE+OFFSET, optionally followed by =NAME. Can be
+    /// repeated.
+    #[arg(long, value_name = "ADDR[=NAME]", requires = "vcd")]
+    vcd_mem: Vec<String>,
+    /// Board description (TOML) listing RAM, reset vector and devices.
+    /// Defaults to the RISC-Y memory map.
+    #[arg(long, default_value = None)]
+    platform: Option<String>,
+    /// Write the generated device tree blob to a .dtb file.
+    #[arg(long, default_value = None)]
+    dump_dtb: Option<String>,
+    /// Boot from a ROM image mapped at 0x0000_0000 instead of preloading
+    /// the program in RAM. The program is then mapped read-only at
+    /// 0x0100_0000 for the bootloader to copy into RAM.
+    #[arg(long, default_value = None)]
+    bootrom: Option<String>,
+    /// Second stage bootloader image mapped at 0x0080_0000.
+    #[arg(long, default_value = None, requires = "bootrom")]
+    stage2: Option<String>,
+    /// Map a device plugin (shared library), e.g. `libaccel.so@0x9000_0000`
+    /// or `libaccel.so@0x9000_0000,irq=5`. Can be repeated.
+    #[arg(long = "device", value_name = "LIB@BASE")]
+    devices: Vec<String>,
+    /// Write a snapshot of the whole machine to this file, at the cycle
+    /// given with --at or when the run ends.
+    #[arg(long, default_value = None)]
+    save_snapshot: Option<String>,
+    /// Cycle at which --save-snapshot is taken.
+    #[arg(long, default_value = None, requires = "save_snapshot")]
+    at: Option<u64>,
+    /// Resume from a snapshot taken with the same platform options.
+    #[arg(long, default_value = None)]
+    restore: Option<String>,
+    /// Service semihosting calls (ebreak wrapped in slli/srai) on the host.
+    #[arg(long)]
+    semihosting: bool,
+    /// Directory the program's semihosting file operations are confined to.
+    #[arg(long, default_value = ".", requires = "semihosting")]
+    semihosting_root: String,
+    /// Arguments returned to the program by SYS_GET_CMDLINE, after its name.
+    #[arg(long, default_value = None, requires = "semihosting")]
+    semihosting_cmdline: Option<String>,
+    /// Run a statically linked RV32 Linux ELF, translating its syscalls to
+    /// the host (like qemu-user). No devices or GUI.
+    #[arg(long, conflicts_with_all = ["platform", "bootrom", "devices"])]
+    user: bool,
+    /// Cycles between the in-memory checkpoints used by `bs`/`bc` in
+    /// interactive mode.
+    #[arg(long, default_value_t = 1_000_000)]
+    checkpoint_interval: u64,
+    /// Run at this core frequency in wall-clock time instead of as fast as
+    /// possible. Also sets the timebase reported to the program.
+    #[arg(long, default_value = None)]
+    clock_mhz: Option<f64>,
+}
+
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print an objdump style listing of a hex, assembly or ELF program.
+    Disasm {
+        program: String,
+        /// Address hex programs are listed from.
+        #[arg(long, default_value = "0x8000_0000", value_parser = parse_u32)]
+        base: u32,
+    },
+    /// Assemble an RV32I source file into a hex file the emulator loads.
+    Asm {
+        input: String,
+        /// Output hex file, the input with a .hex extension by default.
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Address the program is assembled for.
+        #[arg(long, default_value = "0x8000_0000", value_parser = parse_u32)]
+        base: u32,
+    },
+    /// Print the header and events of a --log file as text.
+    LogDump {
+        log: String,
+    },
+    /// Convert a --log file to the testbench's JSON commit log format, or
+    /// a JSON commit log to a --log file.
+    ConvertLog {
+        input: String,
+        output: String,
+    },
+    /// Compare two logs (--log files or JSON commit logs) instruction by
+    /// instruction and report where they diverge.
+    TraceDiff {
+        /// Log written by the emulator.
+        emu: String,
+        /// Log to check against it, e.g. from the RTL testbench.
+        rtl: String,
+        /// Stop after this many divergences.
+        #[arg(short = 'n', long, default_value_t = 10)]
+        max: usize,
+        /// Instructions shown before each divergence.
+        #[arg(short = 'C', long, default_value_t = 5)]
+        context: usize,
+        /// Known RTL trace quirks not to report, comma separated.
+        #[arg(long, value_enum, value_delimiter = ',')]
+        ignore: Vec<tracediff::Ignore>,
+        /// Don't compare the effects of instructions at these PCs.
+        #[arg(long, value_name = "START..END", value_parser = trace::parse_range)]
+        ignore_pc: Vec<Range<u64>>,
+    },
+}
+
+/// `asm` subcommand: one 32 bit word per line, like the other hex programs.
+fn assemble_to_hex(input: &str, output: Option<&str>, base: u32) -> Result<(), Box<dyn Error>> {
+    let assembly = asm::assemble_file(input, base)?;
+    let output = output.map_or_else(|| std::path::Path::new(input).with_extension("hex"), std::path::PathBuf::from);
+    let hex: String = assembly.words.iter().map(|word| format!("{:08x}\n", word)).collect();
+    fs::write(&output, hex)?;
+    println!("Wrote {} words to {}", assembly.words.len(), output.display());
+    Ok(())
+}
+
+impl Args {
+    fn program(&self) -> &str {
+        self.program.as_deref().unwrap_or_default()
+    }
+
+    /// The `-v` tracer, when enabled.
+    fn tracer(&self, symbols: &Symbols) -> Option<Tracer> {
+        let filter = TraceFilter { pc: self.trace_pc.clone(), cycles: self.trace_cycles.clone() };
+        (self.verbose > 0).then(|| Tracer::spawn(filter, symbols.clone()))
+    }
+
+    /// The --log file, when enabled.
+    fn event_log(&self, isa: &str, pc: u32, cycles: u64, privilege: u8) -> Option<EventLog> {
+        let path = self.log.as_ref()?;
+        let log = match self.log_format {
+            LogFormat::Binary => LogWriter::create(path, &LogHeader::new(isa, pc, cycles)).map(EventLog::Binary),
+            LogFormat::Spike => SpikeWriter::create(path, privilege).map(EventLog::Spike),
+        };
+        Some(log.expect("Unable to create log file"))
+    }
+
+    /// The --vcd dump, when enabled.
+    fn vcd(&self, cpu: &Cpu, platform: Option<&Platform>) -> Option<VcdWriter<BufWriter<fs::File>>> {
+        let path = self.vcd.as_ref()?;
+        let watches: Vec<Watch> = self.vcd_mem.iter()
+            .map(|spec| Watch::parse(spec, platform))
+            .collect::<Result<_, _>>()
+            .expect("Invalid --vcd-mem");
+        Some(VcdWriter::create(path, cpu, &watches).expect("Unable to create VCD file"))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+enum LogFormat {
+    /// Event log, read by log-dump and trace-diff.
+    Binary,
+    /// The text Spike writes with --log-commits.
+    Spike,
+}
+
+enum EventLog {
+    Binary(LogWriter<BufWriter<fs::File>>),
+    Spike(SpikeWriter<BufWriter<fs::File>>),
+}
+
+impl EventLog {
+    fn write(&mut self, events: &[Event]) -> std::io::Result<()> {
+        match self {
+            EventLog::Binary(writer) => writer.write(events),
+            EventLog::Spike(writer) => writer.write(events),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            EventLog::Binary(writer) => writer.flush(),
+            EventLog::Spike(writer) => writer.flush(),
+        }
+    }
+}
+
+/// `log-dump` subcommand.
+fn dump_log(path: &str) -> Result<(), Box<dyn Error>> {
+    let mut reader = LogReader::open(path)?;
+    let header = reader.header();
+    println!("Event log v{}, {}, start pc 0x{:08x}, cycle {}, written by {}",
+        header.version, header.isa, header.start_pc, header.start_cycle, header.tool);
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    for event in &mut reader {
+        let event = event?;
+        write!(out, "{:>10} ", event.cycle)?;
+        event.serialize_human_readable(&mut out)?;
+    }
+    Ok(())
+}
+
+/// Runs a subcommand instead of the emulator. Returns the exit code.
+fn run_command(command: &Command) -> i32 {
+    let result = match command {
+        Command::Disasm { program, base } => disasm::print_listing(program, *base).map(|()| 0),
+        Command::Asm { input, output, base } => assemble_to_hex(input, output.as_deref(), *base).map(|()| 0),
+        Command::LogDump { log } => dump_log(log).map(|()| 0),
+        Command::ConvertLog { input, output } => commitlog::convert(input, output).map(|()| 0),
+        Command::TraceDiff { emu, rtl, max, context, ignore, ignore_pc } => {
+            let options = tracediff::DiffOptions { max: *max, context: *context, ignore: ignore.clone(), ignore_pc: ignore_pc.clone() };
+            tracediff::run(emu, rtl, &options).map(|divergences| i32::from(divergences > 0))
+        }
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn save_snapshot(cpu: &Cpu, path: &str) {
+    match cpu.snapshot().save(path) {
+        Ok(()) => println!("Saved snapshot at cycle {} to {}", cpu.cycles(), path),
+        Err(e) => println!("Failed to save snapshot to {}: {}", path, e),
+    }
 }
-fn parse_hex_file(file_path: &str) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
-    let contents = fs::read_to_string(file_path)?;
-    let tokens = contents.split_whitespace();
-    let nums: Vec<u32> = tokens
-        .map(|s| {
-            let s = s.trim();
-            let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
-            let s_no_underscores: String = s.chars().filter(|&c| c != '_').collect();
-            u32::from_str_radix(&s_no_underscores, 16).unwrap()
-        })
-        .collect();
-    Ok(nums)
+
+/// The emulated machine and the image needed to boot it again.
+struct Machine {
+    cpu: Cpu,
+    boot: BootImage,
+    platform: Platform,
+    textmode_frame: ScreenHandle,
 }
 
+/// Builds the machine described by the command line and loads the program.
+/// Used at start-up and again by `Ctrl::Reload` to pick up changed files.
+fn build_machine(args: &Args, uart_tx: mpsc::Sender<char>) -> Result<Machine, Box<dyn Error>> {
+    let mut platform = match &args.platform {
+        Some(path) => Platform::from_file(path)?,
+        None => Platform::risc_y(),
+    };
+    if let Some(bootrom) = &args.bootrom {
+        let chain = BootChain { bootrom, stage2: args.stage2.as_deref(), app: args.program() };
+        platform.add_boot_chain(&chain)?;
+    }
+    for spec in &args.devices {
+        platform.add_plugin(spec)?;
+    }
+    // Checked here so a typo fails before the CPU thread starts.
+    for spec in &args.vcd_mem {
+        Watch::parse(spec, Some(&platform))?;
+    }
+    if let Some(mhz) = args.clock_mhz {
+        if !(mhz > 0.0 && mhz * 1e6 <= u32::MAX as f64) {
+            return Err(format!("--clock-mhz must be between 0 and {}", u32::MAX as f64 / 1e6).into());
+        }
+        platform.clock_hz = (mhz * 1e6) as u32;
+    }
+    // When booting from ROM the bootloader is the one copying the program into RAM.
+    let boot = BootImage::new(args.program(), &platform, args.bootrom.is_some())?;
+    let board = platform.build(uart_tx)?;
+    let mut cpu = Cpu::new(board.bus, platform.reset_vector);
+    boot.load(&mut cpu)?;
+    if args.semihosting {
+        let cmdline = match &args.semihosting_cmdline {
+            Some(extra) => format!("{} {}", args.program(), extra),
+            None => args.program().to_string(),
+        };
+        cpu.enable_semihosting(Semihosting::new(&args.semihosting_root, cmdline));
+    }
+    Ok(Machine { cpu, boot, platform, textmode_frame: board.textmode_frame })
+}
+
+/// GUI side of `cpu_thread`: answers frame and debugger state requests.
+struct Frontend {
+    screen_tx: mpsc::Sender<ScreenMsg>,
+    state_tx: mpsc::Sender<DebugState>,
+    /// For the UART of a machine rebuilt by `Ctrl::Reload`.
+    uart_tx: mpsc::Sender<char>,
+}
+impl Frontend {
+    fn send_frame(&self, machine: &mut Machine) {
+            let screen_csr = machine.platform.screen_csr_base();
+            let cpu = &mut machine.cpu;
+            let frame_buff_enabled = screen_csr.is_some_and(|csr| cpu.read_mem(4, csr) & 1 == 1);
+            let frame_buff_addr = screen_csr.map_or(0, |csr| cpu.read_mem(4, csr + 4));
+            // println!("Received frame request enabled:{} addr:0x{:08X}", frame_buff_enabled, frame_buff_addr);
+            if !frame_buff_enabled{
+                //fucking kill me.
+                if let Ok(buf) = machine.textmode_frame.read() {
+                    self.screen_tx.send(ScreenMsg { screen_type: ScreenType::TextMode, data: buf.clone() }).ok();
+                }
+            }
+            else{
+                // println!("Frame Buffer Addr: 0x{:08X}", frame_buff_addr);
+                let frame_size = SCREEN_WIDTH * SCREEN_HEIGHT * 2;
+                let mut frame_buff = vec![255; frame_size];
+                for i in 0..(frame_size/4) {
+                    let data = cpu.read_mem(4, frame_buff_addr + (i*4) as u32);
+                    let bytes = data.to_le_bytes();
+                    frame_buff[i*4    ] = bytes[0];
+                    frame_buff[i*4 + 1] = bytes[1];
+                    frame_buff[i*4 + 2] = bytes[2];
+                    frame_buff[i*4 + 3] = bytes[3];
+                }
+                let _ = self.screen_tx.send(ScreenMsg { screen_type: ScreenType::FrameBuffer, data: frame_buff } );
+            }
+    }
 
+    /// Serves a message from the GUI. Returns false when asked to stop.
+    fn handle(&self, machine: &mut Machine, debugger: &mut Debugger, pacer: &mut Pacer, args: &Args, msg: CtrlMessage) -> bool {
+        let cpu = &mut machine.cpu;
+        match msg.command {
+            Ctrl::RequestFrame => self.send_frame(machine),
+            Ctrl::RequestState { memory_addr, memory_len } => {
+                let _ = self.state_tx.send(debugger.state(cpu, memory_addr, memory_len));
+            }
+            Ctrl::Pause => debugger.pause(cpu),
+            Ctrl::Resume => debugger.resume(cpu),
+            Ctrl::Step(count) => debugger.step(cpu, count),
+            Ctrl::Reset => match machine.boot.load(cpu) {
+                Ok(()) => {
+                    debugger.machine_changed(cpu);
+                    println!("Machine reset");
+                }
+                Err(e) => println!("Failed to reset the machine: {}", e),
+            },
+            Ctrl::Reload => match build_machine(args, self.uart_tx.clone()) {
+                Ok(mut reloaded) => {
+                    debugger.set_program(reloaded.boot.take_symbols(), reloaded.platform.memory_map());
+                    debugger.machine_changed(&mut reloaded.cpu);
+                    *pacer = Pacer::new(reloaded.platform.clock_hz, pacer.speed());
+                    *machine = reloaded;
+                    println!("Reloaded {}", args.program());
+                }
+                Err(e) => println!("Failed to reload {}: {}", args.program(), e),
+            },
+            Ctrl::AddBreakpoint(location) => {
+                if let Err(e) = debugger.add_breakpoint(&location) {
+                    println!("{}", e);
+                }
+            }
+            Ctrl::RemoveBreakpoint(addr) => debugger.remove_breakpoint(addr),
+            Ctrl::WriteMemory { addr, value } => {
+                if cpu.poke_mem(1, addr, value as u32).is_none() {
+                    println!("Cannot write memory at 0x{:08X}", addr);
+                }
+            }
+            Ctrl::SetSpeed(speed) => pacer.set_speed(speed, cpu.cycles()),
+            Ctrl::SaveSnapshot => save_snapshot(cpu, &format!("snapshot-{}.snap", cpu.cycles())),
+            Ctrl::Stop => return false,
+        }
+        true
+    }
+}
 
-fn cpu_thread(cpu: &mut Cpu, args: &Args, textmode_frame: ScreenHandle, rx: &mpsc::Receiver<CtrlMessage>, screen_tx: mpsc::Sender<ScreenMsg>) {
+fn cpu_thread(machine: &mut Machine, args: &Args, frontend: Frontend, rx: &mpsc::Receiver<CtrlMessage>) {
     // std::thread::sleep(std::time::Duration::from_secs(2));
-    let start = Instant::now();
-    let verbose = args.verbose > 0;
+    let mut start = Instant::now();
     // Start the CPU
-    let limit = if args.limit == 0 { u64::MAX } else { args.limit };
-    if args.limit == 0 {
-        println!("Running in interactive mode. Press Enter to step, 'q/b' to quit.");
+    let interactive = args.limit == 0;
+    let limit = if interactive { u64::MAX } else { args.limit };
+    if interactive {
+        println!("Running in interactive mode. Type 'help' for the debugger commands, Enter steps.");
     } else {
         println!("Running for {} cycles.", limit);
     }
     println!("------------\n");
-    let logging_enabled = args.log.is_some();
-    let mut writer = None;
-    if logging_enabled {
-        let file = File::create(args.log.as_ref().unwrap()).expect("Unable to create log file");
-        writer = Some(BufWriter::with_capacity(64 * 1024, file));
-        // write!(writer.as_mut().unwrap(), "Emulation Trace\n").expect("Failed to write header");
-    }
-    let _batch = if verbose { 1 } else { 1000 };
-    for _ in 0..(limit/_batch) {
-        let mut input = String::new();
-        if args.limit == 0 {
-            io::stdin()
-                .read_line(&mut input) // reads until Enter is pressed
-                .expect("Failed to read line");
-            if input.trim() == "q" || input.trim() == "b" {
-                break;
-            }
+    let logging_enabled = args.log.is_some() || args.vcd.is_some();
+    let mut writer = args.event_log(&machine.platform.isa, machine.cpu.pc(), machine.cpu.cycles(), PRIV_MACHINE);
+    let mut vcd = args.vcd(&machine.cpu, Some(&machine.platform));
+    let _batch = 1000;
+    let mut snapshot_at = args.at;
+    let symbols = machine.boot.take_symbols();
+    let tracer = args.tracer(&symbols);
+    let mut debugger = Debugger::new(symbols, machine.platform.memory_map(), args.checkpoint_interval, logging_enabled, interactive);
+    let mut pacer = Pacer::new(machine.platform.clock_hz, args.clock_mhz.map(|_| 1.0));
+    pacer.restart(machine.cpu.cycles());
+    let mut remaining = limit;
+    // Set once the limit was reached or the program exited, until a reset.
+    let mut ended = false;
+    loop {
+        let cpu = &mut machine.cpu;
+        let over = remaining == 0 || (!interactive && cpu.exit_code().is_some());
+        if over && !ended {
+            finish_run(cpu, args, snapshot_at, start);
+            flush_logs(&mut writer, &mut vcd);
+            debugger.pause(cpu);
+            ended = true;
         }
-        let event_log = cpu.tick(verbose, _batch, logging_enabled);
-        if logging_enabled{
-
-            for event in event_log {
-                event.serialize(writer.as_mut().unwrap()).expect("Failed to write event");
+        let msg = if over {
+            // Stay paused and keep serving the GUI, which can reset or reload.
+            match rx.recv() {
+                Ok(msg) => msg,
+                Err(_) => break,
             }
-        }
-        if let Ok(msg) = rx.try_recv() {
-            match msg.command {
-                Ctrl::RequestFrame => {
-                    let frame_buff_enabled = cpu.read_mem(4, SCREEN_CSR_ENABLE) & 1 == 1;
-                    let frame_buff_addr = cpu.read_mem(4, SCREEN_CSR_ADDR + 4);
-                    // println!("Received frame request enabled:{} addr:0x{:08X}", frame_buff_enabled, frame_buff_addr);
-                    if !frame_buff_enabled{
-                        //fucking kill me.
-                        if let Ok(buf) = textmode_frame.read() {
-                            screen_tx.send(ScreenMsg { screen_type: ScreenType::TextMode, data: buf.clone() }).ok();
-                        }
-                    }
-                    else{
-                        // println!("Frame Buffer Addr: 0x{:08X}", frame_buff_addr);
-                        let frame_size = SCREEN_WIDTH * SCREEN_HEIGHT * 2;
-                        let mut frame_buff = vec![255; frame_size];
-                        for i in 0..(frame_size/4) {
-                            let data = cpu.read_mem(4, frame_buff_addr + (i*4) as u32);
-                            let bytes = data.to_le_bytes();
-                            frame_buff[i*4    ] = bytes[0];
-                            frame_buff[i*4 + 1] = bytes[1];
-                            frame_buff[i*4 + 2] = bytes[2];
-                            frame_buff[i*4 + 3] = bytes[3];
-                        }
-                        let _ = screen_tx.send(ScreenMsg { screen_type: ScreenType::FrameBuffer, data: frame_buff } );
-                    }
+        } else {
+            // Shorten the batch so the snapshot lands on the requested cycle.
+            if let Some(at) = snapshot_at && cpu.cycles() >= at {
+                save_snapshot(cpu, args.save_snapshot.as_ref().unwrap());
+                snapshot_at = None;
+            }
+            let batch = snapshot_at.map_or(_batch, |at| _batch.min(at - cpu.cycles())).min(remaining);
+            let before = cpu.cycles();
+            debugger.collect_events(logging_enabled || tracer.as_ref().is_some_and(|tracer| tracer.covers(before, batch)));
+            let mut log = |event_log: Vec<Event>| {
+                if let Some(writer) = writer.as_mut() {
+                    writer.write(&event_log).expect("Failed to write event");
                 }
-                Ctrl::Stop => break,
+                if let Some(vcd) = vcd.as_mut() {
+                    vcd.write(&event_log).expect("Failed to write VCD");
+                }
+                if let Some(tracer) = &tracer {
+                    tracer.send(event_log);
+                }
+            };
+            let poll = debugger.poll(cpu, batch, &mut log);
+            // Devices and the GUI write memory too, not just the program.
+            if let Some(vcd) = vcd.as_mut() {
+                vcd.refresh(cpu).expect("Failed to write VCD");
             }
+            let msg = match poll {
+                Poll::Quit => break,
+                Poll::Busy => {
+                    remaining -= cpu.cycles().saturating_sub(before).min(remaining);
+                    pacer.pace(cpu.cycles());
+                    rx.try_recv().ok()
+                }
+                // Keep answering frame requests while the guest is stopped.
+                Poll::Paused => {
+                    pacer.restart(cpu.cycles());
+                    rx.recv_timeout(Duration::from_millis(10)).ok()
+                }
+            };
+            let Some(msg) = msg else { continue };
+            msg
+        };
+        let restart = matches!(msg.command, Ctrl::Reset | Ctrl::Reload);
+        if !frontend.handle(machine, &mut debugger, &mut pacer, args, msg) {
+            println!("CPU thread stopping.");
+            break;
+        }
+        if restart {
+            (remaining, start) = (limit, Instant::now());
+            pacer.restart(machine.cpu.cycles());
+            if ended {
+                ended = false;
+                debugger.resume(&machine.cpu);
+            }
+        }
+    }
+    if !ended {
+        finish_run(&mut machine.cpu, args, snapshot_at, start);
+        flush_logs(&mut writer, &mut vcd);
+    }
+    if let Some(tracer) = tracer {
+        tracer.finish();
+    }
+}
+
+/// Saves the `--save-snapshot` of a run that ended and prints its summary.
+fn finish_run(cpu: &mut Cpu, args: &Args, snapshot_at: Option<u64>, start: Instant) {
+    if let Some(path) = &args.save_snapshot {
+        match snapshot_at {
+            Some(at) => println!("Cycle {} was never reached, no snapshot saved", at),
+            None if args.at.is_none() => save_snapshot(cpu, path),
+            None => {}
         }
     }
-    if verbose {
+    if args.verbose > 0 {
         for (i, &name) in REGISTER_NAMES.iter().enumerate() {
             let reg_data = cpu.read_reg(i);
             println!("x{} ({:>3}): 0x{:08X}({})", i, name, reg_data, reg_data);
         }
+        println!("Pending IRQs: 0x{:08X}", cpu.pending_irqs());
     }
     let duration = start.elapsed();
     println!("\n------------");
     println!("CPU execution time: {:?}", duration);
-    if let Ok(msg) = rx.recv() {
-        match msg.command {
-            Ctrl::RequestFrame => {
-                let frame_buff_enabled = cpu.read_mem(4, SCREEN_CSR_ENABLE) & 1 == 1;
-                let frame_buff_addr = cpu.read_mem(4, SCREEN_CSR_ADDR + 4);
-                // println!("Received frame request enabled:{} addr:0x{:08X}", frame_buff_enabled, frame_buff_addr);
-                if !frame_buff_enabled{
-                    //fucking kill me.
-                    if let Ok(buf) = textmode_frame.read() {
-                        screen_tx.send(ScreenMsg { screen_type: ScreenType::TextMode, data: buf.clone() }).ok();
-                    }
-                }
-                else{
-                    // println!("Frame Buffer Addr: 0x{:08X}", frame_buff_addr);
-                    let frame_size = SCREEN_WIDTH * SCREEN_HEIGHT * 2;
-                    let mut frame_buff = vec![255; frame_size];
-                    for i in 0..(frame_size/4) {
-                        let data = cpu.read_mem(4, frame_buff_addr + (i*4) as u32);
-                        let bytes = data.to_le_bytes();
-                        frame_buff[i*4    ] = bytes[0];
-                        frame_buff[i*4 + 1] = bytes[1];
-                        frame_buff[i*4 + 2] = bytes[2];
-                        frame_buff[i*4 + 3] = bytes[3];
-                    }
-                    let _ = screen_tx.send(ScreenMsg { screen_type: ScreenType::FrameBuffer, data: frame_buff } );
-                }
+}
+
+fn flush_logs(writer: &mut Option<EventLog>, vcd: &mut Option<VcdWriter<BufWriter<fs::File>>>) {
+    if let Some(writer) = writer.as_mut() {
+        writer.flush().expect("Failed to write event");
+    }
+    if let Some(vcd) = vcd.as_mut() {
+        vcd.flush().expect("Failed to write VCD");
+    }
+}
+
+/// `--user` mode: load the ELF into flat memory, set up the Linux initial
+/// stack and run until the program exits. Returns the exit code.
+fn run_user(args: &Args) -> i32 {
+    let image = ElfImage::from_file(args.program()).expect("Failed to load ELF file");
+    let mut bus = Bus::new();
+    bus.add_region(0, USER_MEM_SIZE, Box::new(Ram::new(USER_MEM_SIZE as usize)));
+    image.load(&mut bus).expect("Failed to load ELF file");
+
+    let stack_top = USER_MEM_SIZE;
+    let mut linux = LinuxUser::new(image.end(), stack_top - USER_STACK_SIZE);
+    let argv: Vec<String> = std::iter::once(args.program().to_string()).chain(args.program_args.iter().cloned()).collect();
+    let env: Vec<String> = std::env::vars().map(|(key, value)| format!("{}={}", key, value)).collect();
+    let sp = linux.setup_stack(&mut bus, &image, &argv, &env, stack_top).expect("Failed to set up the stack");
+
+    let mut cpu = Cpu::new(bus, image.entry);
+    cpu.write_reg(1, 0);
+    cpu.write_reg(2, sp);
+    cpu.enable_linux_user(linux);
+
+    let limit = if args.limit == 0 { u64::MAX } else { args.limit };
+    let mut writer = args.event_log("rv32i", cpu.pc(), cpu.cycles(), PRIV_USER);
+    let mut vcd = args.vcd(&cpu, None);
+    let tracer = args.tracer(&image.symbols);
+    let batch = 1000;
+    for _ in 0..limit.div_ceil(batch) {
+        let collect = writer.is_some() || vcd.is_some() || tracer.as_ref().is_some_and(|tracer| tracer.covers(cpu.cycles(), batch));
+        let event_log = cpu.run(batch, false, collect);
+        if let Some(writer) = writer.as_mut() {
+            writer.write(&event_log).expect("Failed to write event");
+        }
+        if let Some(vcd) = vcd.as_mut() {
+            vcd.write(&event_log).expect("Failed to write VCD");
+            vcd.refresh(&cpu).expect("Failed to write VCD");
+        }
+        if let Some(tracer) = &tracer {
+            tracer.send(event_log);
+        }
+        if let Some(code) = cpu.exit_code() {
+            if let Some(tracer) = tracer {
+                tracer.finish();
             }
-            Ctrl::Stop => println!("CPU thread stopping."),
+            return code;
         }
     }
+    if let Some(tracer) = tracer {
+        tracer.finish();
+    }
+    eprintln!("Instruction limit reached before the program exited");
+    1
 }
 
 fn main() -> eframe::Result {
     let args = Args::parse();
+    if let Some(command) = &args.command {
+        std::process::exit(run_command(command));
+    }
+    if args.user {
+        std::process::exit(run_user(&args));
+    }
     println!("Loading program from: {:?}", args);
 
-    let file_path = &args.program;
-    let ram_init = parse_hex_file(file_path).expect("Failed to parse hex file");
-    
     // Initiate the thread communication channels
     let (ctrl_tx, ctrl_rx) = mpsc::channel::<CtrlMessage>();
     let (screen_tx, screen_rx) = mpsc::channel::<ScreenMsg>();
+    let (state_tx, state_rx) = mpsc::channel::<DebugState>();
     let (uart_tx, uart_rx) = mpsc::channel::<char>();
-    
-    // Cpu and bus initialization.
-    let mut bus = Bus::new();
 
-    let screen_csr = ScreenCsr::new();
-
-    // SOMEHOW give it the screen csr struct
-    let mut ram = Ram::new(1024 * 4096); // 4MB RAM
-    for (i, &value) in ram_init.iter().enumerate() {
-        ram.write(4, (i * 4) as u32, value).expect("Failed to write to RAM");
+    // Cpu and bus initialization.
+    let mut machine = build_machine(&args, uart_tx.clone()).expect("Failed to set up the machine");
+    machine.platform.print_memory_map();
+    if let Some(path) = &args.dump_dtb {
+        fs::write(path, machine.boot.dtb()).expect("Failed to write the device tree blob");
+    }
+    if machine.boot.overlaps_dtb() {
+        println!("Warning: the program overlaps the device tree at 0x{:08X}", machine.boot.dtb_addr());
+    }
+    if let Some(path) = &args.restore {
+        let snapshot = Snapshot::load(path).expect("Failed to read snapshot");
+        machine.cpu.restore(&snapshot).expect("Snapshot does not match this machine");
+        println!("Restored snapshot from {} at cycle {}", path, snapshot.cycles);
     }
-    let vga_text_mode = TextMode::new();
-    let textmode_frame = vga_text_mode.handle();
-    bus.add_region(SCREEN_CSR_ADDR, 8, Box::new(screen_csr));
-    bus.add_region(UART0_BASE, 0x0000_000F, Box::new(UartNs16550a::new(uart_tx)));
-    bus.add_region(RAM_BASE, ram.size(), Box::new(ram));
-    bus.add_region(VGA_TEXT_MODE_BASE, 1216*2, Box::new(vga_text_mode));
-    let mut cpu = Cpu::new(bus, 0x8000_0000);
-    
 
+    let (clock_hz, speed) = (machine.platform.clock_hz, args.clock_mhz.map(|_| 1.0));
     let thread_handle = thread::spawn(move || {
-        cpu_thread(&mut cpu, &args, textmode_frame, &ctrl_rx, screen_tx);
+        let frontend = Frontend { screen_tx, state_tx, uart_tx };
+        cpu_thread(&mut machine, &args, frontend, &ctrl_rx);
     });
     
     // can use try receive to not block
     // let received = rx.recv().unwrap();
     // println!("Got: {received}");
     let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default().with_inner_size([800.0, 800.0]).with_min_inner_size([800.0, 480.0]),
+        viewport: egui::ViewportBuilder::default().with_inner_size([1200.0, 800.0]).with_min_inner_size([800.0, 480.0]),
         ..Default::default()
     };
     eframe::run_native(
@@ -214,8 +659,10 @@ fn main() -> eframe::Result {
         Box::new(move |_cc| {
             let mut app = GUIApp::default();
             app.screen_rx = Some(screen_rx);
+            app.state_rx = Some(state_rx);
             app.ctrl_tx = Some(ctrl_tx.clone());
             app.uart_rx = Some(uart_rx);
+            app.debug.set_speed(clock_hz, speed);
             Ok(Box::new(app))
         }),
     )?;
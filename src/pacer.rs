@@ -0,0 +1,67 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Further behind than this, the pacer stops trying to catch up and
+/// reports it instead of running a burst at full speed.
+const MAX_LAG: Duration = Duration::from_millis(100);
+/// Shorter sleeps are skipped, the OS does not honour them anyway.
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps `cpu_thread` at `speed` times the core clock in wall-clock time,
+/// counting one cycle per instruction like the rest of the emulator.
+pub struct Pacer {
+    clock_hz: u32,
+    /// Multiple of `clock_hz`, None runs as fast as the host allows.
+    speed: Option<f64>,
+    /// Wall time and cycle count the schedule is measured from.
+    anchor: (Instant, u64),
+    last_report: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(clock_hz: u32, speed: Option<f64>) -> Self {
+        Self { clock_hz, speed, anchor: (Instant::now(), 0), last_report: None }
+    }
+
+    pub fn speed(&self) -> Option<f64> {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Option<f64>, cycles: u64) {
+        self.speed = speed;
+        self.restart(cycles);
+    }
+
+    /// Starts a new schedule at `cycles`. Call while the guest is paused so
+    /// it does not race to make up for the time it spent stopped.
+    pub fn restart(&mut self, cycles: u64) {
+        self.anchor = (Instant::now(), cycles);
+    }
+
+    /// Call after every batch: sleeps while the guest is ahead of the wall
+    /// clock.
+    pub fn pace(&mut self, cycles: u64) {
+        let Some(speed) = self.speed else { return };
+        let (start, start_cycles) = self.anchor;
+        // Reset or restore moved the cycle count back.
+        if cycles < start_cycles {
+            self.restart(cycles);
+            return;
+        }
+        let rate = self.clock_hz as f64 * speed;
+        let elapsed = cycles - start_cycles;
+        let due = start + Duration::from_secs_f64(elapsed as f64 / rate);
+        let now = Instant::now();
+        if due > now + MIN_SLEEP {
+            thread::sleep(due - now);
+        } else if now > due + MAX_LAG {
+            if self.last_report.is_none_or(|last| now - last >= REPORT_INTERVAL) {
+                let achieved = elapsed as f64 / (now - start).as_secs_f64();
+                println!("Falling behind: running at {:.2} MHz, target {:.2} MHz", achieved / 1e6, rate / 1e6);
+                self.last_report = Some(now);
+            }
+            self.restart(cycles);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::bus::{Addr, Device, StateReader, StateWriter};
+
+// SiFive CLINT register map for a single hart.
+const MSIP: Addr = 0x0000;
+const MTIMECMP: Addr = 0x4000;      // 64 bits, low word first
+const MTIMECMP_HIGH: Addr = MTIMECMP + 4;
+const MTIME: Addr = 0xBFF8;         // 64 bits, low word first
+const MTIME_HIGH: Addr = MTIME + 4;
+
+/// Core local interruptor: the software interrupt bit and the machine
+/// timer. `mtime` counts CPU cycles, the device tree timebase is the core
+/// clock, and advances when the bus ticks devices. Like the PLIC it doesn't
+/// signal the hart, software compares `mtime` with `mtimecmp` itself.
+pub struct Clint {
+    msip: bool,
+    mtimecmp: u64,
+    mtime: u64,
+}
+impl Clint {
+    pub fn new() -> Self {
+        // mtimecmp resets to all ones on most cores, so no timer is due.
+        Self { msip: false, mtimecmp: u64::MAX, mtime: 0 }
+    }
+}
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads or replaces the word of `value` at byte `offset` (0 or 4).
+fn word(value: u64, offset: Addr) -> u32 {
+    (value >> (8 * offset)) as u32
+}
+fn set_word(value: &mut u64, offset: Addr, word: u32) {
+    let shift = 8 * offset;
+    *value = (*value & !(0xFFFF_FFFF << shift)) | ((word as u64) << shift);
+}
+
+impl Device for Clint {
+    fn read(&mut self, _size: u8, addr: Addr) -> u32 {
+        match addr & !0x3 {
+            MSIP => self.msip as u32,
+            offset @ (MTIMECMP | MTIMECMP_HIGH) => word(self.mtimecmp, offset - MTIMECMP),
+            offset @ (MTIME | MTIME_HIGH) => word(self.mtime, offset - MTIME),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _size: u8, addr: Addr, value: u32) -> Result<(), ()> {
+        match addr & !0x3 {
+            MSIP => self.msip = value & 1 != 0,
+            offset @ (MTIMECMP | MTIMECMP_HIGH) => set_word(&mut self.mtimecmp, offset - MTIMECMP, value),
+            offset @ (MTIME | MTIME_HIGH) => set_word(&mut self.mtime, offset - MTIME, value),
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.mtime = self.mtime.wrapping_add(cycles);
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bool(self.msip);
+        state.u64(self.mtimecmp);
+        state.u64(self.mtime);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), ()> {
+        let mut state = StateReader::new(state);
+        self.msip = state.bool()?;
+        self.mtimecmp = state.u64()?;
+        self.mtime = state.u64()?;
+        state.finish()
+    }
+}
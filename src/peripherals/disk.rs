@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::bus::{StateReader, StateWriter};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// Host disk image seen as an array of 512 byte blocks. Unless writes
+/// persist, they are kept in an in-memory overlay and lost on exit.
+pub struct DiskImage {
+    file: File,
+    pub blocks: u64,
+    persist: bool,
+    overlay: HashMap<u64, Vec<u8>>,
+}
+impl DiskImage {
+    pub fn open(image_path: &str, persist: bool) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(persist).open(image_path)?;
+        let blocks = file.metadata()?.len().div_ceil(BLOCK_SIZE as u64);
+        Ok(Self { file, blocks, persist, overlay: HashMap::new() })
+    }
+
+    pub fn read_block(&mut self, lba: u64) -> std::io::Result<Vec<u8>> {
+        if let Some(block) = self.overlay.get(&lba) {
+            return Ok(block.clone());
+        }
+        let mut block = vec![0u8; BLOCK_SIZE];
+        self.file.seek(SeekFrom::Start(lba * BLOCK_SIZE as u64))?;
+        // The last block of an unaligned image is zero padded.
+        let mut filled = 0;
+        while filled < BLOCK_SIZE {
+            match self.file.read(&mut block[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        Ok(block)
+    }
+
+    pub fn write_block(&mut self, lba: u64, data: &[u8]) -> std::io::Result<()> {
+        if self.persist {
+            self.file.seek(SeekFrom::Start(lba * BLOCK_SIZE as u64))?;
+            self.file.write_all(data)
+        } else {
+            self.overlay.insert(lba, data.to_vec());
+            Ok(())
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.persist { self.file.sync_data() } else { Ok(()) }
+    }
+
+    /// Persisted writes go straight to the host file, restoring a snapshot
+    /// doesn't take them back.
+    pub fn replay_hazard(&self) -> Option<String> {
+        self.persist.then(|| "a disk image with persist=true, replaying would write the host file again".to_string())
+    }
+
+    /// Writes kept in the overlay are part of the machine state, persisted
+    /// ones already live in the image file.
+    pub fn save(&self, state: &mut StateWriter) {
+        let mut blocks: Vec<_> = self.overlay.iter().collect();
+        blocks.sort_by_key(|(lba, _)| **lba);
+        state.u32(blocks.len() as u32);
+        for (lba, block) in blocks {
+            state.u64(*lba);
+            state.bytes(block);
+        }
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> Result<(), ()> {
+        self.overlay.clear();
+        for _ in 0..state.u32()? {
+            let lba = state.u64()?;
+            self.overlay.insert(lba, state.bytes()?.to_vec());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use crate::bus::{Addr, Bus, Device, IrqLine, StateReader, StateWriter};
+
+// Register map, all registers are 32 bits wide.
+const DMA_SRC: Addr = 0x00;
+const DMA_DST: Addr = 0x04;
+const DMA_LEN: Addr = 0x08;        // bytes per row
+const DMA_ROWS: Addr = 0x0C;       // number of rows, 0 counts as 1
+const DMA_SRC_STRIDE: Addr = 0x10; // bytes between the start of two source rows
+const DMA_DST_STRIDE: Addr = 0x14; // bytes between the start of two destination rows
+const DMA_FILL: Addr = 0x18;       // pattern written in fill mode
+const DMA_CTRL: Addr = 0x1C;
+const DMA_STATUS: Addr = 0x20;
+const DMA_BYTES: Addr = 0x24;      // total bytes moved since reset, read only
+
+const CTRL_START: u32 = 1 << 0;
+const CTRL_FILL: u32 = 1 << 1;
+const CTRL_IRQ_EN: u32 = 1 << 2;
+const CTRL_WIDTH_SHIFT: u32 = 4;   // 0: byte, 1: halfword, 2: word
+
+const STATUS_BUSY: u32 = 1 << 0;
+const STATUS_DONE: u32 = 1 << 1;   // write 1 to clear, also clears the interrupt
+const STATUS_ERROR: u32 = 1 << 2;  // write 1 to clear
+
+/// Memory to memory DMA engine. Transfers go through the `Bus`, so any
+/// mix of RAM and MMIO works. A transfer completes as soon as it starts.
+pub struct DmaController {
+    irq: Option<IrqLine>,
+    src: u32,
+    dst: u32,
+    len: u32,
+    rows: u32,
+    src_stride: u32,
+    dst_stride: u32,
+    fill: u32,
+    ctrl: u32,
+    status: u32,
+    bytes: u32,
+}
+impl DmaController {
+    pub fn new(irq: Option<IrqLine>) -> Self {
+        Self {
+            irq,
+            src: 0,
+            dst: 0,
+            len: 0,
+            rows: 0,
+            src_stride: 0,
+            dst_stride: 0,
+            fill: 0,
+            ctrl: 0,
+            status: 0,
+            bytes: 0,
+        }
+    }
+
+    fn update_irq(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.ctrl & CTRL_IRQ_EN != 0 && self.status & (STATUS_DONE | STATUS_ERROR) != 0);
+        }
+    }
+
+    fn transfer(&mut self, bus: &mut Bus) -> Result<(), ()> {
+        let width: u32 = 1 << ((self.ctrl >> CTRL_WIDTH_SHIFT) & 0x3);
+        if width > 4 || !self.len.is_multiple_of(width) {
+            return Err(());
+        }
+        let fill = self.ctrl & CTRL_FILL != 0;
+        for row in 0..self.rows.max(1) {
+            let src = self.src.wrapping_add(row.wrapping_mul(self.src_stride));
+            let dst = self.dst.wrapping_add(row.wrapping_mul(self.dst_stride));
+            for offset in (0..self.len).step_by(width as usize) {
+                let value = if fill { self.fill } else { bus.read(width as u8, src.wrapping_add(offset))? };
+                bus.write(width as u8, dst.wrapping_add(offset), value)?;
+                self.bytes = self.bytes.wrapping_add(width);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Device for DmaController {
+    fn read(&mut self, _size: u8, addr: Addr) -> u32 {
+        match addr {
+            DMA_SRC => self.src,
+            DMA_DST => self.dst,
+            DMA_LEN => self.len,
+            DMA_ROWS => self.rows,
+            DMA_SRC_STRIDE => self.src_stride,
+            DMA_DST_STRIDE => self.dst_stride,
+            DMA_FILL => self.fill,
+            DMA_CTRL => self.ctrl,
+            DMA_STATUS => self.status,
+            DMA_BYTES => self.bytes,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _size: u8, addr: Addr, value: u32) -> Result<(), ()> {
+        match addr {
+            DMA_SRC => self.src = value,
+            DMA_DST => self.dst = value,
+            DMA_LEN => self.len = value,
+            DMA_ROWS => self.rows = value,
+            DMA_SRC_STRIDE => self.src_stride = value,
+            DMA_DST_STRIDE => self.dst_stride = value,
+            DMA_FILL => self.fill = value,
+            DMA_CTRL => {
+                self.ctrl = value;
+                if value & CTRL_START != 0 {
+                    self.status = (self.status | STATUS_BUSY) & !(STATUS_DONE | STATUS_ERROR);
+                }
+                self.update_irq();
+            }
+            DMA_STATUS => {
+                self.status &= !(value & (STATUS_DONE | STATUS_ERROR));
+                self.update_irq();
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        let irq = self.irq.take();
+        *self = Self::new(irq);
+        self.update_irq();
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        for value in [self.src, self.dst, self.len, self.rows, self.src_stride, self.dst_stride,
+                      self.fill, self.ctrl, self.status, self.bytes] {
+            state.u32(value);
+        }
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), ()> {
+        let mut state = StateReader::new(state);
+        for field in [&mut self.src, &mut self.dst, &mut self.len, &mut self.rows, &mut self.src_stride,
+                      &mut self.dst_stride, &mut self.fill, &mut self.ctrl, &mut self.status, &mut self.bytes] {
+            *field = state.u32()?;
+        }
+        self.update_irq();
+        state.finish()
+    }
+
+    fn dma_pending(&self) -> bool {
+        self.status & STATUS_BUSY != 0
+    }
+
+    fn dma(&mut self, bus: &mut Bus) {
+        let result = self.transfer(bus);
+        self.ctrl &= !CTRL_START;
+        self.status &= !STATUS_BUSY;
+        self.status |= if result.is_ok() { STATUS_DONE } else { STATUS_ERROR };
+        self.update_irq();
+    }
+}
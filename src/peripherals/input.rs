@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{StateReader, StateWriter};
+
+/// Host input (stdin, keys) feeding a device. Every byte handed to the
+/// guest is logged with the device time, so re-executing from a checkpoint
+/// sees the same bytes at the same cycles instead of whatever the host
+/// typed since.
+pub struct RecordedInput {
+    live: Arc<Mutex<VecDeque<u8>>>,
+    log: Vec<(u64, u8)>,
+    /// Next log entry to replay, equal to `log.len()` when running live.
+    cursor: usize,
+    /// Cycles seen through `tick`.
+    now: u64,
+}
+impl RecordedInput {
+    pub fn new(live: Arc<Mutex<VecDeque<u8>>>) -> Self {
+        Self { live, log: Vec::new(), cursor: 0, now: 0 }
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    /// Up to `max` bytes the guest can consume right now.
+    pub fn peek(&self, max: usize) -> Vec<u8> {
+        if self.cursor < self.log.len() {
+            self.log[self.cursor..].iter()
+                .take_while(|(time, _)| *time <= self.now)
+                .map(|&(_, byte)| byte)
+                .take(max)
+                .collect()
+        } else {
+            self.live.lock().unwrap().iter().copied().take(max).collect()
+        }
+    }
+
+    pub fn has_input(&self) -> bool {
+        !self.peek(1).is_empty()
+    }
+
+    /// Marks `count` bytes returned by `peek` as delivered.
+    pub fn consume(&mut self, count: usize) {
+        for _ in 0..count {
+            if self.cursor == self.log.len() {
+                let Some(byte) = self.live.lock().unwrap().pop_front() else { break };
+                self.log.push((self.now, byte));
+            }
+            self.cursor += 1;
+        }
+    }
+
+    // The log itself is history, not machine state, and survives restores.
+    pub fn save(&self, state: &mut StateWriter) {
+        state.u64(self.now);
+        state.u64(self.cursor as u64);
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> Result<(), ()> {
+        self.now = state.u64()?;
+        self.cursor = (state.u64()? as usize).min(self.log.len());
+        Ok(())
+    }
+}
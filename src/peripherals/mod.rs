@@ -2,8 +2,30 @@ mod ram;
 mod uart;
 mod textmode;
 mod screen_csr;
+mod rom;
+mod spi;
+mod sdcard;
+mod disk;
+mod input;
+mod virtio;
+mod virtio_blk;
+mod virtio_console;
+mod dma;
+mod plugin;
+mod clint;
+mod plic;
 pub use ram::Ram;
 pub use uart::UartNs16550a;
 pub use textmode::TextMode;
 pub use screen_csr::ScreenCsr;
+pub use rom::Rom;
+pub use spi::SpiController;
+pub use sdcard::SdCard;
+pub use virtio::VirtioMmio;
+pub use virtio_blk::VirtioBlk;
+pub use virtio_console::VirtioConsole;
+pub use dma::DmaController;
+pub use plugin::{PluginDevice, PluginLibrary};
+pub use clint::Clint;
+pub use plic::Plic;
 pub use textmode::ScreenHandle;
\ No newline at end of file
@@ -0,0 +1,139 @@
+use crate::bus::{Addr, Device, IrqController, StateReader, StateWriter};
+
+// SiFive PLIC register map, as described in the device tree.
+const PRIORITY: Addr = 0x00_0000;   // one word per source, source 0 doesn't exist
+const PENDING: Addr = 0x00_1000;    // bit per source, read only
+const ENABLE: Addr = 0x00_2000;     // bit per source, 0x80 bytes per context
+const ENABLE_STRIDE: Addr = 0x80;
+const CONTEXT: Addr = 0x20_0000;    // threshold, then claim/complete at +4
+const CONTEXT_STRIDE: Addr = 0x1000;
+const CLAIM: Addr = 0x4;
+
+/// Context 0 is the hart's M mode external interrupt, 1 its S mode one,
+/// in the order of `interrupts-extended` in the device tree.
+const CONTEXTS: usize = 2;
+const SOURCES: usize = 32;
+const PRIORITY_MASK: u32 = 0x7;
+
+/// Platform level interrupt controller. Its sources are the bus interrupt
+/// lines: a raised line is pending until a context claims it, and isn't
+/// pending again before the claim is completed. Claims work, but nothing
+/// signals the hart, so software polls the claim register.
+pub struct Plic {
+    irqs: IrqController,
+    priority: [u32; SOURCES],
+    enable: [u32; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+    /// Sources claimed and not completed yet.
+    claimed: u32,
+}
+impl Plic {
+    pub fn new(irqs: IrqController) -> Self {
+        Self { irqs, priority: [0; SOURCES], enable: [0; CONTEXTS], threshold: [0; CONTEXTS], claimed: 0 }
+    }
+
+    fn pending(&self) -> u32 {
+        self.irqs.pending() & !self.claimed & !1
+    }
+
+    /// Highest priority pending source enabled for `context` and above its
+    /// threshold, the lowest id wins a tie. 0 when there is none.
+    fn best(&self, context: usize) -> u32 {
+        let candidates = self.pending() & self.enable[context];
+        let mut best = 0;
+        for source in 1..SOURCES {
+            let priority = self.priority[source];
+            if candidates & (1 << source) != 0 && priority > self.threshold[context]
+                && (best == 0 || priority > self.priority[best]) {
+                best = source;
+            }
+        }
+        best as u32
+    }
+
+    /// Context and register offset of a per context register.
+    fn context(addr: Addr) -> Option<(usize, Addr)> {
+        let offset = addr.checked_sub(CONTEXT)?;
+        let context = (offset / CONTEXT_STRIDE) as usize;
+        (context < CONTEXTS).then_some((context, offset % CONTEXT_STRIDE))
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, _size: u8, addr: Addr) -> u32 {
+        let addr = addr & !0x3;
+        match addr {
+            PRIORITY..PENDING => self.priority.get((addr / 4) as usize).copied().unwrap_or(0),
+            PENDING => self.pending(),
+            ENABLE..CONTEXT => {
+                let offset = addr - ENABLE;
+                match ((offset / ENABLE_STRIDE) as usize, offset % ENABLE_STRIDE) {
+                    (context, 0) if context < CONTEXTS => self.enable[context],
+                    _ => 0,
+                }
+            }
+            _ => match Self::context(addr) {
+                Some((context, 0)) => self.threshold[context],
+                Some((context, CLAIM)) => {
+                    let source = self.best(context);
+                    self.claimed |= (1 << source) & !1;
+                    source
+                }
+                _ => 0,
+            },
+        }
+    }
+
+    fn write(&mut self, _size: u8, addr: Addr, value: u32) -> Result<(), ()> {
+        let addr = addr & !0x3;
+        match addr {
+            PRIORITY..PENDING => {
+                let source = (addr / 4) as usize;
+                if source == 0 || source >= SOURCES {
+                    return Err(());
+                }
+                self.priority[source] = value & PRIORITY_MASK;
+            }
+            ENABLE..CONTEXT => {
+                let offset = addr - ENABLE;
+                match ((offset / ENABLE_STRIDE) as usize, offset % ENABLE_STRIDE) {
+                    (context, 0) if context < CONTEXTS => self.enable[context] = value & !1,
+                    _ => return Err(()),
+                }
+            }
+            _ => match Self::context(addr) {
+                Some((context, 0)) => self.threshold[context] = value & PRIORITY_MASK,
+                // Completing a source the context doesn't have enabled is ignored.
+                Some((context, CLAIM)) => {
+                    if value < SOURCES as u32 && self.enable[context] & (1 << value) != 0 {
+                        self.claimed &= !(1 << value);
+                    }
+                }
+                _ => return Err(()),
+            },
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.irqs.clone());
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        for &value in self.priority.iter().chain(&self.enable).chain(&self.threshold) {
+            state.u32(value);
+        }
+        state.u32(self.claimed);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), ()> {
+        let mut state = StateReader::new(state);
+        for field in self.priority.iter_mut().chain(&mut self.enable).chain(&mut self.threshold) {
+            *field = state.u32()?;
+        }
+        self.claimed = state.u32()?;
+        state.finish()
+    }
+}
@@ -0,0 +1,158 @@
+use std::ffi::{c_char, c_void, CString};
+use std::fmt;
+use std::sync::Arc;
+
+use libloading::Library;
+
+use crate::bus::{Addr, Device, IrqLine, StateReader, StateWriter};
+
+/// Version of `RiscyDeviceApi`, bumped on incompatible changes.
+pub const RISCY_DEVICE_ABI_VERSION: u32 = 1;
+const ENTRY_SYMBOL: &[u8] = b"riscy_device_api\0";
+
+type IrqCallback = extern "C" fn(host: *mut c_void, level: i32);
+
+/// Function table exported by a plugin, see `plugins/riscy_device.h`.
+/// Optional callbacks may be NULL.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RiscyDeviceApi {
+    abi_version: u32,
+    /// Size of the MMIO window in bytes.
+    size: u32,
+    create: extern "C" fn(args: *const c_char, host: *mut c_void, set_irq: IrqCallback) -> *mut c_void,
+    destroy: Option<extern "C" fn(dev: *mut c_void)>,
+    read: extern "C" fn(dev: *mut c_void, size: u8, offset: u32) -> u32,
+    write: extern "C" fn(dev: *mut c_void, size: u8, offset: u32, value: u32) -> i32,
+    tick: Option<extern "C" fn(dev: *mut c_void, cycles: u64)>,
+    reset: Option<extern "C" fn(dev: *mut c_void)>,
+    /// Writes up to `len` bytes of state, returns the full size needed.
+    save: Option<extern "C" fn(dev: *const c_void, buf: *mut u8, len: usize) -> usize>,
+    restore: Option<extern "C" fn(dev: *mut c_void, buf: *const u8, len: usize) -> i32>,
+}
+
+extern "C" fn set_irq(host: *mut c_void, level: i32) {
+    if !host.is_null() {
+        // SAFETY: `host` is the boxed `IrqLine` owned by the `PluginDevice`.
+        unsafe { (*(host as *const IrqLine)).set(level != 0) }
+    }
+}
+
+/// A loaded plugin library and a copy of its function table. The
+/// callbacks point into the library, so they are only called through this
+/// struct, which keeps it loaded.
+pub struct PluginLibrary {
+    path: String,
+    api: RiscyDeviceApi,
+    _library: Library,
+}
+
+impl PluginLibrary {
+    pub fn open(path: &str) -> Result<Arc<Self>, String> {
+        // SAFETY: loading a plugin runs its initializers, which we have to trust.
+        let library = unsafe { Library::new(path) }.map_err(|e| format!("Failed to load plugin '{}': {}", path, e))?;
+        let api = unsafe {
+            let entry = library.get::<extern "C" fn() -> *const RiscyDeviceApi>(ENTRY_SYMBOL)
+                .map_err(|e| format!("'{}' is not a device plugin: {}", path, e))?;
+            *entry().as_ref().ok_or_else(|| format!("Plugin '{}' returned no API table", path))?
+        };
+        if api.abi_version != RISCY_DEVICE_ABI_VERSION {
+            return Err(format!("Plugin '{}' uses ABI version {}, expected {}", path, api.abi_version, RISCY_DEVICE_ABI_VERSION));
+        }
+        Ok(Arc::new(Self { path: path.to_string(), api, _library: library }))
+    }
+
+    /// MMIO window size the plugin asks for, used when it is mapped with `--device`.
+    pub fn window_size(&self) -> u32 {
+        self.api.size
+    }
+}
+
+impl fmt::Debug for PluginLibrary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PluginLibrary").field("path", &self.path).finish_non_exhaustive()
+    }
+}
+
+/// `Device` adapter around a device model loaded from a shared library.
+pub struct PluginDevice {
+    library: Arc<PluginLibrary>,
+    instance: *mut c_void,
+    _irq: Option<Box<IrqLine>>,
+}
+
+// SAFETY: the plugin instance is only ever called from the thread owning the bus.
+unsafe impl Send for PluginDevice {}
+
+impl PluginDevice {
+    pub fn new(library: Arc<PluginLibrary>, args: &str, irq: Option<IrqLine>) -> Result<Self, String> {
+        let (api, path) = (&library.api, &library.path);
+        let irq = irq.map(Box::new);
+        let host = irq.as_deref().map_or(std::ptr::null_mut(), |irq| irq as *const IrqLine as *mut c_void);
+        let args = CString::new(args).map_err(|_| "Plugin arguments contain a NUL byte".to_string())?;
+        let instance = (api.create)(args.as_ptr(), host, set_irq);
+        if instance.is_null() {
+            return Err(format!("Plugin '{}' failed to create a device", path));
+        }
+        println!("Loaded device plugin {}", path);
+        Ok(Self { library, instance, _irq: irq })
+    }
+}
+
+impl Drop for PluginDevice {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.library.api.destroy {
+            destroy(self.instance);
+        }
+    }
+}
+
+impl Device for PluginDevice {
+    fn read(&mut self, size: u8, addr: Addr) -> u32 {
+        (self.library.api.read)(self.instance, size, addr)
+    }
+
+    fn write(&mut self, size: u8, addr: Addr, value: u32) -> Result<(), ()> {
+        if (self.library.api.write)(self.instance, size, addr, value) == 0 { Ok(()) } else { Err(()) }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if let Some(tick) = self.library.api.tick {
+            tick(self.instance, cycles);
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(reset) = self.library.api.reset {
+            reset(self.instance);
+        }
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        let mut buf = Vec::new();
+        if let Some(save) = self.library.api.save {
+            let len = save(self.instance, buf.as_mut_ptr(), 0);
+            buf.resize(len, 0);
+            let written = save(self.instance, buf.as_mut_ptr(), len);
+            buf.truncate(written.min(len));
+        }
+        state.bytes(&buf);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), ()> {
+        let mut state = StateReader::new(state);
+        let buf = state.bytes()?;
+        if let Some(restore) = self.library.api.restore
+            && restore(self.instance, buf.as_ptr(), buf.len()) != 0 {
+            return Err(());
+        }
+        state.finish()
+    }
+
+    fn replay_hazard(&self) -> Option<String> {
+        (self.library.api.save.is_none() || self.library.api.restore.is_none())
+            .then(|| "a device plugin without save/restore, its state can't be rolled back".to_string())
+    }
+}
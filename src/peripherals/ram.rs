@@ -1,4 +1,4 @@
-use crate::{bus::{Addr, Device}};
+use crate::{bus::{Addr, Device, StateReader, StateWriter}};
 
 pub struct Ram {
     // addr: Addr,
@@ -41,6 +41,16 @@ impl Device for Ram {
         }
     }
 
+    fn peek(&self, size: u8, addr: Addr) -> Option<u32> {
+        if !matches!(size, 1 | 2 | 4) {
+            return None;
+        }
+        let o = addr as usize;
+        let mut bytes = [0u8; 4];
+        bytes[..size as usize].copy_from_slice(self.data.get(o..o + size as usize)?);
+        Some(u32::from_le_bytes(bytes))
+    }
+
     fn write(&mut self, size: u8, addr: Addr, value: u32) -> Result<(), ()> {
         let o = addr as usize;
         match size {
@@ -59,4 +69,24 @@ impl Device for Ram {
         }
         Ok(())
     }
+
+    fn reset(&mut self) {
+        self.data.fill(0);
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.data);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), ()> {
+        let mut state = StateReader::new(state);
+        let data = state.bytes()?;
+        if data.len() != self.data.len() {
+            return Err(());
+        }
+        self.data.copy_from_slice(data);
+        state.finish()
+    }
 }
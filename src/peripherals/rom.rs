@@ -0,0 +1,32 @@
+use crate::bus::{Addr, Device};
+
+/// Read-only memory, used for the boot ROM and the flash images of the boot chain.
+/// Reads past the end of the image return 0, writes are rejected.
+pub struct Rom {
+    data: Vec<u8>,
+}
+impl Rom {
+    pub fn new(words: &[u32]) -> Self {
+        let data = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        Self { data }
+    }
+}
+
+impl Device for Rom {
+    fn read(&mut self, size: u8, addr: Addr) -> u32 {
+        self.peek(size, addr).unwrap_or_else(|| panic!("Invalid read size: {size}"))
+    }
+
+    fn peek(&self, size: u8, addr: Addr) -> Option<u32> {
+        let o = addr as usize;
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate().take(size as usize) {
+            *byte = self.data.get(o + i).copied().unwrap_or(0);
+        }
+        matches!(size, 1 | 2 | 4).then(|| u32::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, _size: u8, _addr: Addr, _value: u32) -> Result<(), ()> {
+        Err(())
+    }
+}
@@ -1,8 +1,9 @@
 
 use colored::Colorize;
 
-use crate::bus::{Addr, Device};
+use crate::bus::{Addr, Device, StateReader, StateWriter};
 
+#[derive(Default)]
 pub struct ScreenCsr{
     pub frame_buffer_enabled: bool,
     pub frame_buffer_addr: u32,
@@ -38,4 +39,23 @@ impl Device for ScreenCsr {
             Err(())
         }
     }
+
+    fn reset(&mut self) {
+        // The framebuffer address is handed out by the loader, keep it.
+        self.frame_buffer_enabled = false;
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bool(self.frame_buffer_enabled);
+        state.u32(self.frame_buffer_addr);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), ()> {
+        let mut state = StateReader::new(state);
+        self.frame_buffer_enabled = state.bool()?;
+        self.frame_buffer_addr = state.u32()?;
+        state.finish()
+    }
 }
@@ -0,0 +1,328 @@
+use std::collections::VecDeque;
+
+use super::disk::{DiskImage, BLOCK_SIZE};
+use super::spi::SpiSlave;
+use crate::bus::{StateReader, StateWriter};
+
+// R1 response bits
+const R1_READY: u8 = 0x00;
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+const R1_PARAMETER_ERROR: u8 = 0x40;
+
+// Data tokens
+const TOKEN_START_BLOCK: u8 = 0xFE;
+const TOKEN_START_MULTI_WRITE: u8 = 0xFC;
+const TOKEN_STOP_TRAN: u8 = 0xFD;
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_WRITE_ERROR: u8 = 0x0D;
+
+enum State {
+    Command,
+    /// Waiting for the start token of a block write.
+    WriteToken { lba: u64, multi: bool },
+    WriteData { lba: u64, multi: bool, buf: Vec<u8> },
+}
+
+/// SD card (SDHC, block addressed) speaking the SPI mode command set.
+pub struct SdCard {
+    image: DiskImage,
+    selected: bool,
+    idle: bool,
+    app_cmd: bool,
+    cmd: Vec<u8>,
+    out: VecDeque<u8>,
+    state: State,
+    /// Next block of a CMD18 multiple block read.
+    read_stream: Option<u64>,
+}
+impl SdCard {
+    pub fn new(image_path: &str, persist: bool) -> std::io::Result<Self> {
+        let image = DiskImage::open(image_path, persist)?;
+        println!("Initialized SD card with {} blocks from {}", image.blocks, image_path);
+        Ok(Self {
+            image,
+            selected: false,
+            idle: true,
+            app_cmd: false,
+            cmd: Vec::with_capacity(6),
+            out: VecDeque::new(),
+            state: State::Command,
+            read_stream: None,
+        })
+    }
+
+    fn r1(&self) -> u8 {
+        if self.idle { R1_IDLE } else { R1_READY }
+    }
+
+    fn respond(&mut self, bytes: &[u8]) {
+        // One byte of command response time (Ncr) before the response.
+        self.out.push_back(0xFF);
+        self.out.extend(bytes);
+    }
+
+    fn queue_data_block(&mut self, data: &[u8]) {
+        self.out.push_back(0xFF);
+        self.out.push_back(TOKEN_START_BLOCK);
+        self.out.extend(data);
+        self.out.extend([0xFF, 0xFF]); // CRC is not checked in SPI mode
+    }
+
+    fn queue_read(&mut self, lba: u64) -> bool {
+        if lba >= self.image.blocks {
+            return false;
+        }
+        match self.image.read_block(lba) {
+            Ok(block) => {
+                self.queue_data_block(&block);
+                true
+            }
+            Err(e) => {
+                println!("SD card: read of block {} failed: {}", lba, e);
+                false
+            }
+        }
+    }
+
+    fn csd(&self) -> [u8; 16] {
+        // CSD version 2.0, capacity = (C_SIZE + 1) * 512KiB
+        let c_size = (self.image.blocks / 1024).saturating_sub(1) as u32;
+        [
+            0x40, 0x0E, 0x00, 0x32, 0x5B, 0x59, 0x00,
+            ((c_size >> 16) & 0x3F) as u8, (c_size >> 8) as u8, c_size as u8,
+            0x7F, 0x80, 0x0A, 0x40, 0x00, 0x01,
+        ]
+    }
+
+    fn cid(&self) -> [u8; 16] {
+        [
+            0x52, b'R', b'Y', b'R', b'I', b'S', b'C', b'Y', 0x10,
+            0x00, 0x00, 0x00, 0x01, 0x01, 0x9A, 0x01,
+        ]
+    }
+
+    fn execute(&mut self, index: u8, arg: u32) {
+        let app_cmd = std::mem::take(&mut self.app_cmd);
+        match (app_cmd, index) {
+            (_, 0) => {
+                // GO_IDLE_STATE
+                self.idle = true;
+                self.read_stream = None;
+                self.out.clear();
+                self.state = State::Command;
+                self.respond(&[R1_IDLE]);
+            }
+            (true, 41) => {
+                // SD_SEND_OP_COND, initialization completes immediately
+                self.idle = false;
+                self.respond(&[R1_READY]);
+            }
+            (true, _) => {
+                // Remaining ACMDs (SET_WR_BLK_ERASE_COUNT, SD_STATUS...) are accepted and ignored.
+                let r1 = self.r1();
+                self.respond(&[r1]);
+            }
+            (false, 8) => {
+                // SEND_IF_COND, echo the voltage range and the check pattern
+                let r1 = self.r1();
+                self.respond(&[r1, 0x00, 0x00, ((arg >> 8) & 0x0F) as u8, arg as u8]);
+            }
+            (false, 9) | (false, 10) => {
+                // SEND_CSD / SEND_CID
+                let r1 = self.r1();
+                self.respond(&[r1]);
+                let register = if index == 9 { self.csd() } else { self.cid() };
+                self.queue_data_block(&register);
+            }
+            (false, 12) => {
+                // STOP_TRANSMISSION
+                self.read_stream = None;
+                self.out.clear();
+                let r1 = self.r1();
+                self.respond(&[0xFF, r1]); // stuff byte
+            }
+            (false, 13) => {
+                // SEND_STATUS (R2)
+                let r1 = self.r1();
+                self.respond(&[r1, 0x00]);
+            }
+            (false, 16) => {
+                // SET_BLOCKLEN, SDHC cards only support 512 bytes
+                let r1 = self.r1() | if arg as usize == BLOCK_SIZE { 0 } else { R1_PARAMETER_ERROR };
+                self.respond(&[r1]);
+            }
+            (false, 17) | (false, 18) => {
+                // READ_SINGLE_BLOCK / READ_MULTIPLE_BLOCK
+                let lba = arg as u64;
+                if self.idle {
+                    self.respond(&[R1_IDLE | R1_ILLEGAL_COMMAND]);
+                } else if lba >= self.image.blocks {
+                    self.respond(&[R1_ADDRESS_ERROR]);
+                } else {
+                    self.respond(&[R1_READY]);
+                    if self.queue_read(lba) && index == 18 {
+                        self.read_stream = Some(lba + 1);
+                    }
+                }
+            }
+            (false, 24) | (false, 25) => {
+                // WRITE_BLOCK / WRITE_MULTIPLE_BLOCK
+                let lba = arg as u64;
+                if self.idle {
+                    self.respond(&[R1_IDLE | R1_ILLEGAL_COMMAND]);
+                } else if lba >= self.image.blocks {
+                    self.respond(&[R1_ADDRESS_ERROR]);
+                } else {
+                    self.respond(&[R1_READY]);
+                    self.state = State::WriteToken { lba, multi: index == 25 };
+                }
+            }
+            (false, 55) => {
+                // APP_CMD
+                self.app_cmd = true;
+                let r1 = self.r1();
+                self.respond(&[r1]);
+            }
+            (false, 58) => {
+                // READ_OCR: powered up, high capacity, 2.7-3.6V
+                let r1 = self.r1();
+                self.respond(&[r1, 0xC0, 0xFF, 0x80, 0x00]);
+            }
+            (false, 59) => {
+                // CRC_ON_OFF, CRCs are never checked
+                let r1 = self.r1();
+                self.respond(&[r1]);
+            }
+            _ => {
+                let r1 = self.r1() | R1_ILLEGAL_COMMAND;
+                self.respond(&[r1]);
+            }
+        }
+    }
+
+    fn receive_command_byte(&mut self, mosi: u8) {
+        // Commands start with the 0b01 transmission bits.
+        if self.cmd.is_empty() && mosi & 0xC0 != 0x40 {
+            return;
+        }
+        self.cmd.push(mosi);
+        if self.cmd.len() == 6 {
+            let index = self.cmd[0] & 0x3F;
+            let arg = u32::from_be_bytes([self.cmd[1], self.cmd[2], self.cmd[3], self.cmd[4]]);
+            self.cmd.clear();
+            self.execute(index, arg);
+        }
+    }
+}
+
+impl SpiSlave for SdCard {
+    fn select(&mut self, selected: bool) {
+        self.selected = selected;
+        if !selected {
+            self.cmd.clear();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.selected = false;
+        self.idle = true;
+        self.app_cmd = false;
+        self.cmd.clear();
+        self.out.clear();
+        self.state = State::Command;
+        self.read_stream = None;
+    }
+
+    fn replay_hazard(&self) -> Option<String> {
+        self.image.replay_hazard()
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.selected);
+        state.bool(self.idle);
+        state.bool(self.app_cmd);
+        state.bytes(&self.cmd);
+        state.bytes(&self.out.iter().copied().collect::<Vec<u8>>());
+        match &self.state {
+            State::Command => state.u32(0),
+            State::WriteToken { lba, multi } => {
+                state.u32(1);
+                state.u64(*lba);
+                state.bool(*multi);
+            }
+            State::WriteData { lba, multi, buf } => {
+                state.u32(2);
+                state.u64(*lba);
+                state.bool(*multi);
+                state.bytes(buf);
+            }
+        }
+        state.bool(self.read_stream.is_some());
+        state.u64(self.read_stream.unwrap_or(0));
+        self.image.save(state);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<(), ()> {
+        self.selected = state.bool()?;
+        self.idle = state.bool()?;
+        self.app_cmd = state.bool()?;
+        self.cmd = state.bytes()?.to_vec();
+        self.out = state.bytes()?.iter().copied().collect();
+        self.state = match state.u32()? {
+            0 => State::Command,
+            1 => State::WriteToken { lba: state.u64()?, multi: state.bool()? },
+            2 => State::WriteData { lba: state.u64()?, multi: state.bool()?, buf: state.bytes()?.to_vec() },
+            _ => return Err(()),
+        };
+        let streaming = state.bool()?;
+        let lba = state.u64()?;
+        self.read_stream = streaming.then_some(lba);
+        self.image.restore(state)
+    }
+
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        if !self.selected {
+            return 0xFF;
+        }
+        let miso = self.out.pop_front().unwrap_or(0xFF);
+        match std::mem::replace(&mut self.state, State::Command) {
+            State::Command => self.receive_command_byte(mosi),
+            State::WriteToken { lba, multi } => {
+                self.state = match mosi {
+                    TOKEN_START_BLOCK if !multi => State::WriteData { lba, multi, buf: Vec::with_capacity(BLOCK_SIZE + 2) },
+                    TOKEN_START_MULTI_WRITE if multi => State::WriteData { lba, multi, buf: Vec::with_capacity(BLOCK_SIZE + 2) },
+                    TOKEN_STOP_TRAN if multi => {
+                        self.out.extend([0xFF, 0x00]); // busy for a byte
+                        State::Command
+                    }
+                    _ => State::WriteToken { lba, multi },
+                };
+            }
+            State::WriteData { lba, multi, mut buf } => {
+                buf.push(mosi);
+                if buf.len() < BLOCK_SIZE + 2 {
+                    self.state = State::WriteData { lba, multi, buf };
+                } else {
+                    let status = match self.image.write_block(lba, &buf[..BLOCK_SIZE]) {
+                        Ok(()) => DATA_ACCEPTED,
+                        Err(e) => {
+                            println!("SD card: write of block {} failed: {}", lba, e);
+                            DATA_WRITE_ERROR
+                        }
+                    };
+                    self.out.extend([status, 0x00]); // data response, then busy
+                    if multi && status == DATA_ACCEPTED && lba + 1 < self.image.blocks {
+                        self.state = State::WriteToken { lba: lba + 1, multi };
+                    }
+                }
+            }
+        }
+        // Keep a CMD18 stream flowing until CMD12 arrives.
+        if self.out.is_empty() && let Some(lba) = self.read_stream {
+            self.read_stream = if self.queue_read(lba) { Some(lba + 1) } else { None };
+        }
+        miso
+    }
+}
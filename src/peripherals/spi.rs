@@ -0,0 +1,99 @@
+use crate::bus::{Addr, Device, StateReader, StateWriter};
+
+// Register map, all registers are 32 bits wide.
+const SPI_DATA: Addr = 0x0;   // write: start a transfer, read: last received byte
+const SPI_STATUS: Addr = 0x4; // bit 0: busy, bit 1: rx byte available
+const SPI_CS: Addr = 0x8;     // bit 0: chip select asserted
+const SPI_CLKDIV: Addr = 0xC; // kept for the firmware, transfers are instant
+
+const STATUS_RX_VALID: u32 = 1 << 1;
+
+/// Anything that can sit on the other end of the SPI bus.
+pub trait SpiSlave: Send {
+    fn select(&mut self, selected: bool);
+    /// Full duplex exchange of one byte.
+    fn transfer(&mut self, mosi: u8) -> u8;
+    fn reset(&mut self) {}
+    fn save(&self, _state: &mut StateWriter) {}
+    fn restore(&mut self, _state: &mut StateReader) -> Result<(), ()> { Ok(()) }
+    fn replay_hazard(&self) -> Option<String> { None }
+}
+
+/// Minimal SPI master with a single chip select. Every write to the data
+/// register shifts one byte out and latches the byte shifted in.
+pub struct SpiController {
+    slave: Box<dyn SpiSlave>,
+    rx: u8,
+    rx_valid: bool,
+    cs: bool,
+    clkdiv: u32,
+}
+impl SpiController {
+    pub fn new(slave: Box<dyn SpiSlave>) -> Self {
+        Self { slave, rx: 0xFF, rx_valid: false, cs: false, clkdiv: 0 }
+    }
+}
+
+impl Device for SpiController {
+    fn read(&mut self, _size: u8, addr: Addr) -> u32 {
+        match addr & !0x3 {
+            SPI_DATA => {
+                self.rx_valid = false;
+                self.rx as u32
+            }
+            SPI_STATUS => (self.rx_valid as u32) * STATUS_RX_VALID,
+            SPI_CS => self.cs as u32,
+            SPI_CLKDIV => self.clkdiv,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _size: u8, addr: Addr, value: u32) -> Result<(), ()> {
+        match addr & !0x3 {
+            SPI_DATA => {
+                self.rx = self.slave.transfer(value as u8);
+                self.rx_valid = true;
+            }
+            SPI_STATUS => {}
+            SPI_CS => {
+                self.cs = value & 1 == 1;
+                self.slave.select(self.cs);
+            }
+            SPI_CLKDIV => self.clkdiv = value,
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.rx = 0xFF;
+        self.rx_valid = false;
+        self.cs = false;
+        self.clkdiv = 0;
+        self.slave.reset();
+    }
+
+    fn replay_hazard(&self) -> Option<String> {
+        self.slave.replay_hazard()
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.u32(self.rx as u32);
+        state.bool(self.rx_valid);
+        state.bool(self.cs);
+        state.u32(self.clkdiv);
+        self.slave.save(&mut state);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), ()> {
+        let mut state = StateReader::new(state);
+        self.rx = state.u32()? as u8;
+        self.rx_valid = state.bool()?;
+        self.cs = state.bool()?;
+        self.clkdiv = state.u32()?;
+        self.slave.restore(&mut state)?;
+        state.finish()
+    }
+}
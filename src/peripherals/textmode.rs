@@ -1,7 +1,7 @@
 
 use std::sync::{Arc, RwLock}; // or Mutex if you prefer
 
-use crate::bus::{Addr, Device};
+use crate::bus::{Addr, Device, StateReader, StateWriter};
 
 use crate::constants::*;
 
@@ -19,6 +19,11 @@ pub struct TextMode {
     pub screen_data: ScreenHandle,
     text_data: Vec<u8>,
 }
+impl Default for TextMode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl TextMode {
     pub fn new() -> Self {
         println!("Initiated VGA-TextMode");
@@ -63,6 +68,12 @@ impl TextMode {
         };
         Attribute { fg, bg }
     }
+    fn redraw(&self) {
+        let data = self.convert_vec_to_img();
+        if let Ok(mut screen) = self.screen_data.write() {
+            screen.copy_from_slice(&data);
+        }
+    }
     pub fn handle(&self) -> ScreenHandle {
         Arc::clone(&self.screen_data)
     }
@@ -175,10 +186,7 @@ impl Device for TextMode {
                 // let str = self.convert_vec_to_string();
                 if self.text_data[o..o + 2] != bytes {
                     self.text_data[o..o + 2].copy_from_slice(&bytes);
-                    let data = self.convert_vec_to_img();        // Vec<u8> with RGBA
-                    if let Ok(mut screen) = self.screen_data.write() {
-                        screen.copy_from_slice(&data);
-                    }
+                    self.redraw();
                 }
             }
             4 => {
@@ -189,4 +197,26 @@ impl Device for TextMode {
         }
         Ok(())
     }
+
+    fn reset(&mut self) {
+        self.text_data.fill(0);
+        self.redraw();
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.text_data);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), ()> {
+        let mut state = StateReader::new(state);
+        let text = state.bytes()?;
+        if text.len() != self.text_data.len() {
+            return Err(());
+        }
+        self.text_data.copy_from_slice(text);
+        self.redraw();
+        state.finish()
+    }
 }
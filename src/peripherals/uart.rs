@@ -4,10 +4,11 @@ use crate::bus::{Addr, Device};
 
 pub struct UartNs16550a{
     pub uart_tx: mpsc::Sender<char>,
+    muted: bool,
 }
 impl UartNs16550a {
     pub fn new(uart_tx: mpsc::Sender<char>) -> Self {
-        Self { uart_tx }
+        Self { uart_tx, muted: false }
     }
 }
 impl Device for UartNs16550a {
@@ -20,10 +21,16 @@ impl Device for UartNs16550a {
     fn write(&mut self, size: u8, addr: Addr, value: u32) -> Result<(), ()> {
         if addr == 0 && size == 1 {
             // print!("{}", (value as u8) as char);
-            let _ = self.uart_tx.send(value as u8 as char);
+            if !self.muted {
+                let _ = self.uart_tx.send(value as u8 as char);
+            }
             Ok(())
         } else {
             Err(())
         }
     }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
 }
@@ -0,0 +1,425 @@
+use crate::bus::{Addr, Bus, Device, IrqLine, StateReader, StateWriter};
+
+// virtio-mmio v2 register map (virtio 1.2, section 4.2.2)
+const MAGIC_VALUE: Addr = 0x000;
+const VERSION: Addr = 0x004;
+const DEVICE_ID: Addr = 0x008;
+const VENDOR_ID: Addr = 0x00C;
+const DEVICE_FEATURES: Addr = 0x010;
+const DEVICE_FEATURES_SEL: Addr = 0x014;
+const DRIVER_FEATURES: Addr = 0x020;
+const DRIVER_FEATURES_SEL: Addr = 0x024;
+const QUEUE_SEL: Addr = 0x030;
+const QUEUE_NUM_MAX: Addr = 0x034;
+const QUEUE_NUM: Addr = 0x038;
+const QUEUE_READY: Addr = 0x044;
+const QUEUE_NOTIFY: Addr = 0x050;
+const INTERRUPT_STATUS: Addr = 0x060;
+const INTERRUPT_ACK: Addr = 0x064;
+const STATUS: Addr = 0x070;
+const QUEUE_DESC_LOW: Addr = 0x080;
+const QUEUE_DESC_HIGH: Addr = 0x084;
+const QUEUE_DRIVER_LOW: Addr = 0x090;
+const QUEUE_DRIVER_HIGH: Addr = 0x094;
+const QUEUE_DEVICE_LOW: Addr = 0x0A0;
+const QUEUE_DEVICE_HIGH: Addr = 0x0A4;
+const CONFIG_GENERATION: Addr = 0x0FC;
+const CONFIG: Addr = 0x100;
+
+const VIRTIO_MAGIC: u32 = 0x7472_6976; // "virt"
+const VIRTIO_VENDOR: u32 = 0x5952_4953; // "SIRY"
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const QUEUE_SIZE_MAX: u32 = 256;
+/// Largest request a chain may carry. Descriptor lengths come from the
+/// guest, a chain claiming more is treated as a bad request.
+pub const MAX_REQUEST_SIZE: usize = 4 << 20;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const STATUS_FEATURES_OK: u32 = 8;
+
+/// One buffer of a descriptor chain.
+pub struct Descriptor {
+    pub addr: Addr,
+    pub len: u32,
+    /// Device writable (driver reads the result).
+    pub write: bool,
+}
+
+/// A descriptor chain popped from the available ring.
+pub struct DescriptorChain {
+    pub descriptors: Vec<Descriptor>,
+}
+impl DescriptorChain {
+    /// Concatenation of every device readable buffer, at most
+    /// `MAX_REQUEST_SIZE` bytes.
+    pub fn read_all(&self, bus: &mut Bus) -> Result<Vec<u8>, ()> {
+        let mut data = Vec::new();
+        for desc in self.descriptors.iter().filter(|desc| !desc.write) {
+            let start = data.len();
+            if desc.len as usize > MAX_REQUEST_SIZE - start {
+                return Err(());
+            }
+            data.resize(start + desc.len as usize, 0);
+            bus.read_bytes(desc.addr, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Scatters `data` over the device writable buffers, starting `skip`
+    /// bytes in. Returns how many bytes were written.
+    pub fn write_at(&self, bus: &mut Bus, mut skip: usize, data: &[u8]) -> Result<usize, ()> {
+        let mut written = 0;
+        for desc in self.descriptors.iter().filter(|desc| desc.write) {
+            let len = desc.len as usize;
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let count = (len - skip).min(data.len() - written);
+            bus.write_bytes(desc.addr.wrapping_add(skip as u32), &data[written..written + count])?;
+            written += count;
+            skip = 0;
+            if written == data.len() {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.descriptors.iter().filter(|desc| desc.write).map(|desc| desc.len as usize).sum()
+    }
+}
+
+/// Device type specific half of a virtio device.
+pub trait VirtioBackend: Send {
+    fn device_id(&self) -> u32;
+    /// Device specific feature bits, VIRTIO_F_VERSION_1 is added by the transport.
+    fn features(&self) -> u64;
+    fn num_queues(&self) -> usize;
+    fn config_read(&mut self, offset: Addr) -> u8;
+    /// Handles one chain of `queue`. Returns the number of bytes written
+    /// into the chain, or `None` to leave it on the ring for later.
+    fn process(&mut self, queue: usize, chain: &DescriptorChain, bus: &mut Bus) -> Option<u32>;
+    /// True when the backend has input that should be pushed to the driver.
+    fn has_input(&self) -> bool { false }
+    /// Queue that input produced by the backend is delivered on.
+    fn input_queue(&self) -> Option<usize> { None }
+    fn tick(&mut self, _cycles: u64) {}
+    fn save(&self, _state: &mut StateWriter) {}
+    fn restore(&mut self, _state: &mut StateReader) -> Result<(), ()> { Ok(()) }
+    fn set_muted(&mut self, _muted: bool) {}
+    fn replay_hazard(&self) -> Option<String> { None }
+}
+
+#[derive(Default)]
+struct Virtqueue {
+    num: u32,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    last_avail: u16,
+}
+
+/// virtio-mmio version 2 transport around a `VirtioBackend`.
+pub struct VirtioMmio {
+    backend: Box<dyn VirtioBackend>,
+    irq: Option<IrqLine>,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    interrupt_status: u32,
+    status: u32,
+    notified: Vec<usize>,
+}
+impl VirtioMmio {
+    pub fn new(backend: Box<dyn VirtioBackend>, irq: Option<IrqLine>) -> Self {
+        let queues = (0..backend.num_queues()).map(|_| Virtqueue::default()).collect();
+        Self {
+            backend,
+            irq,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+            status: 0,
+            notified: Vec::new(),
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.backend.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset_device(&mut self) {
+        for queue in &mut self.queues {
+            *queue = Virtqueue::default();
+        }
+        self.driver_features = 0;
+        self.status = 0;
+        self.notified.clear();
+        self.set_interrupt(0);
+    }
+
+    fn set_interrupt(&mut self, status: u32) {
+        self.interrupt_status = status;
+        if let Some(irq) = &self.irq {
+            irq.set(status != 0);
+        }
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn pop_chain(queue: &mut Virtqueue, bus: &mut Bus) -> Result<Option<(u16, DescriptorChain)>, ()> {
+        let avail_idx = bus.read(2, queue.driver as Addr + 2)? as u16;
+        if avail_idx == queue.last_avail {
+            return Ok(None);
+        }
+        let slot = queue.last_avail as u32 % queue.num;
+        let head = bus.read(2, queue.driver as Addr + 4 + slot * 2)? as u16;
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        loop {
+            // Bound the walk so a corrupted ring cannot loop forever.
+            if descriptors.len() as u32 >= queue.num {
+                return Err(());
+            }
+            let entry = queue.desc as Addr + index as u32 * 16;
+            let addr = bus.read(4, entry)?;
+            let len = bus.read(4, entry + 8)?;
+            let flags = bus.read(2, entry + 12)? as u16;
+            let next = bus.read(2, entry + 14)? as u16;
+            descriptors.push(Descriptor { addr, len, write: flags & VIRTQ_DESC_F_WRITE != 0 });
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+        Ok(Some((head, DescriptorChain { descriptors })))
+    }
+
+    fn push_used(queue: &Virtqueue, bus: &mut Bus, head: u16, len: u32) -> Result<(), ()> {
+        let used_idx = bus.read(2, queue.device as Addr + 2)? as u16;
+        let slot = used_idx as u32 % queue.num;
+        let entry = queue.device as Addr + 4 + slot * 8;
+        bus.write(4, entry, head as u32)?;
+        bus.write(4, entry + 4, len)?;
+        bus.write(2, queue.device as Addr + 2, used_idx.wrapping_add(1) as u32)
+    }
+
+    /// Drains the available ring of `index`. Returns true if any buffer was used.
+    fn process_queue(&mut self, index: usize, bus: &mut Bus) -> bool {
+        let mut used = false;
+        loop {
+            let queue = &mut self.queues[index];
+            if !queue.ready || queue.num == 0 {
+                return used;
+            }
+            let (head, chain) = match Self::pop_chain(queue, bus) {
+                Ok(Some(popped)) => popped,
+                Ok(None) => return used,
+                Err(()) => {
+                    println!("virtio: malformed descriptor chain on queue {}", index);
+                    return used;
+                }
+            };
+            let Some(len) = self.backend.process(index, &chain, bus) else {
+                return used;
+            };
+            let queue = &mut self.queues[index];
+            queue.last_avail = queue.last_avail.wrapping_add(1);
+            if Self::push_used(queue, bus, head, len).is_err() {
+                println!("virtio: failed to update the used ring of queue {}", index);
+                return used;
+            }
+            used = true;
+        }
+    }
+}
+
+impl Device for VirtioMmio {
+    fn read(&mut self, size: u8, addr: Addr) -> u32 {
+        if addr >= CONFIG {
+            let mut value = 0;
+            for i in 0..size as u32 {
+                value |= (self.backend.config_read(addr - CONFIG + i) as u32) << (8 * i);
+            }
+            return value;
+        }
+        match addr {
+            MAGIC_VALUE => VIRTIO_MAGIC,
+            VERSION => 2,
+            DEVICE_ID => self.backend.device_id(),
+            VENDOR_ID => VIRTIO_VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => self.selected_queue().map_or(0, |_| QUEUE_SIZE_MAX),
+            QUEUE_READY => self.selected_queue().map_or(0, |queue| queue.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _size: u8, addr: Addr, value: u32) -> Result<(), ()> {
+        match addr {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => {
+                let shift = 32 * (self.driver_features_sel & 1);
+                self.driver_features = (self.driver_features & !(0xFFFF_FFFFu64 << shift)) | ((value as u64) << shift);
+            }
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.num = value.min(QUEUE_SIZE_MAX);
+                }
+            }
+            QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = value & 1 == 1;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                let high = addr & 0x4 != 0;
+                if let Some(queue) = self.selected_queue() {
+                    let field = match addr & !0x4 {
+                        QUEUE_DESC_LOW => &mut queue.desc,
+                        QUEUE_DRIVER_LOW => &mut queue.driver,
+                        _ => &mut queue.device,
+                    };
+                    *field = if high {
+                        (*field & 0xFFFF_FFFF) | ((value as u64) << 32)
+                    } else {
+                        (*field & !0xFFFF_FFFF) | value as u64
+                    };
+                }
+            }
+            QUEUE_NOTIFY => {
+                if (value as usize) < self.queues.len() && !self.notified.contains(&(value as usize)) {
+                    self.notified.push(value as usize);
+                }
+            }
+            INTERRUPT_ACK => {
+                let status = self.interrupt_status & !value;
+                self.set_interrupt(status);
+            }
+            STATUS => {
+                if value == 0 {
+                    self.reset_device();
+                } else if value & STATUS_FEATURES_OK != 0 && self.status & STATUS_FEATURES_OK == 0
+                    && (self.driver_features & !self.device_features() != 0 || self.driver_features & VIRTIO_F_VERSION_1 == 0) {
+                    // Legacy drivers or unknown features: refuse FEATURES_OK.
+                    self.status = value & !STATUS_FEATURES_OK;
+                } else {
+                    self.status = value;
+                }
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.backend.tick(cycles);
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.backend.set_muted(muted);
+    }
+
+    fn replay_hazard(&self) -> Option<String> {
+        self.backend.replay_hazard()
+    }
+
+    fn reset(&mut self) {
+        self.reset_device();
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.u32(self.device_features_sel);
+        state.u64(self.driver_features);
+        state.u32(self.driver_features_sel);
+        state.u32(self.queue_sel);
+        for queue in &self.queues {
+            state.u32(queue.num);
+            state.bool(queue.ready);
+            state.u64(queue.desc);
+            state.u64(queue.driver);
+            state.u64(queue.device);
+            state.u32(queue.last_avail as u32);
+        }
+        state.u32(self.interrupt_status);
+        state.u32(self.status);
+        state.u32(self.notified.len() as u32);
+        for &index in &self.notified {
+            state.u32(index as u32);
+        }
+        self.backend.save(&mut state);
+        state.finish()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), ()> {
+        let mut state = StateReader::new(state);
+        self.device_features_sel = state.u32()?;
+        self.driver_features = state.u64()?;
+        self.driver_features_sel = state.u32()?;
+        self.queue_sel = state.u32()?;
+        for queue in &mut self.queues {
+            queue.num = state.u32()?;
+            queue.ready = state.bool()?;
+            queue.desc = state.u64()?;
+            queue.driver = state.u64()?;
+            queue.device = state.u64()?;
+            queue.last_avail = state.u32()? as u16;
+        }
+        let interrupt_status = state.u32()?;
+        self.set_interrupt(interrupt_status);
+        self.status = state.u32()?;
+        self.notified = (0..state.u32()?).map(|_| state.u32().map(|index| index as usize)).collect::<Result<_, _>>()?;
+        if self.notified.iter().any(|&index| index >= self.queues.len()) {
+            return Err(());
+        }
+        self.backend.restore(&mut state)?;
+        state.finish()
+    }
+
+    fn dma_pending(&self) -> bool {
+        !self.notified.is_empty()
+            || (self.backend.has_input()
+                && self.backend.input_queue().is_some_and(|index| self.queues[index].ready))
+    }
+
+    fn dma(&mut self, bus: &mut Bus) {
+        let mut queues = std::mem::take(&mut self.notified);
+        if let Some(index) = self.backend.input_queue() && self.backend.has_input() && !queues.contains(&index) {
+            queues.push(index);
+        }
+        let mut used = false;
+        for index in queues {
+            used |= self.process_queue(index, bus);
+        }
+        if used {
+            let status = self.interrupt_status | INTERRUPT_USED_BUFFER;
+            self.set_interrupt(status);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::bus::{Addr, Bus, StateReader, StateWriter};
+
+use super::disk::{DiskImage, BLOCK_SIZE};
+use super::virtio::{DescriptorChain, VirtioBackend, MAX_REQUEST_SIZE};
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// virtio-blk backend over a host disk image.
+pub struct VirtioBlk {
+    image: DiskImage,
+}
+impl VirtioBlk {
+    pub fn new(image_path: &str, persist: bool) -> std::io::Result<Self> {
+        let image = DiskImage::open(image_path, persist)?;
+        println!("Initialized virtio-blk with {} sectors from {}", image.blocks, image_path);
+        Ok(Self { image })
+    }
+
+    /// Executes one request, returning its status and the data to hand back.
+    fn execute(&mut self, request_type: u32, sector: u64, data: &[u8], writable: usize) -> (u8, Vec<u8>) {
+        match request_type {
+            VIRTIO_BLK_T_IN => {
+                // The last writable byte is the status.
+                let count = writable.saturating_sub(1) / BLOCK_SIZE;
+                if count * BLOCK_SIZE > MAX_REQUEST_SIZE {
+                    return (VIRTIO_BLK_S_IOERR, Vec::new());
+                }
+                let mut out = Vec::with_capacity(count * BLOCK_SIZE);
+                for lba in sector..sector + count as u64 {
+                    match self.image.read_block(lba) {
+                        Ok(block) if lba < self.image.blocks => out.extend(block),
+                        _ => return (VIRTIO_BLK_S_IOERR, out),
+                    }
+                }
+                (VIRTIO_BLK_S_OK, out)
+            }
+            VIRTIO_BLK_T_OUT => {
+                for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+                    let lba = sector + i as u64;
+                    if lba >= self.image.blocks || block.len() != BLOCK_SIZE || self.image.write_block(lba, block).is_err() {
+                        return (VIRTIO_BLK_S_IOERR, Vec::new());
+                    }
+                }
+                (VIRTIO_BLK_S_OK, Vec::new())
+            }
+            VIRTIO_BLK_T_FLUSH => match self.image.flush() {
+                Ok(()) => (VIRTIO_BLK_S_OK, Vec::new()),
+                Err(_) => (VIRTIO_BLK_S_IOERR, Vec::new()),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = b"riscy-virtio-blk".to_vec();
+                id.resize(20, 0);
+                (VIRTIO_BLK_S_OK, id)
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, Vec::new()),
+        }
+    }
+}
+
+impl VirtioBackend for VirtioBlk {
+    fn device_id(&self) -> u32 { VIRTIO_ID_BLOCK }
+    fn features(&self) -> u64 { VIRTIO_BLK_F_FLUSH }
+    fn num_queues(&self) -> usize { 1 }
+    fn save(&self, state: &mut StateWriter) { self.image.save(state) }
+    fn restore(&mut self, state: &mut StateReader) -> Result<(), ()> { self.image.restore(state) }
+    fn replay_hazard(&self) -> Option<String> { self.image.replay_hazard() }
+
+    fn config_read(&mut self, offset: Addr) -> u8 {
+        // capacity (le64, in 512 byte sectors) is the only field we offer.
+        match offset {
+            0..=7 => (self.image.blocks >> (8 * offset)) as u8,
+            _ => 0,
+        }
+    }
+
+    fn process(&mut self, _queue: usize, chain: &DescriptorChain, bus: &mut Bus) -> Option<u32> {
+        let writable = chain.writable_len();
+        let (status, data) = match chain.read_all(bus) {
+            Ok(readable) if readable.len() >= 16 && writable >= 1 => {
+                let request_type = u32::from_le_bytes(readable[0..4].try_into().unwrap());
+                let sector = u64::from_le_bytes(readable[8..16].try_into().unwrap());
+                self.execute(request_type, sector, &readable[16..], writable)
+            }
+            _ => (VIRTIO_BLK_S_IOERR, Vec::new()),
+        };
+        // Without a writable descriptor there is nowhere to put the status.
+        if writable == 0 {
+            return Some(0);
+        }
+        let data = &data[..data.len().min(writable - 1)];
+        let written = chain.write_at(bus, 0, data).unwrap_or(0);
+        let _ = chain.write_at(bus, writable - 1, &[status]);
+        Some(written as u32 + 1)
+    }
+}
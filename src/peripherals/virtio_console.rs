@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+
+use crate::bus::{Addr, Bus, StateReader, StateWriter};
+
+use super::input::RecordedInput;
+use super::virtio::{DescriptorChain, VirtioBackend};
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+/// virtio-console backend with a single port. Output goes to the GUI log
+/// like the UART, input optionally comes from the host's stdin.
+pub struct VirtioConsole {
+    tx: mpsc::Sender<char>,
+    input: RecordedInput,
+    muted: bool,
+}
+impl VirtioConsole {
+    pub fn new(tx: mpsc::Sender<char>, stdin: bool) -> Self {
+        let input = if stdin { host_stdin() } else { Arc::new(Mutex::new(VecDeque::new())) };
+        Self { tx, input: RecordedInput::new(input), muted: false }
+    }
+}
+
+/// Bytes read from the host's stdin and not consumed yet. One reader thread
+/// serves the whole process, so consoles built again on reload share it
+/// instead of each leaving a thread blocked on stdin.
+fn host_stdin() -> Arc<Mutex<VecDeque<u8>>> {
+    static STDIN: OnceLock<Arc<Mutex<VecDeque<u8>>>> = OnceLock::new();
+    Arc::clone(STDIN.get_or_init(|| {
+        let input = Arc::new(Mutex::new(VecDeque::new()));
+        let reader = Arc::clone(&input);
+        thread::spawn(move || {
+            for byte in std::io::stdin().lock().bytes() {
+                let Ok(byte) = byte else { break };
+                reader.lock().unwrap().push_back(byte);
+            }
+        });
+        input
+    }))
+}
+
+impl VirtioBackend for VirtioConsole {
+    fn device_id(&self) -> u32 { VIRTIO_ID_CONSOLE }
+    fn features(&self) -> u64 { 0 }
+    fn num_queues(&self) -> usize { 2 }
+
+    fn config_read(&mut self, _offset: Addr) -> u8 { 0 }
+
+    fn process(&mut self, queue: usize, chain: &DescriptorChain, bus: &mut Bus) -> Option<u32> {
+        match queue {
+            TRANSMITQ => {
+                if let Ok(data) = chain.read_all(bus)
+                    && !self.muted {
+                    for byte in data {
+                        let _ = self.tx.send(byte as char);
+                    }
+                }
+                Some(0)
+            }
+            RECEIVEQ => {
+                let writable = chain.writable_len();
+                if writable == 0 {
+                    return Some(0); // nothing to fill, don't hold the queue
+                }
+                let data = self.input.peek(writable);
+                if data.is_empty() {
+                    return None; // keep the buffer until there is input
+                }
+                let written = chain.write_at(bus, 0, &data).unwrap_or(0);
+                self.input.consume(written);
+                Some(written as u32)
+            }
+            _ => Some(0),
+        }
+    }
+
+    fn has_input(&self) -> bool {
+        self.input.has_input()
+    }
+
+    fn input_queue(&self) -> Option<usize> { Some(RECEIVEQ) }
+
+    fn tick(&mut self, cycles: u64) {
+        self.input.tick(cycles);
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        self.input.save(state);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<(), ()> {
+        self.input.restore(state)
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+}
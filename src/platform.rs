@@ -0,0 +1,364 @@
+use std::fs;
+use std::sync::{mpsc, Arc, RwLock};
+
+use serde::Deserialize;
+
+use crate::bus::{Addr, Bus};
+use crate::constants::*;
+use crate::loader::parse_hex_file;
+use crate::peripherals::{Ram, Rom, ScreenCsr, ScreenHandle, SdCard, SpiController, TextMode, UartNs16550a};
+use crate::peripherals::{DmaController, VirtioBlk, VirtioConsole, VirtioMmio};
+use crate::peripherals::{PluginDevice, PluginLibrary};
+use crate::peripherals::{Clint, Plic};
+
+/// Board description loaded from `--platform board.toml`.
+///
+/// Describes everything that used to be hardcoded in `main.rs`: the RAM
+/// window, the reset vector, the ISA string and every MMIO device on the bus.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Platform {
+    #[serde(default = "default_name")]
+    pub name: String,
+    #[serde(default = "default_isa")]
+    pub isa: String,
+    #[serde(default = "default_reset_vector")]
+    pub reset_vector: u32,
+    /// Core clock, reported as the timebase and UART clock. Devices are
+    /// ticked with the cycles elapsed every `DEVICE_QUANTUM` cycles, so
+    /// they see time in steps of 1000 cycles, the CLINT's `mtime` too.
+    #[serde(default = "default_clock_hz")]
+    pub clock_hz: u32,
+    pub ram: RamConfig,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RamConfig {
+    #[serde(default = "default_ram_base")]
+    pub base: Addr,
+    pub size: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Device model, e.g. `uart_ns16550a`, `screen_csr`, `vga_text_mode`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Instance name, defaults to the device type.
+    pub name: Option<String>,
+    pub base: Addr,
+    pub size: u32,
+    /// Interrupt line the device is wired to, if any.
+    pub irq: Option<u32>,
+    /// Model specific parameters.
+    #[serde(default)]
+    pub params: toml::Table,
+    /// Plugin library already loaded to read its window size (`--device`),
+    /// the device is created from it instead of loading it again.
+    #[serde(skip)]
+    pub plugin: Option<Arc<PluginLibrary>>,
+}
+
+/// Boot chain requested on the command line with `--bootrom`/`--stage2`.
+pub struct BootChain<'a> {
+    pub bootrom: &'a str,
+    pub stage2: Option<&'a str>,
+    /// Application the bootloader copies into RAM.
+    pub app: &'a str,
+}
+
+const APP_IMAGE_NAME: &str = "app_image";
+
+fn default_name() -> String { "RISC-Y".to_string() }
+fn default_isa() -> String { "rv32i".to_string() }
+fn default_reset_vector() -> u32 { RAM_BASE }
+fn default_clock_hz() -> u32 { BOARD_CLOCK_HZ }
+fn default_ram_base() -> Addr { RAM_BASE }
+
+/// Parses a decimal or 0x prefixed number, underscores allowed.
+pub fn parse_u32(text: &str) -> Result<u32, String> {
+    parse_u64(text)?.try_into().map_err(|_| format!("'{}' does not fit in 32 bits", text))
+}
+
+pub fn parse_u64(text: &str) -> Result<u64, String> {
+    let digits: String = text.chars().filter(|&c| c != '_').collect();
+    match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }.map_err(|_| format!("'{}' is not a valid number", text))
+}
+
+impl DeviceConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.kind)
+    }
+
+    fn check_params(&self, allowed: &[&str]) -> Result<(), String> {
+        match self.params.keys().find(|key| !allowed.contains(&key.as_str())) {
+            Some(key) => Err(format!("Device '{}' does not take a '{}' parameter", self.name(), key)),
+            None => Ok(()),
+        }
+    }
+
+    fn param_str(&self, key: &str) -> Result<&str, String> {
+        self.params.get(key)
+            .and_then(|value| value.as_str())
+            .ok_or_else(|| format!("Device '{}' needs a '{}' string parameter", self.name(), key))
+    }
+
+    fn param_bool(&self, key: &str, default: bool) -> Result<bool, String> {
+        match self.params.get(key) {
+            None => Ok(default),
+            Some(value) => value.as_bool()
+                .ok_or_else(|| format!("Parameter '{}' of '{}' must be a boolean", key, self.name())),
+        }
+    }
+}
+
+/// Everything the rest of the emulator needs from a freshly built board.
+pub struct Board {
+    pub bus: Bus,
+    pub textmode_frame: ScreenHandle,
+}
+
+impl Platform {
+    pub fn from_file(file_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(file_path)?;
+        let platform: Platform = toml::from_str(&contents)?;
+        platform.validate()?;
+        Ok(platform)
+    }
+
+    /// The RISC-Y memory map the emulator has always used.
+    pub fn risc_y() -> Self {
+        let device = |kind: &str, base: Addr, size: u32| DeviceConfig {
+            kind: kind.to_string(),
+            name: None,
+            base,
+            size,
+            irq: None,
+            params: toml::Table::new(),
+            plugin: None,
+        };
+        Self {
+            name: default_name(),
+            isa: default_isa(),
+            reset_vector: RAM_BASE,
+            clock_hz: BOARD_CLOCK_HZ,
+            ram: RamConfig { base: RAM_BASE, size: 1024 * 4096 }, // 4MB RAM
+            devices: vec![
+                device("screen_csr", SCREEN_CSR_ADDR, 8),
+                DeviceConfig { irq: Some(1), ..device("uart_ns16550a", UART0_BASE, 0x0000_000F) },
+                device("vga_text_mode", VGA_TEXT_MODE_BASE, 1216 * 2),
+            ],
+        }
+    }
+
+    /// Maps the boot ROM, the optional second stage and the application
+    /// image, and moves the reset vector to the boot ROM.
+    pub fn add_boot_chain(&mut self, chain: &BootChain) -> Result<(), Box<dyn std::error::Error>> {
+        let app_size = (parse_hex_file(chain.app)?.len() * 4).max(4) as u32;
+        let rom = |name: &str, image: &str, base: Addr, size: u32| {
+            let mut params = toml::Table::new();
+            params.insert("image".to_string(), toml::Value::String(image.to_string()));
+            DeviceConfig { kind: "rom".to_string(), name: Some(name.to_string()), base, size, irq: None, params, plugin: None }
+        };
+        self.devices.push(rom("bootrom", chain.bootrom, BOOTROM_BASE, STAGE2_BASE - BOOTROM_BASE));
+        if let Some(stage2) = chain.stage2 {
+            self.devices.push(rom("stage2", stage2, STAGE2_BASE, APP_IMAGE_BASE - STAGE2_BASE));
+        }
+        self.devices.push(rom(APP_IMAGE_NAME, chain.app, APP_IMAGE_BASE, app_size));
+        self.reset_vector = BOOTROM_BASE;
+        self.validate()?;
+        Ok(())
+    }
+
+    /// Maps a device plugin given on the command line as
+    /// `path@base[,irq=N]`. The window size comes from the plugin itself.
+    pub fn add_plugin(&mut self, spec: &str) -> Result<(), String> {
+        let (library, rest) = spec.rsplit_once('@')
+            .ok_or_else(|| format!("Expected <library>@<base>[,irq=N], got '{}'", spec))?;
+        let mut fields = rest.split(',');
+        let base = parse_u32(fields.next().unwrap_or_default())?;
+        let mut irq = None;
+        for field in fields {
+            match field.split_once('=') {
+                Some(("irq", value)) => irq = Some(parse_u32(value)?),
+                _ => return Err(format!("Unknown plugin option '{}'", field)),
+            }
+        }
+        let mut params = toml::Table::new();
+        params.insert("library".to_string(), toml::Value::String(library.to_string()));
+        let name = std::path::Path::new(library).file_stem().map(|stem| stem.to_string_lossy().into_owned());
+        let plugin = PluginLibrary::open(library)?;
+        let size = plugin.window_size();
+        self.devices.push(DeviceConfig { kind: "plugin".to_string(), name, base, size, irq, params, plugin: Some(plugin) });
+        self.validate()
+    }
+
+    /// Base and size of the application image the bootloader copies into RAM.
+    pub fn app_image(&self) -> Option<(Addr, u32)> {
+        self.devices.iter()
+            .find(|dev| dev.kind == "rom" && dev.name() == APP_IMAGE_NAME)
+            .map(|dev| (dev.base, dev.size))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let isa = self.isa.to_ascii_lowercase();
+        if !isa.starts_with("rv32i") {
+            return Err(format!("Unsupported ISA '{}', only rv32i is emulated", self.isa));
+        }
+        if !self.reset_vector.is_multiple_of(4) {
+            return Err(format!("Reset vector 0x{:08X} is not aligned", self.reset_vector));
+        }
+        let mut windows: Vec<(&str, u64, u64)> = vec![
+            ("ram", self.ram.base as u64, self.ram.size as u64),
+            ("clint", CLINT_BASE as u64, CLINT_SIZE as u64),
+            ("plic", PLIC_BASE as u64, PLIC_SIZE as u64),
+        ];
+        for dev in &self.devices {
+            windows.push((dev.name(), dev.base as u64, dev.size as u64));
+        }
+        for dev in &self.devices {
+            // Lines are PLIC sources, and source 0 means no interrupt.
+            if dev.irq.is_some_and(|irq| !(1..32).contains(&irq)) {
+                return Err(format!("Device '{}' uses IRQ line {}, only 1-31 exist", dev.name(), dev.irq.unwrap()));
+            }
+            // The GUI reads the enable bit and the frame buffer address at +4.
+            if dev.kind == "screen_csr" && dev.size < 8 {
+                return Err(format!("Device '{}' needs a window of at least 8 bytes", dev.name()));
+            }
+        }
+        for (name, base, size) in &windows {
+            if *size == 0 || base + size > 1 << 32 {
+                return Err(format!("Device '{}' has an invalid window 0x{:08X}+0x{:X}", name, base, size));
+            }
+        }
+        for (i, (name_a, base_a, size_a)) in windows.iter().enumerate() {
+            for (name_b, base_b, size_b) in &windows[i + 1..] {
+                if *base_a < base_b + size_b && *base_b < base_a + size_a {
+                    return Err(format!("Devices '{}' and '{}' overlap", name_a, name_b));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn print_memory_map(&self) {
+        println!("Platform: {} ({}), reset vector 0x{:08X}, {} MHz", self.name, self.isa, self.reset_vector, self.clock_hz as f64 / 1e6);
+        for line in self.memory_map() {
+            println!("  {}", line);
+        }
+    }
+
+    /// One `base-end name [irq N]` line per mapping, RAM and the interrupt
+    /// controllers first.
+    pub fn memory_map(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("0x{:08X}-0x{:08X} ram", self.ram.base, self.ram.base + (self.ram.size - 1)),
+            format!("0x{:08X}-0x{:08X} clint", CLINT_BASE, CLINT_BASE + (CLINT_SIZE - 1)),
+            format!("0x{:08X}-0x{:08X} plic", PLIC_BASE, PLIC_BASE + (PLIC_SIZE - 1)),
+        ];
+        for dev in &self.devices {
+            let irq = dev.irq.map(|irq| format!(" irq {}", irq)).unwrap_or_default();
+            lines.push(format!("0x{:08X}-0x{:08X} {}{}", dev.base, dev.base + (dev.size - 1), dev.name(), irq));
+        }
+        lines
+    }
+
+    pub fn find_device(&self, kind: &str) -> Option<&DeviceConfig> {
+        self.devices.iter().find(|dev| dev.kind == kind)
+    }
+
+    /// Base of the screen CSR block, used by the frame requests of the GUI.
+    pub fn screen_csr_base(&self) -> Option<Addr> {
+        self.find_device("screen_csr").map(|dev| dev.base)
+    }
+
+    /// Instantiates every device and maps it on a new `Bus`.
+    pub fn build(&self, uart_tx: mpsc::Sender<char>) -> Result<Board, String> {
+        let mut bus = Bus::new();
+        let mut textmode_frame: Option<ScreenHandle> = None;
+        // Consoles share the GUI log with the UART.
+        let console_tx = uart_tx.clone();
+        let mut uart_tx = Some(uart_tx);
+
+        let ram = Ram::new(self.ram.size as usize);
+        bus.add_region(self.ram.base, ram.size(), Box::new(ram));
+        bus.add_region(CLINT_BASE, CLINT_SIZE, Box::new(Clint::new()));
+        let plic = Plic::new(bus.irq_controller());
+        bus.add_region(PLIC_BASE, PLIC_SIZE, Box::new(plic));
+        for dev in &self.devices {
+            match dev.kind.as_str() {
+                "rom" => dev.check_params(&["image"])?,
+                "spi_sd" | "virtio_blk" => dev.check_params(&["image", "persist"])?,
+                "virtio_console" => dev.check_params(&["stdin"])?,
+                "plugin" => dev.check_params(&["library", "args"])?,
+                _ => dev.check_params(&[])?,
+            }
+            match dev.kind.as_str() {
+                "screen_csr" => {
+                    bus.add_region(dev.base, dev.size, Box::new(ScreenCsr::new()));
+                }
+                "uart_ns16550a" => {
+                    let tx = uart_tx.take().ok_or("Only one UART is supported by the GUI")?;
+                    bus.add_region(dev.base, dev.size, Box::new(UartNs16550a::new(tx)));
+                }
+                "vga_text_mode" => {
+                    let vga_text_mode = TextMode::new();
+                    textmode_frame = Some(vga_text_mode.handle());
+                    bus.add_region(dev.base, dev.size, Box::new(vga_text_mode));
+                }
+                "rom" => {
+                    let image = dev.param_str("image")?;
+                    let words = parse_hex_file(image).map_err(|e| format!("Failed to load '{}': {}", image, e))?;
+                    if words.len() * 4 > dev.size as usize {
+                        return Err(format!("Image '{}' does not fit in '{}'", image, dev.name()));
+                    }
+                    bus.add_region(dev.base, dev.size, Box::new(Rom::new(&words)));
+                }
+                "spi_sd" => {
+                    let image = dev.param_str("image")?;
+                    let persist = dev.param_bool("persist", false)?;
+                    let card = SdCard::new(image, persist).map_err(|e| format!("Failed to open '{}': {}", image, e))?;
+                    bus.add_region(dev.base, dev.size, Box::new(SpiController::new(Box::new(card))));
+                }
+                "virtio_blk" => {
+                    let image = dev.param_str("image")?;
+                    let persist = dev.param_bool("persist", false)?;
+                    let blk = VirtioBlk::new(image, persist).map_err(|e| format!("Failed to open '{}': {}", image, e))?;
+                    let irq = dev.irq.map(|irq| bus.irq_line(irq));
+                    bus.add_region(dev.base, dev.size, Box::new(VirtioMmio::new(Box::new(blk), irq)));
+                }
+                "virtio_console" => {
+                    let console = VirtioConsole::new(console_tx.clone(), dev.param_bool("stdin", false)?);
+                    let irq = dev.irq.map(|irq| bus.irq_line(irq));
+                    bus.add_region(dev.base, dev.size, Box::new(VirtioMmio::new(Box::new(console), irq)));
+                }
+                "dma" => {
+                    let irq = dev.irq.map(|irq| bus.irq_line(irq));
+                    bus.add_region(dev.base, dev.size, Box::new(DmaController::new(irq)));
+                }
+                "plugin" => {
+                    let args = if dev.params.contains_key("args") { dev.param_str("args")? } else { "" };
+                    let irq = dev.irq.map(|irq| bus.irq_line(irq));
+                    let library = match &dev.plugin {
+                        Some(library) => Arc::clone(library),
+                        None => PluginLibrary::open(dev.param_str("library")?)?,
+                    };
+                    let plugin = PluginDevice::new(library, args, irq)?;
+                    bus.add_region(dev.base, dev.size, Box::new(plugin));
+                }
+                other => return Err(format!("Unknown device type '{}' for '{}'", other, dev.name())),
+            }
+        }
+
+        let textmode_frame = textmode_frame
+            .unwrap_or_else(|| Arc::new(RwLock::new(vec![255; SCREEN_WIDTH * SCREEN_HEIGHT * 4])));
+        Ok(Board { bus, textmode_frame })
+    }
+}
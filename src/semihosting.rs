@@ -0,0 +1,251 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bus::{Addr, Bus};
+
+// RISC-V semihosting uses the ARM semihosting operation numbers.
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_READC: u32 = 0x07;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_SEEK: u32 = 0x0A;
+const SYS_FLEN: u32 = 0x0C;
+const SYS_REMOVE: u32 = 0x0E;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_HEAPINFO: u32 = 0x16;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x2_0026;
+
+// newlib errno values reported back to the guest.
+const EBADF: u32 = 9;
+const EFAULT: u32 = 14;
+const EINVAL: u32 = 22;
+const EACCES: u32 = 13;
+
+/// Largest SYS_READ/SYS_WRITE transfer done at once. Lengths come from the
+/// program, so they are clamped before sizing host buffers, and the guest
+/// sees a short transfer and calls again.
+const MAX_TRANSFER: u32 = 1 << 20;
+
+/// `slli x0, x0, 0x1f` and `srai x0, x0, 7` around the `ebreak`.
+pub const SEMIHOSTING_ENTRY: u32 = 0x01F0_1013;
+pub const SEMIHOSTING_EXIT: u32 = 0x4070_5013;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Host side of the semihosting ABI. Files are confined to `root`.
+pub struct Semihosting {
+    root: PathBuf,
+    cmdline: String,
+    handles: Vec<Option<Handle>>,
+    errno: u32,
+    start: Instant,
+}
+
+impl Semihosting {
+    pub fn new(root: &str, cmdline: String) -> Self {
+        Self {
+            root: PathBuf::from(root),
+            cmdline,
+            handles: Vec::new(),
+            errno: 0,
+            start: Instant::now(),
+        }
+    }
+
+    /// Executes operation `op` with parameter `param` (usually a pointer to
+    /// a block of words). Returns the value for a0, and the exit code once
+    /// the guest asked to stop.
+    pub fn call(&mut self, op: u32, param: u32, bus: &mut Bus) -> (u32, Option<i32>) {
+        match self.dispatch(op, param, bus) {
+            Ok(result) => result,
+            Err(errno) => {
+                self.errno = errno;
+                (u32::MAX, None)
+            }
+        }
+    }
+
+    fn dispatch(&mut self, op: u32, param: u32, bus: &mut Bus) -> Result<(u32, Option<i32>), u32> {
+        let arg = |bus: &mut Bus, i: u32| bus.read(4, param.wrapping_add(4 * i)).map_err(|_| EFAULT);
+        let result = match op {
+            SYS_OPEN => {
+                let (name, mode, len) = (arg(bus, 0)?, arg(bus, 1)?, arg(bus, 2)?);
+                let name = read_string(bus, name, len)?;
+                self.open(&name, mode)?
+            }
+            SYS_CLOSE => {
+                let slot = self.handles.get_mut(arg(bus, 0)? as usize).ok_or(EBADF)?;
+                slot.take().ok_or(EBADF)?;
+                0
+            }
+            SYS_WRITEC => {
+                let c = bus.read(1, param).map_err(|_| EFAULT)? as u8;
+                let _ = io::stdout().write_all(&[c]);
+                0
+            }
+            SYS_WRITE0 => {
+                let text = read_string(bus, param, u32::MAX)?;
+                print!("{}", text);
+                0
+            }
+            SYS_WRITE => {
+                let (handle, buf, len) = (arg(bus, 0)?, arg(bus, 1)?, arg(bus, 2)?);
+                let mut data = vec![0u8; len.min(MAX_TRANSFER) as usize];
+                bus.read_bytes(buf, &mut data).map_err(|_| EFAULT)?;
+                let written = match self.handle(handle)? {
+                    Handle::Stdout => io::stdout().write(&data),
+                    Handle::Stderr => io::stderr().write(&data),
+                    Handle::File(file) => file.write(&data),
+                    Handle::Stdin => return Err(EBADF),
+                }.map_err(errno)?;
+                len - written as u32 // bytes not written
+            }
+            SYS_READ => {
+                let (handle, buf, len) = (arg(bus, 0)?, arg(bus, 1)?, arg(bus, 2)?);
+                let mut data = vec![0u8; len.min(MAX_TRANSFER) as usize];
+                let read = match self.handle(handle)? {
+                    Handle::Stdin => io::stdin().read(&mut data),
+                    Handle::File(file) => file.read(&mut data),
+                    _ => return Err(EBADF),
+                }.map_err(errno)?;
+                bus.write_bytes(buf, &data[..read]).map_err(|_| EFAULT)?;
+                len - read as u32 // bytes not read
+            }
+            SYS_READC => {
+                let mut c = [0u8; 1];
+                io::stdin().read_exact(&mut c).map_err(errno)?;
+                c[0] as u32
+            }
+            SYS_ISTTY => match self.handle(arg(bus, 0)?)? {
+                Handle::File(_) => 0,
+                _ => 1,
+            },
+            SYS_SEEK => {
+                let position = arg(bus, 1)?;
+                match self.handle(arg(bus, 0)?)? {
+                    Handle::File(file) => file.seek(SeekFrom::Start(position as u64)).map_err(errno)?,
+                    _ => return Err(EBADF),
+                };
+                0
+            }
+            SYS_FLEN => match self.handle(arg(bus, 0)?)? {
+                Handle::File(file) => file.metadata().map_err(errno)?.len() as u32,
+                _ => return Err(EBADF),
+            },
+            SYS_REMOVE => {
+                let (name, len) = (arg(bus, 0)?, arg(bus, 1)?);
+                let name = read_string(bus, name, len)?;
+                fs::remove_file(self.sandboxed(&name)?).map_err(errno)?;
+                0
+            }
+            SYS_CLOCK => (self.start.elapsed().as_millis() / 10) as u32, // centiseconds
+            SYS_TIME => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs() as u32),
+            SYS_ERRNO => self.errno,
+            SYS_GET_CMDLINE => {
+                let (buf, len) = (arg(bus, 0)?, arg(bus, 1)?);
+                let mut cmdline = self.cmdline.as_bytes().to_vec();
+                if cmdline.len() + 1 > len as usize {
+                    return Err(EINVAL);
+                }
+                cmdline.push(0);
+                bus.write_bytes(buf, &cmdline).map_err(|_| EFAULT)?;
+                bus.write(4, param.wrapping_add(4), (cmdline.len() - 1) as u32).map_err(|_| EFAULT)?;
+                0
+            }
+            SYS_HEAPINFO => {
+                // Let the C library fall back to its linker script symbols.
+                let block = arg(bus, 0)?;
+                bus.write_bytes(block, &[0u8; 16]).map_err(|_| EFAULT)?;
+                0
+            }
+            SYS_EXIT => {
+                // On RV32 the parameter is the reason code itself.
+                let code = if param == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 };
+                return Ok((0, Some(code)));
+            }
+            SYS_EXIT_EXTENDED => {
+                let (reason, subcode) = (arg(bus, 0)?, arg(bus, 1)?);
+                let code = if reason == ADP_STOPPED_APPLICATION_EXIT { subcode as i32 } else { 1 };
+                return Ok((0, Some(code)));
+            }
+            _ => {
+                println!("Semihosting: unsupported operation 0x{:02X}", op);
+                return Err(EINVAL);
+            }
+        };
+        Ok((result, None))
+    }
+
+    fn handle(&mut self, handle: u32) -> Result<&mut Handle, u32> {
+        self.handles.get_mut(handle as usize).and_then(|slot| slot.as_mut()).ok_or(EBADF)
+    }
+
+    fn open(&mut self, name: &str, mode: u32) -> Result<u32, u32> {
+        let handle = if name == ":tt" {
+            // fopen("r") is stdin, "w" stdout and "a" stderr
+            match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            let path = self.sandboxed(name)?;
+            let mut options = OpenOptions::new();
+            match mode / 4 {
+                0 => options.read(true).write(mode & 2 != 0),
+                1 => options.write(true).create(true).truncate(true).read(mode & 2 != 0),
+                2 => options.append(true).create(true).read(mode & 2 != 0),
+                _ => return Err(EINVAL),
+            };
+            Handle::File(options.open(path).map_err(errno)?)
+        };
+        let slot = self.handles.iter().position(|slot| slot.is_none()).unwrap_or(self.handles.len());
+        if slot == self.handles.len() {
+            self.handles.push(None);
+        }
+        self.handles[slot] = Some(handle);
+        Ok(slot as u32)
+    }
+
+    /// Resolves a guest path below the sandbox root, refusing to escape it.
+    fn sandboxed(&self, name: &str) -> Result<PathBuf, u32> {
+        let path = Path::new(name);
+        if path.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Err(EACCES);
+        }
+        Ok(self.root.join(path))
+    }
+}
+
+fn read_string(bus: &mut Bus, addr: Addr, len: u32) -> Result<String, u32> {
+    let mut bytes = Vec::new();
+    for i in 0..len {
+        let byte = bus.read(1, addr.wrapping_add(i)).map_err(|_| EFAULT)? as u8;
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn errno(error: io::Error) -> u32 {
+    error.raw_os_error().map_or(EINVAL, |code| code as u32)
+}
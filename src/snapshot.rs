@@ -0,0 +1,67 @@
+use std::error::Error;
+use std::fs;
+
+use crate::bus::{StateReader, StateWriter};
+
+const MAGIC: &[u8] = b"RISCYSNP";
+const VERSION: u32 = 2;
+
+/// Full machine state: the hart plus every device on the bus, in mapping
+/// order. Only loads back into a machine built from the same platform.
+pub struct Snapshot {
+    pub regs: [u32; 32],
+    pub pc: u32,
+    pub cycles: u64,
+    pub devices: Vec<Vec<u8>>,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(MAGIC);
+        state.u32(VERSION);
+        for &reg in &self.regs {
+            state.u32(reg);
+        }
+        state.u32(self.pc);
+        state.u64(self.cycles);
+        state.u32(self.devices.len() as u32);
+        for device in &self.devices {
+            state.bytes(device);
+        }
+        state.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        let corrupt = |_| "snapshot is truncated or corrupt".to_string();
+        let mut state = StateReader::new(data);
+        if state.bytes().map_err(corrupt)? != MAGIC {
+            return Err("not a snapshot file".to_string());
+        }
+        let version = state.u32().map_err(corrupt)?;
+        if version != VERSION {
+            return Err(format!("snapshot version {} is not supported", version));
+        }
+        let mut regs = [0u32; 32];
+        for reg in regs.iter_mut() {
+            *reg = state.u32().map_err(corrupt)?;
+        }
+        let pc = state.u32().map_err(corrupt)?;
+        let cycles = state.u64().map_err(corrupt)?;
+        let devices = (0..state.u32().map_err(corrupt)?)
+            .map(|_| state.bytes().map(<[u8]>::to_vec))
+            .collect::<Result<_, _>>()
+            .map_err(corrupt)?;
+        state.finish().map_err(corrupt)?;
+        Ok(Self { regs, pc, cycles, devices })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::from_bytes(&fs::read(path)?)?)
+    }
+}
@@ -0,0 +1,120 @@
+//! `--log-format spike`: the text Spike prints with `--log-commits`, one
+//! line per retired instruction:
+//! ```text
+//! core   0: 3 0x80000008 (0x00328503) x10 0xffffff80 mem 0x80000033
+//! core   0: 3 0x80000010 (0x00a28223) mem 0x80000034 0x80
+//! ```
+//! Register and CSR writes come first, then the load address, then the
+//! store address and value printed at the access width. Like Spike, writes
+//! to x0 are left out. Traps have no syntax of their own and aren't shown.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::types::{Event, EventType};
+
+pub const PRIV_USER: u8 = 0;
+pub const PRIV_MACHINE: u8 = 3;
+
+pub struct SpikeWriter<W: Write> {
+    out: W,
+    /// Privilege level printed on every line; the hart never changes it.
+    privilege: u8,
+}
+
+impl SpikeWriter<BufWriter<File>> {
+    pub fn create(path: &str, privilege: u8) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::with_capacity(64 * 1024, File::create(path)?), privilege))
+    }
+}
+
+impl<W: Write> SpikeWriter<W> {
+    pub fn new(out: W, privilege: u8) -> Self {
+        Self { out, privilege }
+    }
+
+    /// Writes a batch from `Cpu::run`, which always ends on an instruction
+    /// boundary.
+    pub fn write(&mut self, events: &[Event]) -> io::Result<()> {
+        for effects in events.chunk_by(|a, b| a.cycle == b.cycle) {
+            self.write_instruction(effects)?;
+        }
+        Ok(())
+    }
+
+    fn write_instruction(&mut self, effects: &[Event]) -> io::Result<()> {
+        let first = &effects[0];
+        write!(self.out, "core   0: {} 0x{:08x} (0x{:08x})", self.privilege, first.pc, first.opcode)?;
+        for effect in effects {
+            match effect.instr_type {
+                EventType::RegWrite { reg, value } if reg != 0 => write!(self.out, " x{:<2} 0x{:08x}", reg, value)?,
+                EventType::FlowLink { register, .. } if register != 0 => {
+                    write!(self.out, " x{:<2} 0x{:08x}", register, first.pc.wrapping_add(4))?;
+                }
+                EventType::CsrWrite { csr, value } => write!(self.out, " c{}_{} 0x{:08x}", csr, csr_name(csr), value)?,
+                _ => {}
+            }
+        }
+        for effect in effects {
+            if let EventType::MemRead { addr, .. } = effect.instr_type {
+                write!(self.out, " mem 0x{:08x}", addr)?;
+            }
+        }
+        for effect in effects {
+            if let EventType::MemWrite { addr, value, size } = effect.instr_type {
+                write!(self.out, " mem 0x{:08x} 0x{:0width$x}", addr, value, width = size as usize * 2)?;
+            }
+        }
+        writeln!(self.out)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Spike's name for a CSR, as printed after its number.
+fn csr_name(csr: u16) -> &'static str {
+    match csr {
+        0x001 => "fflags",
+        0x002 => "frm",
+        0x003 => "fcsr",
+        0x300 => "mstatus",
+        0x301 => "misa",
+        0x302 => "medeleg",
+        0x303 => "mideleg",
+        0x304 => "mie",
+        0x305 => "mtvec",
+        0x306 => "mcounteren",
+        0x310 => "mstatush",
+        0x340 => "mscratch",
+        0x341 => "mepc",
+        0x342 => "mcause",
+        0x343 => "mtval",
+        0x344 => "mip",
+        0x100 => "sstatus",
+        0x104 => "sie",
+        0x105 => "stvec",
+        0x140 => "sscratch",
+        0x141 => "sepc",
+        0x142 => "scause",
+        0x143 => "stval",
+        0x144 => "sip",
+        0x180 => "satp",
+        0xB00 => "mcycle",
+        0xB02 => "minstret",
+        0xB80 => "mcycleh",
+        0xB82 => "minstreth",
+        0xC00 => "cycle",
+        0xC01 => "time",
+        0xC02 => "instret",
+        0xC80 => "cycleh",
+        0xC81 => "timeh",
+        0xC82 => "instreth",
+        0xF11 => "mvendorid",
+        0xF12 => "marchid",
+        0xF13 => "mimpid",
+        0xF14 => "mhartid",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+
+use crate::cpu::Cpu;
+use crate::snapshot::Snapshot;
+
+/// Reverse execution for the interactive stepper. Keeps periodic
+/// checkpoints of the machine and goes back in time by restoring the
+/// nearest one and re-executing forward, which is exact because devices
+/// tick on fixed cycles and host input is replayed from its log. Output
+/// devices are muted while re-executing, so the guest's console output
+/// isn't printed twice. Machines whose effects on the host can't be rolled
+/// back (semihosting, `--user`, persistent disk images, plugins without
+/// save/restore) are refused.
+pub struct TimeTravel {
+    checkpoints: VecDeque<Snapshot>,
+    interval: u64,
+    max_checkpoints: usize,
+}
+
+/// Where `reverse_continue` stopped.
+pub enum Hit {
+    Breakpoint(u32),
+    /// The instruction at `pc` changed the word at `addr` from `old` to `new`.
+    Watchpoint { addr: u32, old: u32, new: u32, pc: u32 },
+}
+
+impl TimeTravel {
+    pub fn new(interval: u64, max_checkpoints: usize) -> Self {
+        Self { checkpoints: VecDeque::new(), interval, max_checkpoints }
+    }
+
+    /// Takes a checkpoint if the last one is at least `interval` cycles old.
+    /// Call after every forward batch.
+    pub fn record(&mut self, cpu: &Cpu) {
+        let due = self.checkpoints.back()
+            .is_none_or(|last| cpu.cycles() >= last.cycles + self.interval);
+        if due {
+            if self.checkpoints.len() == self.max_checkpoints {
+                self.checkpoints.pop_front();
+            }
+            self.checkpoints.push_back(cpu.snapshot());
+        }
+    }
+
+    /// Drops every checkpoint, for when the machine is reset or replaced.
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+    }
+
+    /// Latest checkpoint at or before `cycle`.
+    fn checkpoint_before(&self, cycle: u64) -> Option<usize> {
+        self.checkpoints.iter().rposition(|checkpoint| checkpoint.cycles <= cycle)
+    }
+
+    fn check_replayable(cpu: &Cpu) -> Result<(), String> {
+        match cpu.replay_hazard() {
+            Some(reason) => Err(format!("Going back is not supported with {}", reason)),
+            None => Ok(()),
+        }
+    }
+
+    /// Restores checkpoint `index` and runs forward to `cycle`.
+    fn replay_to(&self, cpu: &mut Cpu, index: usize, cycle: u64) -> Result<(), String> {
+        let checkpoint = &self.checkpoints[index];
+        cpu.restore(checkpoint)?;
+        cpu.set_muted(true);
+        cpu.run(cycle - checkpoint.cycles, false, false);
+        cpu.set_muted(false);
+        Ok(())
+    }
+
+    /// Goes back `count` instructions.
+    pub fn reverse_step(&self, cpu: &mut Cpu, count: u64) -> Result<(), String> {
+        Self::check_replayable(cpu)?;
+        let target = cpu.cycles().saturating_sub(count);
+        let index = self.checkpoint_before(target)
+            .ok_or_else(|| format!("Cycle {} is older than the recorded history", target))?;
+        self.replay_to(cpu, index, target)
+    }
+
+    /// Goes back to the last instruction that hit one of `breakpoints`
+    /// (its PC was about to execute) or changed one of the words at
+    /// `watchpoints`, stopping before it runs. Returns what was hit, or
+    /// None after going back to the start of the recorded history.
+    pub fn reverse_continue(&self, cpu: &mut Cpu, breakpoints: &[u32], watchpoints: &[u32]) -> Result<Option<Hit>, String> {
+        Self::check_replayable(cpu)?;
+        let now = cpu.cycles();
+        let newest = self.checkpoint_before(now.saturating_sub(1))
+            .ok_or("There is no recorded history to go back to")?;
+        if !breakpoints.is_empty() || !watchpoints.is_empty() {
+            // Scan each checkpoint interval, newest first, for the last hit.
+            for index in (0..=newest).rev() {
+                let end = self.checkpoints.get(index + 1).map_or(now, |next| next.cycles.min(now));
+                self.replay_to(cpu, index, self.checkpoints[index].cycles)?;
+                let mut values: Vec<Option<u32>> = watchpoints.iter().map(|&addr| cpu.peek_mem(4, addr)).collect();
+                let mut hit = None;
+                cpu.set_muted(true);
+                while cpu.cycles() < end {
+                    let (cycle, pc) = (cpu.cycles(), cpu.pc());
+                    if breakpoints.contains(&pc) {
+                        hit = Some((cycle, Hit::Breakpoint(pc)));
+                    }
+                    cpu.run(1, false, false);
+                    for (&addr, value) in watchpoints.iter().zip(values.iter_mut()) {
+                        let new = cpu.peek_mem(4, addr);
+                        if new != *value {
+                            let (old, new) = (value.unwrap_or(0), new.unwrap_or(0));
+                            hit = Some((cycle, Hit::Watchpoint { addr, old, new, pc }));
+                            *value = Some(new);
+                        }
+                    }
+                }
+                cpu.set_muted(false);
+                if let Some((cycle, found)) = hit {
+                    self.replay_to(cpu, index, cycle)?;
+                    return Ok(Some(found));
+                }
+            }
+        }
+        let oldest = &self.checkpoints[0];
+        cpu.restore(oldest)?;
+        Ok(None)
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use crate::constants::*;
+use crate::disasm::disassemble_with;
+use crate::elf::Symbols;
+use crate::platform::parse_u64;
+use crate::types::{Event, EventType};
+
+/// Batches in flight between the CPU thread and the formatter. When the
+/// ring is full the CPU thread waits for it, so no line is ever dropped.
+const RING_SLOTS: usize = 64;
+
+/// Which instructions `-v` prints.
+#[derive(Clone, Default)]
+pub struct TraceFilter {
+    pub pc: Option<Range<u64>>,
+    pub cycles: Option<Range<u64>>,
+}
+
+impl TraceFilter {
+    /// Whether any of `count` instructions starting at `cycle` can be
+    /// printed. Outside the cycle window the CPU collects no events at all.
+    pub fn covers(&self, cycle: u64, count: u64) -> bool {
+        self.cycles.as_ref().is_none_or(|window| cycle < window.end && cycle + count > window.start)
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        self.pc.as_ref().is_none_or(|range| range.contains(&(event.pc as u64)))
+            && self.cycles.as_ref().is_none_or(|window| window.contains(&event.cycle))
+    }
+}
+
+/// `START..END` with either bound left out, for `--trace-pc` and
+/// `--trace-cycles`. The end is exclusive.
+pub fn parse_range(text: &str) -> Result<Range<u64>, String> {
+    let (start, end) = text.split_once("..").ok_or_else(|| format!("'{}' is not a range like 0x100..0x200", text))?;
+    let start = if start.is_empty() { 0 } else { parse_u64(start)? };
+    let end = if end.is_empty() { u64::MAX } else { parse_u64(end)? };
+    Ok(start..end)
+}
+
+/// Human readable execution trace (`-v`). The CPU thread only hands over
+/// the events it collects for `--log` anyway; disassembly and formatting
+/// happen on a thread of their own.
+pub struct Tracer {
+    filter: TraceFilter,
+    tx: mpsc::SyncSender<Vec<Event>>,
+    thread: JoinHandle<()>,
+}
+
+impl Tracer {
+    pub fn spawn(filter: TraceFilter, symbols: Symbols) -> Self {
+        let (tx, rx) = mpsc::sync_channel(RING_SLOTS);
+        let thread_filter = filter.clone();
+        let thread = thread::spawn(move || {
+            if let Err(e) = format_trace(rx, &thread_filter, &symbols) {
+                eprintln!("Trace output failed: {}", e);
+            }
+        });
+        Self { filter, tx, thread }
+    }
+
+    pub fn covers(&self, cycle: u64, count: u64) -> bool {
+        self.filter.covers(cycle, count)
+    }
+
+    pub fn send(&self, events: Vec<Event>) {
+        if !events.is_empty() {
+            let _ = self.tx.send(events);
+        }
+    }
+
+    /// Waits until every line sent so far is printed.
+    pub fn finish(self) {
+        drop(self.tx);
+        let _ = self.thread.join();
+    }
+}
+
+fn format_trace(rx: mpsc::Receiver<Vec<Event>>, filter: &TraceFilter, symbols: &Symbols) -> io::Result<()> {
+    let mut out = BufWriter::new(io::stdout());
+    loop {
+        let batch = match rx.try_recv() {
+            Ok(batch) => batch,
+            // Flush whenever the queue runs dry so a paused guest's trace is
+            // complete on screen.
+            Err(mpsc::TryRecvError::Empty) => {
+                out.flush()?;
+                match rx.recv() {
+                    Ok(batch) => batch,
+                    Err(_) => break,
+                }
+            }
+            Err(mpsc::TryRecvError::Disconnected) => break,
+        };
+        // A batch holds every effect of the instructions in it.
+        for effects in batch.chunk_by(|a, b| a.cycle == b.cycle) {
+            if filter.matches(&effects[0]) {
+                writeln!(out, "{}", format_line(effects, symbols))?;
+            }
+        }
+    }
+    out.flush()
+}
+
+/// `cycle  pc  encoding  disassembly  effects` of one instruction, e.g.
+/// `      1042  80000010  0092a023  sw s1, 0(t0)   [0x80001000] <- 0x00000003`.
+pub fn format_line(effects: &[Event], symbols: &Symbols) -> String {
+    let event = &effects[0];
+    let mut line = String::new();
+    for effect in effects {
+        let text = match effect.instr_type {
+            EventType::RegWrite { reg: 0, .. } | EventType::FlowLink { register: 0, .. } | EventType::Pipeline(_) => String::new(),
+            EventType::RegWrite { reg, value } => format!("{} = 0x{:08x}", REGISTER_NAMES[reg as usize & 0x1F], value),
+            // Follows the register write of the load.
+            EventType::MemRead { addr, .. } => format!("<- [0x{:08x}]", addr),
+            EventType::MemWrite { addr, value, size } => format!("[0x{:08x}] <- 0x{:0width$x}", addr, value, width = size as usize * 2),
+            EventType::FlowChange { new_pc } if new_pc == event.pc.wrapping_add(4) => "not taken".to_string(),
+            EventType::FlowChange { .. } => "taken".to_string(),
+            EventType::FlowLink { register, .. } => format!("{} = 0x{:08x}", REGISTER_NAMES[register as usize & 0x1F], event.pc.wrapping_add(4)),
+            EventType::Trap { cause, tval } => format!("trap cause {} tval 0x{:08x}", cause, tval),
+            EventType::CsrWrite { csr, value } => format!("csr 0x{:03x} = 0x{:08x}", csr, value),
+        };
+        if !text.is_empty() {
+            let separator = match effect.instr_type {
+                EventType::MemRead { .. } => " ",
+                _ => ", ",
+            };
+            if !line.is_empty() {
+                line.push_str(separator);
+            }
+            line.push_str(&text);
+        }
+    }
+    // Jumps through a register only show where they went here.
+    if let EventType::FlowLink { new_pc, .. } = event.instr_type && event.opcode & 0x7F == I_JALR_FORMAT {
+        let label = symbols.label(new_pc).map_or(String::new(), |label| format!(" {}", label));
+        line.push_str(&format!("{}-> 0x{:08x}{}", if line.is_empty() { "" } else { ", " }, new_pc, label));
+    }
+    let text = disassemble_with(event.opcode, event.pc, symbols);
+    let line = format!("{:>10}  {:08x}  {:08x}  {:<36} {}", event.cycle, event.pc, event.opcode, text, line);
+    line.trim_end().to_string()
+}
@@ -0,0 +1,159 @@
+//! `trace-diff`: compares two logs retired instruction by retired
+//! instruction, typically the emulator's against one taken from the RTL.
+//! Either log may be an event log or a JSON commit log.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::mem::discriminant;
+use std::ops::Range;
+
+use clap::ValueEnum;
+use colored::*;
+
+use crate::constants::*;
+use crate::elf::Symbols;
+use crate::commitlog::{is_event_log, CommitLogReader};
+use crate::eventlog::LogReader;
+use crate::trace::format_line;
+use crate::types::{Event, EventType};
+
+/// Differences known to come from the RTL trace rather than the core.
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+pub enum Ignore {
+    /// Effects of jal/jalr, which the RTL trace doesn't record reliably.
+    Jumps,
+    /// Where conditional branches went.
+    Branches,
+    /// Writes to x0.
+    X0,
+    /// Access widths of loads and stores.
+    Widths,
+    /// Effects only one of the logs records, for commit logs that keep a
+    /// single event per instruction.
+    Unmatched,
+}
+
+pub struct DiffOptions {
+    /// Stop after this many divergences.
+    pub max: usize,
+    /// Instructions printed before each divergence.
+    pub context: usize,
+    pub ignore: Vec<Ignore>,
+    /// PCs whose effects aren't compared, e.g. reads of a free running timer.
+    pub ignore_pc: Vec<Range<u64>>,
+}
+
+impl DiffOptions {
+    fn ignores(&self, rule: Ignore) -> bool {
+        self.ignore.contains(&rule)
+    }
+
+    /// Whether two records of the same instruction differ in a way the
+    /// ignore rules don't excuse.
+    fn differs(&self, emu: &[Event], rtl: &[Event]) -> bool {
+        let (pc, format) = (emu[0].pc as u64, emu[0].opcode & 0x7F);
+        if self.ignores(Ignore::Jumps) && (format == J_FORMAT || format == I_JALR_FORMAT)
+            || self.ignore_pc.iter().any(|range| range.contains(&pc)) {
+            return false;
+        }
+        let (emu, rtl) = (self.relevant(emu), self.relevant(rtl));
+        if self.ignores(Ignore::Unmatched) {
+            let unmatched = |ours: &[EventType], theirs: &[EventType]| ours.iter().any(|effect| {
+                theirs.iter().any(|other| discriminant(other) == discriminant(effect)) && !theirs.contains(effect)
+            });
+            unmatched(&emu, &rtl) || unmatched(&rtl, &emu)
+        } else {
+            emu != rtl
+        }
+    }
+
+    fn relevant(&self, effects: &[Event]) -> Vec<EventType> {
+        effects.iter().filter_map(|event| match event.instr_type {
+            // Timing, not architectural state.
+            EventType::Pipeline(_) => None,
+            EventType::FlowChange { .. } if self.ignores(Ignore::Branches) => None,
+            EventType::RegWrite { reg: 0, .. } if self.ignores(Ignore::X0) => None,
+            EventType::MemRead { addr, value, .. } if self.ignores(Ignore::Widths) => Some(EventType::MemRead { addr, value, size: 0 }),
+            EventType::MemWrite { addr, value, .. } if self.ignores(Ignore::Widths) => Some(EventType::MemWrite { addr, value, size: 0 }),
+            effect => Some(effect),
+        }).collect()
+    }
+}
+
+enum Source {
+    Events(LogReader<File>),
+    Commits(CommitLogReader<BufReader<File>>),
+}
+
+impl Source {
+    fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        if !is_event_log(path)? {
+            println!("{}: JSON commit log", path);
+            return Ok(Source::Commits(CommitLogReader::open(path)?));
+        }
+        let reader = LogReader::open(path)?;
+        let header = reader.header();
+        println!("{}: {} log written by {}, start pc 0x{:08x}", path, header.isa, header.tool, header.start_pc);
+        Ok(Source::Events(reader))
+    }
+
+    fn read_instruction(&mut self, effects: &mut Vec<Event>) -> io::Result<bool> {
+        match self {
+            Source::Events(reader) => reader.read_instruction(effects),
+            Source::Commits(reader) => reader.read_instruction(effects),
+        }
+    }
+}
+
+/// Returns the number of divergences found.
+pub fn run(emu_path: &str, rtl_path: &str, options: &DiffOptions) -> Result<usize, Box<dyn Error>> {
+    let mut emu = Source::open(emu_path)?;
+    let mut rtl = Source::open(rtl_path)?;
+    let symbols = Symbols::default();
+    // The last `context` instructions, with their buffers reused.
+    let mut history: VecDeque<Vec<Event>> = VecDeque::with_capacity(options.context + 1);
+    let (mut emu_effects, mut rtl_effects) = (Vec::new(), Vec::new());
+    let mut retired = 0u64;
+    let mut divergences = 0;
+    loop {
+        let emu_more = emu.read_instruction(&mut emu_effects).map_err(|e| format!("{}: {}", emu_path, e))?;
+        let rtl_more = rtl.read_instruction(&mut rtl_effects).map_err(|e| format!("{}: {}", rtl_path, e))?;
+        if !emu_more || !rtl_more {
+            if emu_more != rtl_more {
+                let (ended, other) = if emu_more { (rtl_path, emu_path) } else { (emu_path, rtl_path) };
+                println!("{} ends after {} instructions, {} goes on", ended, retired, other);
+            }
+            break;
+        }
+        let (first, other) = (&emu_effects[0], &rtl_effects[0]);
+        let lost = first.pc != other.pc || first.opcode != other.opcode;
+        let same = emu_effects.iter().map(|event| event.instr_type).eq(rtl_effects.iter().map(|event| event.instr_type));
+        if lost || !same && options.differs(&emu_effects, &rtl_effects) {
+            divergences += 1;
+            println!("\n{}", format!("Divergence {} at instruction {}", divergences, retired).red().bold());
+            for effects in &history {
+                println!("      {}", format_line(effects, &symbols));
+            }
+            println!("{} {}", "emu >".red(), format_line(&emu_effects, &symbols));
+            println!("{} {}", "rtl >".red(), format_line(&rtl_effects, &symbols));
+            if lost {
+                println!("Control flow diverged, the logs can't be aligned past this point");
+                break;
+            }
+            if divergences == options.max {
+                println!("Stopping after {} divergences", divergences);
+                break;
+            }
+        }
+        if options.context > 0 {
+            let mut slot = if history.len() == options.context { history.pop_front().unwrap() } else { Vec::new() };
+            slot.clone_from(&emu_effects);
+            history.push_back(slot);
+        }
+        retired += 1;
+    }
+    println!("\nCompared {} instructions, {} divergences", retired, divergences);
+    Ok(divergences)
+}
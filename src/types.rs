@@ -1,13 +1,58 @@
 use std::io::Write;
 
+use serde::{Deserialize, Serialize};
+
 pub struct CtrlMessage {
     pub command: Ctrl,
 }
 pub enum Ctrl {
     RequestFrame,
+    /// Ask for a `DebugState`, including `memory_len` bytes from
+    /// `memory_addr` for the memory viewer.
+    RequestState { memory_addr: u32, memory_len: u32 },
+    Pause,
+    Resume,
+    /// Execute this many instructions, then pause.
+    Step(u64),
+    /// Reset the CPU and devices and load the program again.
+    Reset,
+    /// Rebuild the machine from the files on disk (platform, program,
+    /// images), for after a recompile.
+    Reload,
+    /// Address or symbol, resolved like the console's `break`.
+    AddBreakpoint(String),
+    RemoveBreakpoint(u32),
+    WriteMemory { addr: u32, value: u8 },
+    /// Multiple of the core clock to run at, None for as fast as possible.
+    SetSpeed(Option<f64>),
+    /// Write a snapshot of the machine to a file named after the cycle count.
+    SaveSnapshot,
     Stop,
 }
 
+pub struct DisasmLine {
+    pub addr: u32,
+    pub word: u32,
+    /// `0x80000010 <main+16>` label of the address.
+    pub label: String,
+    pub text: String,
+}
+
+/// What the GUI debugger panels show, sent in reply to `Ctrl::RequestState`.
+pub struct DebugState {
+    pub regs: [u32; 32],
+    pub pc: u32,
+    pub cycles: u64,
+    pub running: bool,
+    pub exit_code: Option<i32>,
+    /// Instructions around the PC.
+    pub disassembly: Vec<DisasmLine>,
+    pub breakpoints: Vec<(u32, String)>,
+    pub memory_addr: u32,
+    /// None for unmapped bytes and device registers, which aren't read.
+    pub memory: Vec<Option<u8>>,
+}
+
 pub struct ScreenMsg{
     pub screen_type: ScreenType,
     pub data: Vec<u8>,
@@ -17,53 +62,48 @@ pub enum ScreenType {
     FrameBuffer,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum EventType{
     RegWrite{reg: u8, value: u32},
-    MemRead{addr: u32, value: u32},
-    MemWrite{addr: u32, value: u32},
+    /// `value` as it came off the bus, before sign extension; `size` is
+    /// the access width in bytes.
+    MemRead{addr: u32, value: u32, size: u8},
+    MemWrite{addr: u32, value: u32, size: u8},
+    /// Conditional branch, `new_pc` is where execution continues.
     FlowChange{new_pc: u32},
-    #[allow(dead_code)]
+    /// JAL/JALR to `new_pc`, linking pc + 4 into `register`.
     FlowLink{new_pc: u32, register: u8},
+    /// Exception raised by the instruction (mcause, mtval).
+    Trap{cause: u32, tval: u32},
+    /// This core has no Zicsr, only logs from other sources carry these.
+    CsrWrite{csr: u16, value: u32},
+    /// When an out-of-order core moved the instruction through each stage,
+    /// kept from RTL commit logs.
+    Pipeline(PipelineTimes),
 }
 
+/// Cycle stamps of the RISC-Y pipeline stages, the `status` object of its
+/// commit log.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct PipelineTimes {
+    pub decode: u64,
+    pub rename: u64,
+    pub issue: u64,
+    pub dispatch: u64,
+    pub resolve: u64,
+    pub commit: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Event{
+    /// Cycle the instruction executed on. The binary log stores it once per
+    /// instruction, as a skip from the cycle after the previous one.
+    pub cycle: u64,
     pub pc: u32,
     pub opcode: u32,
     pub instr_type: EventType,
 }
 impl Event{
-    pub fn serialize <W: Write>( &self, writer: &mut W ) -> std::io::Result<()> {
-        writer.write_all(&self.pc.to_le_bytes())?;
-        writer.write_all(&self.opcode.to_le_bytes())?;
-        match &self.instr_type {
-            EventType::RegWrite{reg, value} => {
-                writer.write_all(&[0u8])?;
-                writer.write_all(&[*reg])?;
-                writer.write_all(&value.to_le_bytes())?;
-            }
-            EventType::MemRead{addr, value} => {
-                writer.write_all(&[1u8])?;
-                writer.write_all(&addr.to_le_bytes())?;
-                writer.write_all(&value.to_le_bytes())?;
-            }
-            EventType::MemWrite{addr, value} => {
-                writer.write_all(&[2u8])?;
-                writer.write_all(&addr.to_le_bytes())?;
-                writer.write_all(&value.to_le_bytes())?;
-            }
-            EventType::FlowChange{new_pc} => {
-                writer.write_all(&[3u8])?;
-                writer.write_all(&new_pc.to_le_bytes())?;
-            }
-            EventType::FlowLink{new_pc, register} => {
-                writer.write_all(&[4u8])?;
-                writer.write_all(&new_pc.to_le_bytes())?;
-                writer.write_all(&[*register])?;
-            }
-        }
-        Ok(())
-    }
-    #[allow(dead_code)]
     pub fn serialize_human_readable<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
         // Print the common fields
         write!(writer, "PC=0x{:08X} OPCODE=0x{:08X} ", self.pc, self.opcode)?;
@@ -73,11 +113,11 @@ impl Event{
             EventType::RegWrite { reg, value } => {
                 writeln!(writer, "TYPE=RegWrite REG={} VALUE=0x{:08X}", reg, value)?;
             }
-            EventType::MemRead { addr, value } => {
-                writeln!(writer, "TYPE=MemRead ADDR=0x{:08X} VALUE=0x{:08X}", addr, value)?;
+            EventType::MemRead { addr, value, size } => {
+                writeln!(writer, "TYPE=MemRead ADDR=0x{:08X} VALUE=0x{:08X} SIZE={}", addr, value, size)?;
             }
-            EventType::MemWrite { addr, value } => {
-                writeln!(writer, "TYPE=MemWrite ADDR=0x{:08X} VALUE=0x{:08X}", addr, value)?;
+            EventType::MemWrite { addr, value, size } => {
+                writeln!(writer, "TYPE=MemWrite ADDR=0x{:08X} VALUE=0x{:08X} SIZE={}", addr, value, size)?;
             }
             EventType::FlowChange { new_pc } => {
                 writeln!(writer, "TYPE=FlowChange NEW_PC=0x{:08X}", new_pc)?;
@@ -85,8 +125,18 @@ impl Event{
             EventType::FlowLink { new_pc, register } => {
                 writeln!(writer, "TYPE=FlowLink NEW_PC=0x{:08X} REG={}", new_pc, register)?;
             }
+            EventType::Trap { cause, tval } => {
+                writeln!(writer, "TYPE=Trap CAUSE={} TVAL=0x{:08X}", cause, tval)?;
+            }
+            EventType::CsrWrite { csr, value } => {
+                writeln!(writer, "TYPE=CsrWrite CSR=0x{:03X} VALUE=0x{:08X}", csr, value)?;
+            }
+            EventType::Pipeline(times) => {
+                writeln!(writer, "TYPE=Pipeline DECODE={} RENAME={} ISSUE={} DISPATCH={} RESOLVE={} COMMIT={}",
+                    times.decode, times.rename, times.issue, times.dispatch, times.resolve, times.commit)?;
+            }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
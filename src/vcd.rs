@@ -0,0 +1,232 @@
+//! `--vcd`: architectural state as a VCD waveform, one timestep per
+//! retired instruction so it can be lined up with the RTL's commit stage.
+//! Time `n + 1` holds the state after the instruction of cycle `n`:
+//! `cpu.pc` and `cpu.insn` are the instruction retired, `regs.*` the
+//! register file and `mem.*` the watched words (`--vcd-mem`).
+//!
+//! Registers are rebuilt from the same events `--log` uses. Watched RAM
+//! and ROM words also follow the program's stores, and are read again
+//! after every batch of instructions for the writes of DMA engines, virtio
+//! devices and host calls, which show up at the end of the batch they
+//! happened in. Device registers are never read behind the program's back:
+//! MMIO words show the values the program loaded and stored, and bits
+//! nothing has accessed yet are `x`. Going back in time with `bs`/`bc`
+//! doesn't rewrite the dump, which resumes once the run passes the last
+//! cycle dumped.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::constants::REGISTER_NAMES;
+use crate::cpu::Cpu;
+use crate::eventlog::TOOL;
+use crate::platform::{parse_u32, Platform};
+use crate::types::{Event, EventType};
+
+/// A 32 bit word watched with `--vcd-mem`.
+pub struct Watch {
+    pub addr: u32,
+    pub name: String,
+}
+
+impl Watch {
+    /// `ADDR[=NAME]` or `DEVICE+OFFSET[=NAME]`, the device named as in the
+    /// platform file.
+    pub fn parse(spec: &str, platform: Option<&Platform>) -> Result<Self, String> {
+        let (location, name) = match spec.split_once('=') {
+            Some((location, name)) => (location, Some(name.to_string())),
+            None => (spec, None),
+        };
+        let addr = match location.split_once('+') {
+            Some((device, offset)) => {
+                let base = platform
+                    .and_then(|platform| platform.devices.iter().find(|dev| dev.name() == device))
+                    .ok_or_else(|| format!("No device named '{}'", device))?
+                    .base;
+                base.wrapping_add(parse_u32(offset)?)
+            }
+            None => parse_u32(location)?,
+        };
+        if addr % 4 != 0 {
+            return Err(format!("{} is not word aligned", location));
+        }
+        let name = name.unwrap_or_else(|| location.replace('+', "_"));
+        Ok(Self { addr, name })
+    }
+}
+
+/// A watched word and which of its bits are known.
+struct Word {
+    addr: u32,
+    value: u32,
+    known: u32,
+}
+
+pub struct VcdWriter<W: Write> {
+    out: W,
+    regs: [u32; 32],
+    words: Vec<Word>,
+    /// Timestep of the last value changes written.
+    time: u64,
+}
+
+/// Identifier codes: pc, insn, then x1..x31 (x0 has none), then the
+/// watched words.
+const PC: usize = 0;
+const INSN: usize = 1;
+const FIRST_REG: usize = 1;
+const FIRST_WORD: usize = FIRST_REG + 32;
+
+impl VcdWriter<BufWriter<File>> {
+    pub fn create(path: &str, cpu: &Cpu, watches: &[Watch]) -> io::Result<Self> {
+        Self::new(BufWriter::with_capacity(64 * 1024, File::create(path)?), cpu, watches)
+    }
+}
+
+impl<W: Write> VcdWriter<W> {
+    /// Starts from the current state of `cpu`.
+    pub fn new(mut out: W, cpu: &Cpu, watches: &[Watch]) -> io::Result<Self> {
+        let (cycle, regs) = (cpu.cycles(), std::array::from_fn(|reg| cpu.read_reg(reg)));
+        writeln!(out, "$version {} $end", TOOL)?;
+        writeln!(out, "$comment one timestep per retired instruction $end")?;
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module cpu $end")?;
+        writeln!(out, "$var wire 32 {} pc $end", code(PC))?;
+        writeln!(out, "$var wire 32 {} insn $end", code(INSN))?;
+        writeln!(out, "$scope module regs $end")?;
+        for (reg, name) in REGISTER_NAMES.iter().enumerate().skip(1) {
+            writeln!(out, "$var wire 32 {} x{}_{} $end", code(FIRST_REG + reg), reg, name)?;
+        }
+        writeln!(out, "$upscope $end")?;
+        if !watches.is_empty() {
+            writeln!(out, "$scope module mem $end")?;
+            for (i, watch) in watches.iter().enumerate() {
+                writeln!(out, "$var wire 32 {} {} $end", code(FIRST_WORD + i), watch.name)?;
+            }
+            writeln!(out, "$upscope $end")?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+
+        let words: Vec<Word> = watches.iter().map(|watch| {
+            let value = cpu.peek_mem(4, watch.addr);
+            Word { addr: watch.addr, value: value.unwrap_or(0), known: if value.is_some() { u32::MAX } else { 0 } }
+        }).collect();
+        writeln!(out, "#{}", cycle)?;
+        writeln!(out, "$dumpvars")?;
+        writeln!(out, "bx {}", code(PC))?;
+        writeln!(out, "bx {}", code(INSN))?;
+        for (reg, value) in regs.iter().enumerate().skip(1) {
+            writeln!(out, "b{:b} {}", value, code(FIRST_REG + reg))?;
+        }
+        for (i, word) in words.iter().enumerate() {
+            write_word(&mut out, word, FIRST_WORD + i)?;
+        }
+        writeln!(out, "$end")?;
+        Ok(Self { out, regs, words, time: cycle })
+    }
+
+    /// Writes a batch from `Cpu::run`, which always ends on an instruction
+    /// boundary.
+    pub fn write(&mut self, events: &[Event]) -> io::Result<()> {
+        for effects in events.chunk_by(|a, b| a.cycle == b.cycle) {
+            self.write_instruction(effects)?;
+        }
+        Ok(())
+    }
+
+    fn write_instruction(&mut self, effects: &[Event]) -> io::Result<()> {
+        let first = &effects[0];
+        let time = first.cycle + 1;
+        if time <= self.time {
+            return Ok(());
+        }
+        self.time = time;
+        writeln!(self.out, "#{}", time)?;
+        writeln!(self.out, "b{:b} {}", first.pc, code(PC))?;
+        writeln!(self.out, "b{:b} {}", first.opcode, code(INSN))?;
+        for effect in effects {
+            match effect.instr_type {
+                EventType::RegWrite { reg, value } => self.set_reg(reg, value)?,
+                EventType::FlowLink { register, .. } => self.set_reg(register, first.pc.wrapping_add(4))?,
+                EventType::MemRead { addr, value, size } | EventType::MemWrite { addr, value, size } => {
+                    self.access(addr, value, size)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn set_reg(&mut self, reg: u8, value: u32) -> io::Result<()> {
+        let reg = reg as usize;
+        if reg != 0 && self.regs[reg] != value {
+            self.regs[reg] = value;
+            writeln!(self.out, "b{:b} {}", value, code(FIRST_REG + reg))?;
+        }
+        Ok(())
+    }
+
+    /// A load or store of `size` bytes at `addr`, which updates the bytes
+    /// of any watched word it overlaps.
+    fn access(&mut self, addr: u32, value: u32, size: u8) -> io::Result<()> {
+        for (i, word) in self.words.iter_mut().enumerate() {
+            let (mut new_value, mut known) = (word.value, word.known);
+            for byte in 0..size as u32 {
+                let Some(lane) = addr.wrapping_add(byte).checked_sub(word.addr).filter(|&lane| lane < 4) else {
+                    continue;
+                };
+                let mask = 0xFF << (lane * 8);
+                new_value = new_value & !mask | ((value >> (byte * 8)) & 0xFF) << (lane * 8);
+                known |= mask;
+            }
+            if (new_value, known) != (word.value, word.known) {
+                (word.value, word.known) = (new_value, known);
+                write_word(&mut self.out, word, FIRST_WORD + i)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks up what was written to the watched memory words other than by
+    /// the program's stores since the last call.
+    pub fn refresh(&mut self, cpu: &Cpu) -> io::Result<()> {
+        for (i, word) in self.words.iter_mut().enumerate() {
+            if let Some(value) = cpu.peek_mem(4, word.addr)
+                && (value, u32::MAX) != (word.value, word.known) {
+                (word.value, word.known) = (value, u32::MAX);
+                write_word(&mut self.out, word, FIRST_WORD + i)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn write_word(out: &mut impl Write, word: &Word, index: usize) -> io::Result<()> {
+    if word.known == u32::MAX {
+        return writeln!(out, "b{:b} {}", word.value, code(index));
+    }
+    let bits: String = (0..32).rev().map(|bit| match (word.known >> bit & 1, word.value >> bit & 1) {
+        (0, _) => 'x',
+        (_, 0) => '0',
+        _ => '1',
+    }).collect();
+    writeln!(out, "b{} {}", bits, code(index))
+}
+
+/// VCD identifier of signal `index`, in printable ASCII.
+fn code(mut index: usize) -> String {
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return code;
+        }
+        index -= 1;
+    }
+}